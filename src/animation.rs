@@ -0,0 +1,286 @@
+// Camera animation: keyframes of the camera placement along a timeline of frames, and a
+// camera that moves while its shutter is open.
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::vec3::*;
+use std::path::PathBuf;
+
+// Placement of the camera at some frame. None leaves the setting at its default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    pub lookfrom: Loc,
+    pub lookat: Loc,
+    pub fov: Option<f64>,
+    pub focus_dist: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    pub frame: f64,
+    pub pose: Pose,
+}
+
+// How the camera moves between keyframes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    // Straight lines at constant speed, with kinks at the keyframes
+    Linear,
+    // Smooth curve through all keyframes
+    CatmullRom,
+}
+
+// Keyframes with one line each: frame, lookfrom x y z, lookat x y z and optionally the
+// field of view and the focus distance. Every line has the same columns, frames increase.
+pub fn parse_keyframes(text: &str) -> Result<Vec<Keyframe>, String> {
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let values: Vec<f64> = line
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Malformed number in line {}", number + 1))?;
+        if !(7..=9).contains(&values.len()) {
+            return Err(format!("Line {} needs 7 to 9 values", number + 1));
+        }
+        let keyframe = Keyframe {
+            frame: values[0],
+            pose: Pose {
+                lookfrom: Vec3::new(values[1], values[2], values[3]),
+                lookat: Vec3::new(values[4], values[5], values[6]),
+                fov: values.get(7).copied(),
+                focus_dist: values.get(8).copied(),
+            },
+        };
+        if keyframe.pose.lookfrom == keyframe.pose.lookat {
+            return Err(format!("The camera looks at itself in line {}", number + 1));
+        }
+        let fov = keyframe.pose.fov;
+        let focus_dist = keyframe.pose.focus_dist;
+        if !fov.is_none_or(|fov| fov > 0.0 && fov <= 360.0) || !focus_dist.is_none_or(|d| d > 0.0) {
+            return Err(format!("Invalid camera settings in line {}", number + 1));
+        }
+        if let Some(previous) = keyframes.last() {
+            if keyframe.pose.fov.is_some() != previous.pose.fov.is_some()
+                || keyframe.pose.focus_dist.is_some() != previous.pose.focus_dist.is_some()
+            {
+                return Err(format!(
+                    "Line {} has other columns than the ones before",
+                    number + 1
+                ));
+            }
+            if keyframe.frame <= previous.frame {
+                return Err(format!("The frame in line {} doesn't increase", number + 1));
+            }
+        }
+        keyframes.push(keyframe);
+    }
+    if keyframes.is_empty() {
+        return Err("The camera path has no keyframes".to_string());
+    }
+    Ok(keyframes)
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraPath {
+    keyframes: Vec<Keyframe>,
+    interpolation: Interpolation,
+}
+
+impl CameraPath {
+    pub fn new(keyframes: Vec<Keyframe>, interpolation: Interpolation) -> CameraPath {
+        assert!(!keyframes.is_empty(), "A camera path needs keyframes");
+        CameraPath {
+            keyframes,
+            interpolation,
+        }
+    }
+
+    // Whole frames from the first to the last keyframe
+    pub fn frames(&self) -> (u32, u32) {
+        let first = self.keyframes[0].frame.ceil().max(0.0) as u32;
+        let last = self.keyframes[self.keyframes.len() - 1]
+            .frame
+            .floor()
+            .max(0.0) as u32;
+        (first, last.max(first))
+    }
+
+    // The camera stands still before the first and after the last keyframe
+    pub fn pose_at(&self, frame: f64) -> Pose {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len() - 1;
+        if frame <= keyframes[0].frame {
+            return keyframes[0].pose.clone();
+        }
+        if frame >= keyframes[last].frame {
+            return keyframes[last].pose.clone();
+        }
+        // Segment from keyframe i to i + 1 that contains the frame
+        let i = keyframes.partition_point(|k| k.frame <= frame) - 1;
+        let s = (frame - keyframes[i].frame) / (keyframes[i + 1].frame - keyframes[i].frame);
+        let value = |get: &dyn Fn(&Pose) -> f64| self.interpolate(i, s, get);
+        let point = |get: &dyn Fn(&Pose) -> &Loc| {
+            Vec3::new(
+                value(&|pose| get(pose).e0),
+                value(&|pose| get(pose).e1),
+                value(&|pose| get(pose).e2),
+            )
+        };
+        let pose = &keyframes[i].pose;
+        Pose {
+            lookfrom: point(&|pose| &pose.lookfrom),
+            lookat: point(&|pose| &pose.lookat),
+            fov: pose
+                .fov
+                .map(|_| value(&|pose| pose.fov.unwrap_or_default())),
+            focus_dist: pose
+                .focus_dist
+                .map(|_| value(&|pose| pose.focus_dist.unwrap_or_default())),
+        }
+    }
+
+    // Value between keyframes i and i + 1, at s from 0 to 1
+    fn interpolate(&self, i: usize, s: f64, get: &dyn Fn(&Pose) -> f64) -> f64 {
+        let keyframes = &self.keyframes;
+        let (p1, p2) = (get(&keyframes[i].pose), get(&keyframes[i + 1].pose));
+        match self.interpolation {
+            Interpolation::Linear => (1.0 - s) * p1 + s * p2,
+            Interpolation::CatmullRom => {
+                // Cubic Hermite curve whose tangents point from the previous to the next
+                // keyframe. Tangents are scaled to the segment, which keeps the speed
+                // continuous when keyframes are spaced unevenly.
+                let last = keyframes.len() - 1;
+                let tangent = |k: usize| {
+                    let (before, after) = (k.saturating_sub(1), (k + 1).min(last));
+                    let slope = (get(&keyframes[after].pose) - get(&keyframes[before].pose))
+                        / (keyframes[after].frame - keyframes[before].frame);
+                    slope * (keyframes[i + 1].frame - keyframes[i].frame)
+                };
+                let (m1, m2) = (tangent(i), tangent(i + 1));
+                let (s2, s3) = (s * s, s * s * s);
+                (2.0 * s3 - 3.0 * s2 + 1.0) * p1
+                    + (s3 - 2.0 * s2 + s) * m1
+                    + (-2.0 * s3 + 3.0 * s2) * p2
+                    + (s3 - s2) * m2
+            }
+        }
+    }
+}
+
+// Path of a frame, the first run of #s in the pattern is replaced by the zero padded
+// frame number. Patterns without # are the same path for every frame.
+pub fn frame_path(pattern: &str, frame: u32) -> PathBuf {
+    let start = match pattern.find('#') {
+        Some(start) => start,
+        None => return pattern.into(),
+    };
+    let digits = pattern[start..].chars().take_while(|&c| c == '#').count();
+    let end = start + digits;
+    format!(
+        "{}{:0width$}{}",
+        &pattern[..start],
+        frame,
+        &pattern[end..],
+        width = digits
+    )
+    .into()
+}
+
+// Camera that moves from one placement to another while the shutter is open, which blurs
+// moving parts of the image. Rays are interpolated between the two cameras.
+#[derive(Debug)]
+pub struct MotionBlurCamera {
+    open: Box<dyn Camera>,
+    close: Box<dyn Camera>,
+}
+
+impl MotionBlurCamera {
+    pub fn new(open: Box<dyn Camera>, close: Box<dyn Camera>) -> MotionBlurCamera {
+        MotionBlurCamera { open, close }
+    }
+}
+
+impl Camera for MotionBlurCamera {
+    fn get_ray(&self, s: f64, t: f64, lens: (f64, f64)) -> Option<Ray> {
+        self.open.get_ray(s, t, lens)
+    }
+
+    fn get_ray_at(&self, s: f64, t: f64, lens: (f64, f64), time: f64) -> Option<Ray> {
+        let open = self.open.get_ray(s, t, lens)?;
+        let close = self.close.get_ray(s, t, lens)?;
+        Some(Ray::new(
+            (1.0 - time) * &open.orig + time * &close.orig,
+            (1.0 - time) * &open.dir + time * &close.dir,
+        ))
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.open.sensitivity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "# frame, lookfrom, lookat, fov
+        0   0 0 10   0 0 0   20
+        10  10 0 10  0 0 0   40
+        30  10 10 10 0 0 0   40";
+
+    #[test]
+    fn test_parse_keyframes() {
+        let keyframes = parse_keyframes(PATH).unwrap();
+        assert_eq!(keyframes.len(), 3);
+        assert_eq!(keyframes[1].frame, 10.0);
+        assert_eq!(keyframes[1].pose.lookfrom, Vec3::new(10.0, 0.0, 10.0));
+        assert_eq!(keyframes[1].pose.fov, Some(40.0));
+        assert_eq!(keyframes[1].pose.focus_dist, None);
+        assert!(parse_keyframes("0 0 0 1 0 0 0\n0 1 0 1 0 0 0").is_err());
+        assert!(parse_keyframes("0 0 0 1 0 0 0\n1 1 0 1 0 0 0 30").is_err());
+        assert!(parse_keyframes("0 0 0 1 0 0").is_err());
+        assert!(parse_keyframes("# nothing").is_err());
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let keyframes = parse_keyframes(PATH).unwrap();
+        for interpolation in [Interpolation::Linear, Interpolation::CatmullRom] {
+            let path = CameraPath::new(keyframes.clone(), interpolation);
+            assert_eq!(path.frames(), (0, 30));
+            // Both pass through the keyframes and hold still outside of them
+            assert_eq!(path.pose_at(10.0), keyframes[1].pose);
+            assert_eq!(path.pose_at(-5.0), keyframes[0].pose);
+            assert_eq!(path.pose_at(40.0), keyframes[2].pose);
+        }
+
+        let linear = CameraPath::new(keyframes.clone(), Interpolation::Linear);
+        let pose = linear.pose_at(5.0);
+        assert_eq!(pose.lookfrom, Vec3::new(5.0, 0.0, 10.0));
+        assert_eq!(pose.fov, Some(30.0));
+
+        // The curve rounds the corner at the second keyframe: it passes it moving up and
+        // keeps moving sideways for a while
+        let smooth = CameraPath::new(keyframes, Interpolation::CatmullRom);
+        assert!(smooth.pose_at(9.9).lookfrom.e1 < 0.0);
+        assert!(smooth.pose_at(11.0).lookfrom.e0 > 10.0);
+        // The speed is continuous at the keyframe
+        let speed =
+            |a: f64, b: f64| (smooth.pose_at(b).lookfrom - smooth.pose_at(a).lookfrom).length();
+        assert!((speed(9.999, 10.0) - speed(10.0, 10.001)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_frame_path() {
+        assert_eq!(
+            frame_path("out/frame####.ppm", 42),
+            PathBuf::from("out/frame0042.ppm")
+        );
+        assert_eq!(frame_path("f#_#.ppm", 123), PathBuf::from("f123_#.ppm"));
+        assert_eq!(frame_path("stats.json", 7), PathBuf::from("stats.json"));
+    }
+}
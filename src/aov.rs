@@ -0,0 +1,328 @@
+// Arbitrary output variables: what the camera paths of a pixel saw besides its color,
+// for compositing. Written as layers of an OpenEXR file or as one image per pass.
+use crate::checkpoint::fingerprint;
+use crate::denoise::Features;
+use crate::exr;
+use crate::film::Film;
+use crate::image::{write_atomically, Image};
+use crate::materials::Material;
+use crate::sampling::hash_to_unit;
+use crate::tonemap::{PostProcess, Tonemap, Transfer};
+use crate::vec3::*;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    Depth,
+    Normal,
+    Albedo,
+    ObjectId,
+    MaterialId,
+    Direct,
+    Indirect,
+    Emission,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object-id",
+            Aov::MaterialId => "material-id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+
+    // Channels of the EXR layer, taken from the components of the value in order
+    fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    // Value of a pixel, given the record summed over its samples. Identifiers are those
+    // of the first sample, they can't be averaged.
+    pub fn value(&self, sum: &PathRecord, samples: i32) -> Vec3 {
+        let mean = |v: &Vec3| v / samples.max(1) as f64;
+        match self {
+            Aov::Depth => Vec3::new(sum.features.depth / samples.max(1) as f64, 0.0, 0.0),
+            Aov::Normal => mean(&sum.features.normal),
+            Aov::Albedo => mean(&sum.features.albedo),
+            Aov::ObjectId => Vec3::new(sum.object_id as f64, 0.0, 0.0),
+            Aov::MaterialId => Vec3::new(sum.material_id as f64, 0.0, 0.0),
+            Aov::Direct => mean(&sum.direct),
+            Aov::Indirect => mean(&sum.indirect),
+            Aov::Emission => mean(&sum.emission),
+        }
+    }
+}
+
+// What the camera path of a sample saw. The sky is the only light source, its light is
+// split by the number of bounces it took to reach the camera.
+#[derive(Debug, Clone)]
+pub struct PathRecord {
+    pub features: Features,
+    // Zero where the camera ray escaped
+    pub object_id: u32,
+    pub material_id: u32,
+    // Seen by the camera ray itself
+    pub emission: Color,
+    // Reached the camera after one bounce
+    pub direct: Color,
+    pub indirect: Color,
+    // Luminance removed by clamping and the number of clamped light contributions
+    pub clamped: f64,
+    pub clamped_samples: u32,
+}
+
+impl PathRecord {
+    pub fn zero() -> PathRecord {
+        PathRecord {
+            features: Features::zero(),
+            object_id: 0,
+            material_id: 0,
+            emission: Vec3::zero(),
+            direct: Vec3::zero(),
+            indirect: Vec3::zero(),
+            clamped: 0.0,
+            clamped_samples: 0,
+        }
+    }
+
+    pub fn add_light(&mut self, bounces: u32, light: &Color) {
+        match bounces {
+            0 => self.emission.add_cum(light),
+            1 => self.direct.add_cum(light),
+            _ => self.indirect.add_cum(light),
+        }
+    }
+
+    // Adds the record of another sample, the first sample decides the identifiers
+    pub fn add(&mut self, other: &PathRecord, first: bool) {
+        self.features.add(&other.features);
+        if first {
+            self.object_id = other.object_id;
+            self.material_id = other.material_id;
+        }
+        self.emission.add_cum(&other.emission);
+        self.direct.add_cum(&other.direct);
+        self.indirect.add_cum(&other.indirect);
+        self.clamped += other.clamped;
+        self.clamped_samples += other.clamped_samples;
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.features.write_to(out)?;
+        out.write_all(&self.object_id.to_le_bytes())?;
+        out.write_all(&self.material_id.to_le_bytes())?;
+        out.write_all(&self.clamped_samples.to_le_bytes())?;
+        for c in [&self.emission, &self.direct, &self.indirect] {
+            for v in [c.e0, c.e1, c.e2] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+        out.write_all(&self.clamped.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<PathRecord> {
+        let features = Features::read_from(input)?;
+        let mut read_u32 = || -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            input.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let object_id = read_u32()?;
+        let material_id = read_u32()?;
+        let clamped_samples = read_u32()?;
+        let mut values = [0.0; 10];
+        for v in values.iter_mut() {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            *v = f64::from_le_bytes(bytes);
+        }
+        Ok(PathRecord {
+            features,
+            object_id,
+            material_id,
+            emission: Vec3::new(values[0], values[1], values[2]),
+            direct: Vec3::new(values[3], values[4], values[5]),
+            indirect: Vec3::new(values[6], values[7], values[8]),
+            clamped: values[9],
+            clamped_samples,
+        })
+    }
+}
+
+// Material identifiers are a hash of the material parameters, equal materials of
+// different objects share one and it stays the same between renders. Hashing large
+// textures is slow, so every material is hashed once and found by address afterwards.
+pub struct MaterialIds {
+    enabled: bool,
+    ids: RwLock<HashMap<usize, u32>>,
+}
+
+impl MaterialIds {
+    // A disabled table hands out zeros, for renders without a material ID output
+    pub fn new(enabled: bool) -> MaterialIds {
+        MaterialIds {
+            enabled,
+            ids: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, material: &dyn Material) -> u32 {
+        if !self.enabled {
+            return 0;
+        }
+        let address = material as *const dyn Material as *const () as usize;
+        if let Some(&id) = self.ids.read().unwrap().get(&address) {
+            return id;
+        }
+        // 24 bits are exact in the float channels of an EXR, zero is the sky
+        let id = (fingerprint(&[&material]) % 0xff_ffff) as u32 + 1;
+        self.ids.write().unwrap().insert(address, id);
+        id
+    }
+}
+
+fn component(v: &Vec3, i: usize) -> f64 {
+    [v.e0, v.e1, v.e2][i]
+}
+
+// Replaces the extension of path by "<name>.<extension>"
+fn layer_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map_or("ppm".into(), |e| e.to_string_lossy());
+    path.with_file_name(format!("{}.{}.{}", stem, name, extension))
+}
+
+// Displayable version of an AOV. Light passes are developed like the image, the
+// others are mapped into [0,1].
+fn develop(film: &Film, aov: Aov, post: &PostProcess) -> Image {
+    let values = film.aov(aov);
+    let max_depth = values.iter().map(|v| v.e0).fold(0.0, f64::max);
+    let srgb = PostProcess::new(0.0, None, Tonemap::Clamp, Transfer::Srgb);
+    let mut image = Image::new(film.width as usize, film.height as usize);
+    for (i, v) in values.iter().enumerate() {
+        let color = match aov {
+            Aov::Depth => Vec3::new(1.0, 1.0, 1.0) * (v.e0 / max_depth.max(1e-10)),
+            Aov::Normal => 0.5 * (v + Vec3::new(1.0, 1.0, 1.0)),
+            Aov::Albedo => srgb.apply(v),
+            Aov::ObjectId | Aov::MaterialId if v.e0 == 0.0 => Vec3::zero(),
+            // A random color per identifier
+            Aov::ObjectId | Aov::MaterialId => Vec3::new(
+                hash_to_unit(&[v.e0, 0.0]),
+                hash_to_unit(&[v.e0, 1.0]),
+                hash_to_unit(&[v.e0, 2.0]),
+            ),
+            Aov::Direct | Aov::Indirect | Aov::Emission => post.apply(v),
+        };
+        image.set(i % film.width as usize, i / film.width as usize, color);
+    }
+    image
+}
+
+// Writes the AOVs of the film: as layers next to the linear image in an EXR file if path
+// ends in .exr, or else as one PPM per AOV with the name of the AOV added to path
+pub fn save(path: &Path, film: &Film, aovs: &[Aov], post: &PostProcess) -> io::Result<()> {
+    if path.extension().is_some_and(|e| e == "exr") {
+        let mut channels = Vec::new();
+        let colors = film.colors();
+        for (i, name) in ["R", "G", "B"].iter().enumerate() {
+            channels.push(exr::Channel {
+                name: name.to_string(),
+                values: colors.iter().map(|c| component(c, i) as f32).collect(),
+            });
+        }
+        for &aov in aovs {
+            let values = film.aov(aov);
+            for (i, name) in aov.channels().iter().enumerate() {
+                channels.push(exr::Channel {
+                    name: format!("{}.{}", aov.name(), name),
+                    values: values.iter().map(|v| component(v, i) as f32).collect(),
+                });
+            }
+        }
+        let (width, height) = (film.width as usize, film.height as usize);
+        write_atomically(path, |out| exr::write(out, width, height, channels))
+    } else {
+        for &aov in aovs {
+            develop(film, aov, post).save_ppm(layer_path(path, aov.name()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_split() {
+        let mut record = PathRecord::zero();
+        record.add_light(0, &Vec3::new(1.0, 0.0, 0.0));
+        record.add_light(1, &Vec3::new(0.0, 1.0, 0.0));
+        record.add_light(4, &Vec3::new(0.0, 0.0, 1.0));
+        record.add_light(2, &Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(record.emission, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(record.direct, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(record.indirect, Vec3::new(0.0, 0.0, 2.0));
+
+        record.object_id = 3;
+        record.clamped = 0.25;
+        record.clamped_samples = 1;
+        let mut sum = PathRecord::zero();
+        sum.add(&record, true);
+        record.object_id = 5;
+        sum.add(&record, false);
+        assert_eq!(Aov::ObjectId.value(&sum, 2).e0, 3.0);
+        assert_eq!(Aov::Direct.value(&sum, 2), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!((sum.clamped, sum.clamped_samples), (0.5, 2));
+
+        let mut bytes = Vec::new();
+        sum.write_to(&mut bytes).unwrap();
+        let read = PathRecord::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", sum));
+    }
+
+    #[test]
+    fn test_names() {
+        for aov in Aov::ALL {
+            assert_eq!(Aov::from_name(aov.name()), Some(aov));
+        }
+        assert_eq!(Aov::from_name("beauty"), None);
+        assert_eq!(
+            layer_path(Path::new("out/render.ppm"), "depth"),
+            PathBuf::from("out/render.depth.ppm")
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// Shapes of the lens opening, which give defocused highlights (bokeh) their shape. Lens
+// samples are spread uniformly over the open area.
+use crate::image::Image;
+use crate::vec3::*;
+use std::f64::consts::PI;
+use std::io;
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Circle,
+    // Regular polygon formed by the blades of an iris, with one corner at the top
+    // before rotating it counterclockwise
+    Polygon { blades: u32, rotation: f64 },
+    Image(ApertureImage),
+}
+
+// Grayscale mask stretched over the square around the unit disk, white is open. Lens
+// samples follow the brightness, gray areas let through part of the light.
+#[derive(Debug, Clone)]
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    // Cumulative brightness of the rows, and within each row, starting at zero
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<f64>,
+}
+
+// Index of the bin of a cumulative distribution that u falls in, and where within it
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let (start, total) = (cdf[0], cdf[cdf.len() - 1]);
+    let target = start + u * (total - start);
+    // Empty bins never contain the target
+    let i = (cdf.partition_point(|&c| c <= target) - 1).min(cdf.len() - 2);
+    let width = cdf[i + 1] - cdf[i];
+    let fraction = if width > 0.0 {
+        (target - cdf[i]) / width
+    } else {
+        0.5
+    };
+    (i, fraction.clamp(0.0, 1.0))
+}
+
+impl ApertureImage {
+    pub fn new(image: &Image) -> io::Result<ApertureImage> {
+        let (width, height) = (image.width, image.height);
+        let mut row_cdf = vec![0.0];
+        let mut column_cdfs = Vec::with_capacity(height * (width + 1));
+        for y in 0..height {
+            let mut sum = 0.0;
+            column_cdfs.push(sum);
+            for x in 0..width {
+                sum += image.get(x, y).luminance().max(0.0);
+                column_cdfs.push(sum);
+            }
+            row_cdf.push(row_cdf[y] + sum);
+        }
+        if row_cdf[height] <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The aperture image is black",
+            ));
+        }
+        Ok(ApertureImage {
+            width,
+            height,
+            row_cdf,
+            column_cdfs,
+        })
+    }
+
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<ApertureImage> {
+        ApertureImage::new(&Image::read_netpbm(path)?)
+    }
+
+    fn sample(&self, u: (f64, f64)) -> (f64, f64) {
+        let (row, fy) = sample_cdf(&self.row_cdf, u.1);
+        let columns = &self.column_cdfs[row * (self.width + 1)..(row + 1) * (self.width + 1)];
+        let (column, fx) = sample_cdf(columns, u.0);
+        let x = (column as f64 + fx) / self.width as f64;
+        // Row 0 is the top row
+        let y = (row as f64 + fy) / self.height as f64;
+        (2.0 * x - 1.0, 1.0 - 2.0 * y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Aperture {
+    shape: Shape,
+    // Anamorphic lenses squeeze the aperture horizontally, giving oval bokeh
+    squeeze: f64,
+}
+
+impl Aperture {
+    pub fn new(shape: Shape, squeeze: f64) -> Aperture {
+        Aperture { shape, squeeze }
+    }
+
+    pub fn circle() -> Aperture {
+        Aperture::new(Shape::Circle, 1.0)
+    }
+
+    // Point on the aperture for a uniform sample in [0,1)^2, within the unit disk (or the
+    // square around it for images)
+    pub fn sample(&self, u: (f64, f64)) -> (f64, f64) {
+        let (x, y) = match &self.shape {
+            Shape::Circle => {
+                let p = Vec3::uniform_in_unit_disk(u);
+                (p.e0, p.e1)
+            }
+            Shape::Polygon { blades, rotation } => {
+                // One of the equally large triangles between the center and two
+                // neighbouring corners, then a uniform point in it
+                let n = *blades as f64;
+                let scaled = u.0 * n;
+                let triangle = scaled.floor().min(n - 1.0);
+                let r = (scaled - triangle).sqrt();
+                let corner = |i: f64| {
+                    let angle = rotation.to_radians() + PI / 2.0 + 2.0 * PI * i / n;
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(triangle), corner(triangle + 1.0));
+                (
+                    r * ((1.0 - u.1) * a.0 + u.1 * b.0),
+                    r * ((1.0 - u.1) * a.1 + u.1 * b.1),
+                )
+            }
+            Shape::Image(image) => image.sample(u),
+        };
+        (x / self.squeeze, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(aperture: &Aperture) -> Vec<(f64, f64)> {
+        let n = 64;
+        (0..n * n)
+            .map(|i| {
+                let u = (
+                    ((i % n) as f64 + 0.5) / n as f64,
+                    ((i / n) as f64 + 0.5) / n as f64,
+                );
+                aperture.sample(u)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_polygon_is_uniform() {
+        let aperture = Aperture::new(
+            Shape::Polygon {
+                blades: 6,
+                rotation: 30.0,
+            },
+            2.0,
+        );
+        let points = samples(&aperture);
+        // A hexagon with corners left and right, squeezed to half its width
+        let inside = |&(x, y): &(f64, f64)| {
+            let x = 2.0 * x;
+            y.abs() <= 3f64.sqrt() / 2.0 + 1e-9
+                && 3f64.sqrt() * x.abs() + y.abs() <= 3f64.sqrt() + 1e-9
+        };
+        assert!(points.iter().all(inside));
+        // Uniform: the inner hexagon of half the size gets a quarter of the samples
+        let inner = points
+            .iter()
+            .filter(|&&(x, y)| inside(&(2.0 * x, 2.0 * y)))
+            .count();
+        let fraction = inner as f64 / points.len() as f64;
+        assert!((fraction - 0.25).abs() < 0.01, "{}", fraction);
+    }
+
+    #[test]
+    fn test_image_follows_brightness() {
+        // Open on the left, half open at the bottom right, closed at the top right
+        let mut image = Image::new(2, 2);
+        image.set(0, 0, Vec3::new(1.0, 1.0, 1.0));
+        image.set(0, 1, Vec3::new(1.0, 1.0, 1.0));
+        image.set(1, 1, Vec3::new(0.5, 0.5, 0.5));
+        let aperture = Aperture::new(Shape::Image(ApertureImage::new(&image).unwrap()), 1.0);
+        let points = samples(&aperture);
+        assert!(!points.iter().any(|&(x, y)| x > 0.0 && y > 0.0));
+        let right = points.iter().filter(|&&(x, _)| x > 0.0).count();
+        let fraction = right as f64 / points.len() as f64;
+        assert!((fraction - 0.2).abs() < 0.01, "{}", fraction);
+        assert!(ApertureImage::new(&Image::new(2, 2)).is_err());
+    }
+}
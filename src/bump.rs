@@ -0,0 +1,272 @@
+// Materials that perturb the shading normal of a hit before handing it to their base
+// material. The geometric normal stays in charge of which side of the surface a
+// scattered ray ends up on.
+use crate::hittable::*;
+use crate::materials::*;
+use crate::ray::*;
+use crate::sampler::SampleStream;
+use crate::texture::*;
+use crate::vec3::*;
+
+// Tangent space normal map (red: +dpdu, green: +dpdv, blue: normal), as baked by
+// most tools in the OpenGL convention
+#[derive(Debug)]
+pub struct NormalMapped {
+    base: Box<dyn Material>,
+    normal_map: Box<dyn Texture>,
+}
+
+impl NormalMapped {
+    pub fn new<T: Texture + 'static>(base: Box<dyn Material>, normal_map: T) -> NormalMapped {
+        NormalMapped {
+            base,
+            normal_map: Box::new(normal_map),
+        }
+    }
+}
+
+// Scalar displacement along the normal, height values are multiplied by scale
+#[derive(Debug)]
+pub struct BumpMapped {
+    base: Box<dyn Material>,
+    height: Box<dyn Texture>,
+    scale: f64,
+}
+
+impl BumpMapped {
+    pub fn new<T: Texture + 'static>(base: Box<dyn Material>, height: T, scale: f64) -> BumpMapped {
+        BumpMapped {
+            base,
+            height: Box::new(height),
+            scale,
+        }
+    }
+
+    fn displacement(&self, uv: TexCoord, location: &Loc) -> f64 {
+        self.scale * self.height.scalar(uv, location)
+    }
+}
+
+// World space step used for the finite differences of the height texture
+const BUMP_DELTA: f64 = 1e-3;
+
+impl Material for NormalMapped {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        let n = &hit.normal;
+        let dpdu = &hit.tangents.dpdu;
+        // Gram-Schmidt, dpdu is not necessarily orthogonal to the normal
+        let projected = dpdu - dpdu.dot(n) * n;
+        let t = if projected.is_near_zero() {
+            // No tangent at the poles of a sphere, any frame around the normal will do
+            n.orthonormal_basis().0
+        } else {
+            projected.unit_vector()
+        };
+        let mut b = n.cross(&t);
+        // The normal is flipped on inside hits, keep b pointing along dpdv
+        if b.dot(&hit.tangents.dpdv) < 0.0 {
+            b = -b;
+        }
+        let c = self.normal_map.value(hit.uv, &hit.location);
+        let local = 2.0 * c - Vec3::new(1.0, 1.0, 1.0);
+        let shading_normal = (local.e0 * t + local.e1 * b + local.e2.max(0.0) * n).unit_vector();
+        scatter_with_shading_normal(&*self.base, r, hit, shading_normal, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Color {
+        self.base.albedo(hit)
+    }
+
+    fn opacity(&self, uv: TexCoord, location: &Loc) -> f64 {
+        self.base.opacity(uv, location)
+    }
+}
+
+impl Material for BumpMapped {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        let tangents = &hit.tangents;
+        let du_length = tangents.dpdu.length();
+        let dv_length = tangents.dpdv.length();
+        if du_length == 0.0 || dv_length == 0.0 {
+            return self.base.scatter(r, hit, sampler);
+        }
+        let du = BUMP_DELTA / du_length;
+        let dv = BUMP_DELTA / dv_length;
+        let (u, v) = hit.uv;
+
+        let d = self.displacement(hit.uv, &hit.location);
+        let d_u = self.displacement((u + du, v), &(&hit.location + du * &tangents.dpdu));
+        let d_v = self.displacement((u, v + dv), &(&hit.location + dv * &tangents.dpdv));
+
+        // Derivatives of the displaced surface p + d(u, v) * n, ignoring dn/du and dn/dv
+        let n = &hit.normal;
+        let dpdu = &tangents.dpdu + ((d_u - d) / du) * n;
+        let dpdv = &tangents.dpdv + ((d_v - d) / dv) * n;
+        let mut shading_normal = dpdu.cross(&dpdv).unit_vector();
+        if shading_normal.dot(n) < 0.0 {
+            shading_normal = -shading_normal;
+        }
+        scatter_with_shading_normal(&*self.base, r, hit, shading_normal, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Color {
+        self.base.albedo(hit)
+    }
+
+    fn opacity(&self, uv: TexCoord, location: &Loc) -> f64 {
+        self.base.opacity(uv, location)
+    }
+}
+
+fn scatter_with_shading_normal(
+    base: &dyn Material,
+    r: &Ray,
+    hit: &Hit,
+    shading_normal: Vec3,
+    sampler: &mut SampleStream,
+) -> Option<ScatterResult> {
+    let geometric_normal = &hit.normal;
+    let wo = -r.unit_direction();
+    // A viewer below the shading hemisphere would see the back of the perturbed
+    // surface, fall back to the geometric normal
+    let normal = if wo.dot(&shading_normal) > 1e-4 {
+        shading_normal
+    } else {
+        geometric_normal.clone()
+    };
+    let shaded_hit = Hit {
+        normal,
+        ..hit.clone()
+    };
+    let mut result = base.scatter(r, &shaded_hit, sampler)?;
+
+    // Directions that lie on different sides of the shading and the geometric surface
+    // would leak light through the surface. Mirror them at the geometric tangent plane.
+    let dir = &result.ray.dir;
+    let geometric_side = dir.dot(geometric_normal);
+    if geometric_side * dir.dot(&shaded_hit.normal) < 0.0 {
+        result.ray.dir = dir - 2.0 * geometric_side * geometric_normal;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    fn hit_on_plane(material: &dyn Material) -> Hit<'_> {
+        Hit::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            material,
+            Surface::Outside,
+            (0.5, 0.5),
+            Tangents {
+                dpdu: Vec3::new(1.0, 0.0, 0.0),
+                dpdv: Vec3::new(0.0, 1.0, 0.0),
+            },
+        )
+    }
+
+    fn scatter(material: &dyn Material, r: &Ray) -> Option<ScatterResult> {
+        let sampler = IndependentSampler::new(0);
+        material.scatter(r, &hit_on_plane(material), &mut SampleStream::new(&sampler))
+    }
+
+    #[test]
+    fn test_flat_normal_map_keeps_mirror_reflection() {
+        let mirror = NormalMapped::new(
+            Box::new(Metal::new(Vec3::new(1.0, 1.0, 1.0), 0.0)),
+            Vec3::new(0.5, 0.5, 1.0),
+        );
+        let r = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0));
+        let result = scatter(&mirror, &r).unwrap();
+        assert!(
+            (&result.ray.dir.unit_vector() - Vec3::new(1.0, 0.0, 1.0).unit_vector()).length()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn test_tilted_normal_never_leaks_below_surface() {
+        // Shading normal tilted by 60 degrees towards +x
+        let mirror = NormalMapped::new(
+            Box::new(Metal::new(Vec3::new(1.0, 1.0, 1.0), 0.0)),
+            Vec3::new(0.5 + 0.5 * 0.75_f64.sqrt(), 0.5, 0.75),
+        );
+        for i in 1..10 {
+            let angle = i as f64 * 0.15;
+            let r = Ray::new(Vec3::zero(), Vec3::new(-angle.sin(), 0.0, -angle.cos()));
+            if let Some(result) = scatter(&mirror, &r) {
+                assert!(result.ray.dir.e2 >= 0.0, "leaked for angle {}", angle);
+            }
+        }
+    }
+
+    #[test]
+    fn test_normal_map_at_a_pole() {
+        let mirror = NormalMapped::new(
+            Box::new(Metal::new(Vec3::new(1.0, 1.0, 1.0), 0.0)),
+            Vec3::new(0.75, 0.5, 0.9),
+        );
+        // Spheres have dpdu = 0 at their poles
+        let hit = Hit {
+            tangents: Tangents {
+                dpdu: Vec3::zero(),
+                dpdv: Vec3::new(1.0, 0.0, 0.0),
+            },
+            ..hit_on_plane(&mirror)
+        };
+        let r = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let sampler = IndependentSampler::new(0);
+        let result = mirror
+            .scatter(&r, &hit, &mut SampleStream::new(&sampler))
+            .unwrap();
+        let dir = &result.ray.dir;
+        assert!(dir.e0.is_finite() && dir.e1.is_finite() && dir.e2.is_finite());
+        // Still tilted by the map
+        assert!(dir.e2 > 0.0 && dir.e2 < 0.99 * dir.length());
+    }
+
+    #[test]
+    fn test_linear_bump_tilts_normal() {
+        #[derive(Debug)]
+        struct Ramp;
+        impl Texture for Ramp {
+            fn value(&self, uv: TexCoord, _: &Loc) -> Color {
+                Vec3::new(uv.0, uv.0, uv.0)
+            }
+        }
+        // Height rises with slope 1 along +x, the normal tilts towards -x by 45 degrees
+        let bumped = BumpMapped::new(
+            Box::new(Metal::new(Vec3::new(1.0, 1.0, 1.0), 0.0)),
+            Ramp,
+            1.0,
+        );
+        let r = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let result = scatter(&bumped, &r).unwrap();
+        // Reflection of straight down at a 45 degree normal is horizontal
+        let dir = result.ray.dir.unit_vector();
+        assert!(dir.e2.abs() < 1e-6 && (dir.e0 + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_normal_map_keeps_cutout() {
+        let leaf = |alpha: f64| {
+            NormalMapped::new(
+                Box::new(AlphaMasked::new(
+                    Box::new(Lambertian::new(Vec3::new(0.2, 0.5, 0.1))),
+                    alpha,
+                    AlphaMode::Mask(0.5),
+                )),
+                Vec3::new(0.5, 0.5, 1.0),
+            )
+        };
+        let r = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let p = Vec3::zero();
+        assert!(!passes_alpha_test(&leaf(0.2), &r, 1.0, (0.5, 0.5), &p));
+        assert!(passes_alpha_test(&leaf(0.8), &r, 1.0, (0.5, 0.5), &p));
+    }
+}
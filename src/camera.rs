@@ -1,22 +1,75 @@
+use crate::aperture::Aperture;
+use crate::hittable::Hittable;
 use crate::ray::*;
 use crate::vec3::*;
-use rand::thread_rng;
+use std::f64::consts::PI;
+use std::fmt;
 
-pub struct Camera {
+// Maps positions on the image to camera rays. s and t run from 0 to 1 across the image,
+// starting in the lower left corner; lens is a uniform sample in [0,1)^2 that picks the
+// ray origin on the aperture. None where the image shows nothing, e.g. outside the
+// circle of a fisheye lens.
+pub trait Camera: fmt::Debug + Send + Sync {
+    fn get_ray(&self, s: f64, t: f64, lens: (f64, f64)) -> Option<Ray>;
+
+    // Ray at a point in time while the shutter is open, from 0 (opening) to 1 (closing).
+    // Only cameras that move during the exposure depend on it.
+    fn get_ray_at(&self, s: f64, t: f64, lens: (f64, f64), _time: f64) -> Option<Ray> {
+        self.get_ray(s, t, lens)
+    }
+
+    // Factor for the light of every ray. Cameras that block some of their rays (like
+    // real lenses) make up for the loss in the center of the image.
+    fn sensitivity(&self) -> f64 {
+        1.0
+    }
+}
+
+type Degree = f64;
+
+// Orthonormal basis of a camera at lookfrom that looks at lookat: u points right, v up
+// and w backwards, away from the scene
+pub fn orientation(lookfrom: &Loc, lookat: &Loc, vup: &Loc) -> (Loc, Loc, Loc) {
+    let w = (lookfrom - lookat).unit_vector();
+    let u = vup.cross(&w).unit_vector();
+    let v = w.cross(&u); // unit vec by definition
+    (u, v, w)
+}
+
+// Distance of the sharp plane through point from a camera at lookfrom that looks at
+// lookat. It is measured along the view direction, the plane faces the camera.
+pub fn focus_distance(lookfrom: &Loc, lookat: &Loc, point: &Loc) -> Result<f64, String> {
+    let forward = (lookat - lookfrom).unit_vector();
+    let distance = (point - lookfrom).dot(&forward);
+    if distance <= 0.0 {
+        return Err("The focus point is behind the camera".to_string());
+    }
+    Ok(distance)
+}
+
+// What the image shows at s, t: the first point of the world along the ray through the
+// center of the lens, which doesn't depend on the focus
+pub fn visible_point(camera: &dyn Camera, world: &dyn Hittable, s: f64, t: f64) -> Option<Loc> {
+    let ray = camera.get_ray(s, t, (0.5, 0.5))?;
+    let hit = world.hit(&ray, &|t| t > 0.0)?;
+    Some(ray.at(hit.t))
+}
+
+// Pinhole or thin lens camera
+#[derive(Debug)]
+pub struct PerspectiveCamera {
     origin: Loc,
     horiz: Loc,
     vert: Loc,
     lower_left_corner: Loc,
     lens_radius: f64,
+    aperture: Aperture,
+    focus_dist: f64,
     u: Loc,
     v: Loc,
-    #[allow(dead_code)]
-    w: Loc,
 }
 
-type Degree = f64;
-
-impl Camera {
+impl PerspectiveCamera {
     pub fn new(
         lookfrom: Loc,
         lookat: Loc,
@@ -25,39 +78,326 @@ impl Camera {
         aspect_ratio: f64,
         aperture: f64,
         focus_dist: f64,
-    ) -> Camera {
+    ) -> PerspectiveCamera {
         let theta = vfov.to_radians();
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = (&lookfrom - &lookat).unit_vector();
-        let u = vup.cross(&w).unit_vector();
-        let v = w.cross(&u); // unit vec by definition
+        let (u, v, w) = orientation(&lookfrom, &lookat, &vup);
 
         let origin = lookfrom;
         let horiz = focus_dist * viewport_width * &u;
         let vert = focus_dist * viewport_height * &v;
         let lower_left_corner = &origin - &horiz / 2.0 - &vert / 2.0 - focus_dist * &w;
 
-        Camera {
-            lower_left_corner: lower_left_corner,
-            origin: origin,
-            horiz: horiz,
-            vert: vert,
+        PerspectiveCamera {
+            lower_left_corner,
+            origin,
+            horiz,
+            vert,
             lens_radius: aperture / 2.0,
-            u: u,
-            v: v,
-            w: w,
+            aperture: Aperture::circle(),
+            focus_dist,
+            u,
+            v,
         }
     }
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let mut rng = thread_rng();
-        let rd = self.lens_radius * Vec3::random_in_unit_disk(&mut rng);
-        let offset = &self.u * rd.e0 + &self.v * rd.e1;
+    // Shape of the aperture, which is round otherwise. Its diameter stays the same.
+    pub fn with_aperture(self, aperture: Aperture) -> PerspectiveCamera {
+        PerspectiveCamera { aperture, ..self }
+    }
+
+    // Moves the image window sideways by shift times the distance from the camera,
+    // positive to the right, without turning the camera (off-axis projection)
+    pub fn shifted(self, shift: f64) -> PerspectiveCamera {
+        let offset = shift * self.focus_dist * &self.u;
+        PerspectiveCamera {
+            lower_left_corner: &self.lower_left_corner + offset,
+            ..self
+        }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f64, t: f64, lens: (f64, f64)) -> Option<Ray> {
+        let (x, y) = self.aperture.sample(lens);
+        let offset = self.lens_radius * (&self.u * x + &self.v * y);
         let new_orig = &self.origin + offset;
         let ray_dir = &self.lower_left_corner + s * &self.horiz + t * &self.vert - &new_orig;
-        Ray::new(new_orig, ray_dir)
+        Some(Ray::new(new_orig, ray_dir))
+    }
+}
+
+// Parallel rays along the view direction, objects keep their size at any distance.
+// Rays start in the plane through lookfrom, nothing behind it is visible.
+#[derive(Debug)]
+pub struct OrthographicCamera {
+    horiz: Loc,
+    vert: Loc,
+    lower_left_corner: Loc,
+    direction: Vec3,
+}
+
+impl OrthographicCamera {
+    // view_height is the extent of the scene that fills the image vertically, the view
+    // is aspect_ratio times as wide
+    pub fn new(
+        lookfrom: Loc,
+        lookat: Loc,
+        vup: Loc,
+        view_height: f64,
+        aspect_ratio: f64,
+    ) -> OrthographicCamera {
+        let (u, v, w) = orientation(&lookfrom, &lookat, &vup);
+        let horiz = aspect_ratio * view_height * &u;
+        let vert = view_height * &v;
+        let lower_left_corner = &lookfrom - &horiz / 2.0 - &vert / 2.0;
+
+        OrthographicCamera {
+            horiz,
+            vert,
+            lower_left_corner,
+            direction: -w,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f64, t: f64, _lens: (f64, f64)) -> Option<Ray> {
+        let origin = &self.lower_left_corner + s * &self.horiz + t * &self.vert;
+        Some(Ray::new(origin, self.direction.clone()))
+    }
+}
+
+// Latitude-longitude panorama of the full sphere around lookfrom, with the direction of
+// lookat in the center of the image. The poles point along vup, so that the horizon
+// stays level. 360 viewers expect an aspect ratio of 2:1.
+#[derive(Debug)]
+pub struct EquirectangularCamera {
+    origin: Loc,
+    u: Loc,
+    v: Loc,
+    w: Loc,
+    eye_offset: f64,
+}
+
+impl EquirectangularCamera {
+    // Fails if lookat lies straight above or below lookfrom, there is no horizontal
+    // direction to center then
+    pub fn new(lookfrom: Loc, lookat: Loc, vup: Loc) -> Result<EquirectangularCamera, String> {
+        let v = vup.unit_vector();
+        let back = &lookfrom - &lookat;
+        let horizontal = &back - back.dot(&v) * &v;
+        if horizontal.is_near_zero() {
+            return Err("Panoramas can't look along the up direction".to_string());
+        }
+        let w = horizontal.unit_vector();
+        let u = v.cross(&w);
+        Ok(EquirectangularCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            eye_offset: 0.0,
+        })
+    }
+
+    // Omni-directional stereo: rays start on a circle around the origin, offset sideways
+    // from their direction by eye_offset (negative for the left eye), like the eyes of a
+    // head that turns to look in every direction
+    pub fn with_eye_offset(self, eye_offset: f64) -> EquirectangularCamera {
+        EquirectangularCamera { eye_offset, ..self }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f64, t: f64, _lens: (f64, f64)) -> Option<Ray> {
+        let longitude = 2.0 * PI * (s - 0.5);
+        let latitude = PI * (t - 0.5);
+        let horizontal = longitude.sin() * &self.u - longitude.cos() * &self.w;
+        let direction = latitude.cos() * horizontal + latitude.sin() * &self.v;
+        let right = longitude.cos() * &self.u + longitude.sin() * &self.w;
+        Some(Ray::new(&self.origin + self.eye_offset * right, direction))
+    }
+}
+
+// How the angle between a ray and the view direction maps to the distance from the
+// center of a fisheye image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    // Proportional to the angle, used for dome projection
+    Equidistant,
+    // Preserves areas, like most fisheye lenses for photography
+    Equisolid,
+}
+
+// Circular fisheye image that fits the shorter side of the image, black outside
+#[derive(Debug)]
+pub struct FisheyeCamera {
+    origin: Loc,
+    u: Loc,
+    v: Loc,
+    w: Loc,
+    fov: Degree,
+    mapping: FisheyeMapping,
+    aspect_ratio: f64,
+}
+
+impl FisheyeCamera {
+    // fov is the angle across the circle, up to 360 degrees
+    pub fn new(
+        lookfrom: Loc,
+        lookat: Loc,
+        vup: Loc,
+        fov: Degree,
+        mapping: FisheyeMapping,
+        aspect_ratio: f64,
+    ) -> FisheyeCamera {
+        let (u, v, w) = orientation(&lookfrom, &lookat, &vup);
+        FisheyeCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            fov,
+            mapping,
+            aspect_ratio,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f64, t: f64, _lens: (f64, f64)) -> Option<Ray> {
+        // Relative to the center, the circle has a radius of one
+        let scale = self.aspect_ratio.min(1.0);
+        let x = 2.0 * (s - 0.5) * self.aspect_ratio / scale;
+        let y = 2.0 * (t - 0.5) / scale;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let max_angle = self.fov.to_radians() / 2.0;
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * max_angle,
+            FisheyeMapping::Equisolid => 2.0 * (r * (max_angle / 2.0).sin()).asin(),
+        };
+        let phi = y.atan2(x);
+        let sideways = phi.cos() * &self.u + phi.sin() * &self.v;
+        let direction = theta.sin() * sideways - theta.cos() * &self.w;
+        Some(Ray::new(self.origin.clone(), direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orthographic_rays_are_parallel() {
+        let camera = OrthographicCamera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            2.0,
+            1.5,
+        );
+        let center = camera.get_ray(0.5, 0.5, (0.3, 0.8)).unwrap();
+        let corner = camera.get_ray(0.0, 1.0, (0.0, 0.0)).unwrap();
+        assert_eq!(center.orig, Vec3::new(0.0, 0.0, 5.0));
+        assert_eq!(center.dir, Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(corner.dir, center.dir);
+        assert_eq!(corner.orig, Vec3::new(-1.5, 1.0, 5.0));
+    }
+
+    #[test]
+    fn test_focus_distance() {
+        let (lookfrom, lookat) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -2.0));
+        // Along the view direction, not the distance to the point
+        let distance = focus_distance(&lookfrom, &lookat, &Vec3::new(3.0, 0.0, -4.0));
+        assert_eq!(distance, Ok(4.0));
+        assert!(focus_distance(&lookfrom, &lookat, &Vec3::new(0.0, 1.0, 3.0)).is_err());
+    }
+
+    #[test]
+    fn test_visible_point() {
+        use crate::materials::Lambertian;
+        use crate::sphere::Sphere;
+
+        let sphere = Sphere::new(
+            Vec3::new(0.0, 0.0, -5.0),
+            1.0,
+            Box::new(Lambertian::new(Vec3::zero())),
+        );
+        let (lookfrom, lookat) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        // Focused elsewhere and with a large aperture, the center of the lens sees the
+        // same point
+        let camera = PerspectiveCamera::new(
+            lookfrom.clone(),
+            lookat.clone(),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            1.5,
+            2.0,
+            20.0,
+        );
+        let point = visible_point(&camera, &sphere, 0.5, 0.5).unwrap();
+        assert_close(&point, &Vec3::new(0.0, 0.0, -4.0));
+        let distance = focus_distance(&lookfrom, &lookat, &point).unwrap();
+        assert!((distance - 4.0).abs() < 1e-12);
+        // Nothing in the corner of the image
+        assert!(visible_point(&camera, &sphere, 0.0, 0.0).is_none());
+    }
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((a - b).length() < 1e-12, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_panoramas() {
+        let (lookfrom, lookat, vup) = (
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        // Looking down doesn't tilt the horizon
+        let below = Vec3::new(1.0, 0.0, 0.0);
+        let equirect = EquirectangularCamera::new(lookfrom.clone(), below, vup.clone()).unwrap();
+        let dir = |s, t| equirect.get_ray(s, t, (0.5, 0.5)).unwrap().dir;
+        assert_close(&dir(0.5, 0.5), &Vec3::new(0.0, 0.0, -1.0));
+        assert_close(&dir(0.75, 0.5), &Vec3::new(1.0, 0.0, 0.0));
+        assert_close(&dir(0.0, 0.5), &Vec3::new(0.0, 0.0, 1.0));
+        assert_close(&dir(0.3, 1.0), &Vec3::new(0.0, 1.0, 0.0));
+        // The left eye of a head turned right
+        let left = equirect
+            .with_eye_offset(-0.5)
+            .get_ray(0.75, 0.5, (0.5, 0.5));
+        assert_close(&left.unwrap().orig, &Vec3::new(1.0, 2.0, 2.5));
+        let straight_up = Vec3::new(1.0, 5.0, 3.0);
+        assert!(EquirectangularCamera::new(lookfrom.clone(), straight_up, vup.clone()).is_err());
+
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let fisheye = FisheyeCamera::new(
+                lookfrom.clone(),
+                lookat.clone(),
+                vup.clone(),
+                180.0,
+                mapping,
+                2.0,
+            );
+            let dir = |s, t| fisheye.get_ray(s, t, (0.5, 0.5)).map(|r| r.dir);
+            assert_close(&dir(0.5, 0.5).unwrap(), &Vec3::new(0.0, 0.0, -1.0));
+            // The circle touches the top and bottom, where it sees 90 degrees sideways
+            assert_close(&dir(0.5, 1.0).unwrap(), &Vec3::new(0.0, 1.0, 0.0));
+            assert_close(&dir(0.25, 0.5).unwrap(), &Vec3::new(-1.0, 0.0, 0.0));
+            assert!(dir(0.1, 0.5).is_none());
+        }
+        let equisolid =
+            FisheyeCamera::new(lookfrom, lookat, vup, 180.0, FisheyeMapping::Equisolid, 1.0);
+        // Half way to the edge: sin(theta / 2) = 0.5 sin(45°)
+        let half = equisolid.get_ray(0.75, 0.5, (0.5, 0.5)).unwrap().dir;
+        let theta = 2.0 * (0.5 * (PI / 4.0).sin()).asin();
+        assert_close(&half, &Vec3::new(theta.sin(), 0.0, -theta.cos()));
     }
 }
@@ -0,0 +1,147 @@
+// Render state that is periodically saved so an interrupted render can continue.
+// Samplers are deterministic, so the film together with the number of samples that
+// were taken is all that is needed to continue exactly where the render stopped.
+use crate::film::Film;
+use crate::image::write_atomically;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RTCKPT05";
+
+pub struct Checkpoint {
+    // Identifies scene and settings the checkpoint was made with
+    pub fingerprint: u64,
+    // Every pixel has this many samples, or fewer if it converged
+    pub end_sample: u32,
+    pub film: Film,
+}
+
+// 64 bit FNV-1a, fed with formatted text
+struct Fnv1a(u64);
+
+impl fmt::Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+        Ok(())
+    }
+}
+
+// Hash of the debug representation of everything that influences the rendered image
+pub fn fingerprint(parts: &[&dyn fmt::Debug]) -> u64 {
+    use std::fmt::Write as _;
+    let mut hasher = Fnv1a(0xcbf29ce484222325);
+    for part in parts {
+        write!(hasher, "{:?};", part).expect("Hashing can't fail");
+    }
+    hasher.0
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Checkpoint {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path, |out| self.write_to(out))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        Checkpoint::read_from(&mut io::BufReader::new(fs::File::open(path)?))
+    }
+
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.fingerprint.to_le_bytes())?;
+        out.write_all(&self.end_sample.to_le_bytes())?;
+        self.film.write_to(out)
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Checkpoint> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a checkpoint file"));
+        }
+        let mut fingerprint = [0; 8];
+        input.read_exact(&mut fingerprint)?;
+        let mut end_sample = [0; 4];
+        input.read_exact(&mut end_sample)?;
+        Ok(Checkpoint {
+            fingerprint: u64::from_le_bytes(fingerprint),
+            end_sample: u32::from_le_bytes(end_sample),
+            film: Film::read_from(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::PathRecord;
+    use crate::denoise::Features;
+    use crate::film::spiral_tiles;
+    use crate::filter::Filter;
+    use crate::vec3::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut film = Film::new(3, 2);
+        for tile in spiral_tiles(3, 2, 2) {
+            let mut buffer = film.tile_buffer(
+                &tile,
+                Filter::Mitchell {
+                    radius: 2.0,
+                    b: 1.0 / 3.0,
+                    c: 1.0 / 3.0,
+                },
+            );
+            for y in tile.rows() {
+                for x in tile.x0..tile.x0 + tile.width {
+                    let position = (x as f64 + 0.3, y as f64 + 0.6);
+                    let mut record = PathRecord::zero();
+                    record.features =
+                        Features::new(Vec3::new(0.5, 0.1, 0.9), Vec3::new(0.0, 0.6, 0.8), 2.5);
+                    record.object_id = x + 1;
+                    record.add_light(1, &Vec3::new(0.25, 0.5, 0.125));
+                    let colors = [
+                        Vec3::new(x as f64 / 3.0, 0.2, 0.1),
+                        Vec3::new(0.7, y as f64, 1.0 / 7.0),
+                    ];
+                    for color in &colors {
+                        buffer.add_sample(x, y, position, color, &record);
+                    }
+                }
+            }
+            film.merge_tile(buffer);
+        }
+        let checkpoint = Checkpoint {
+            fingerprint: fingerprint(&[&"scene", &42]),
+            end_sample: 2,
+            film,
+        };
+        let mut data = Vec::new();
+        checkpoint.write_to(&mut data).unwrap();
+        let loaded = Checkpoint::read_from(&mut data.as_slice()).unwrap();
+        assert_eq!(loaded.fingerprint, checkpoint.fingerprint);
+        assert_eq!(loaded.end_sample, 2);
+        // Bit exact, so that a resumed render matches an uninterrupted one
+        let mut reencoded = Vec::new();
+        loaded.write_to(&mut reencoded).unwrap();
+        assert_eq!(data, reencoded);
+
+        assert!(Checkpoint::read_from(&mut &data[..data.len() - 1]).is_err());
+        assert!(Checkpoint::read_from(&mut &b"P3 1 1 255"[..]).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_detects_changes() {
+        assert_eq!(fingerprint(&[&1.5, &"a"]), fingerprint(&[&1.5, &"a"]));
+        assert_ne!(fingerprint(&[&1.5, &"a"]), fingerprint(&[&1.25, &"a"]));
+        assert_ne!(fingerprint(&[&"ab", &"c"]), fingerprint(&[&"a", &"bc"]));
+    }
+}
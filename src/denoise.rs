@@ -0,0 +1,239 @@
+// Edge-avoiding À-trous wavelet filter (Dammertz et al.), with the variance guided
+// luminance weights of SVGF. Noise is filtered out of the irradiance (color divided by
+// albedo), so that texture detail survives; features of the first hit keep it from
+// blurring across edges. Luminance is compared in color space, where the variance of
+// every pixel is known from its samples.
+use crate::vec3::*;
+use rayon::prelude::*;
+use std::io;
+use std::io::{Read, Write};
+
+// What the camera ray of a sample hit first
+#[derive(Debug, Clone)]
+pub struct Features {
+    pub albedo: Color,
+    // Zero where the ray escaped
+    pub normal: Vec3,
+    // Distance along the ray, zero where it escaped
+    pub depth: f64,
+}
+
+impl Features {
+    pub fn new(albedo: Color, normal: Vec3, depth: f64) -> Features {
+        Features {
+            albedo,
+            normal,
+            depth,
+        }
+    }
+
+    pub fn zero() -> Features {
+        Features::new(Vec3::zero(), Vec3::zero(), 0.0)
+    }
+
+    pub fn add(&mut self, other: &Features) {
+        self.albedo.add_cum(&other.albedo);
+        self.normal.add_cum(&other.normal);
+        self.depth += other.depth;
+    }
+
+    pub fn scaled(&self, s: f64) -> Features {
+        Features::new(s * &self.albedo, s * &self.normal, s * self.depth)
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (a, n) = (&self.albedo, &self.normal);
+        for v in [a.e0, a.e1, a.e2, n.e0, n.e1, n.e2, self.depth] {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Features> {
+        let mut values = [0.0; 7];
+        for v in values.iter_mut() {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            *v = f64::from_le_bytes(bytes);
+        }
+        Ok(Features::new(
+            Vec3::new(values[0], values[1], values[2]),
+            Vec3::new(values[3], values[4], values[5]),
+            values[6],
+        ))
+    }
+}
+
+// Each level doubles the distance between the taps, 5 levels reach 64 pixels wide
+const LEVELS: usize = 5;
+// B3 spline
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// Luminance differences in standard deviations of the noise
+const SIGMA_LUMINANCE: f64 = 4.0;
+const SIGMA_NORMAL: f64 = 0.3;
+// Relative to the depth
+const SIGMA_DEPTH: f64 = 0.05;
+const SIGMA_ALBEDO: f64 = 0.1;
+// Keeps black surfaces from dividing by zero
+const MIN_ALBEDO: f64 = 0.01;
+
+fn demodulation(features: &Features) -> Color {
+    let a = &features.albedo;
+    Vec3::new(
+        a.e0.max(MIN_ALBEDO),
+        a.e1.max(MIN_ALBEDO),
+        a.e2.max(MIN_ALBEDO),
+    )
+}
+
+// Edge stopping weight between the features of two pixels, ignoring luminance
+fn feature_weight(p: &Features, q: &Features) -> f64 {
+    let normal = (&p.normal - &q.normal).length_squared() / (SIGMA_NORMAL * SIGMA_NORMAL);
+    let depth_scale = SIGMA_DEPTH * p.depth.max(q.depth).max(1e-6);
+    let depth = ((p.depth - q.depth) / depth_scale).powi(2);
+    let albedo = (&p.albedo - &q.albedo).length_squared() / (SIGMA_ALBEDO * SIGMA_ALBEDO);
+    (-(normal + depth + albedo)).exp()
+}
+
+// 3x3 Gaussian blur, a more stable estimate of the variance for the luminance weights
+fn blur(width: usize, height: usize, values: &[f64]) -> Vec<f64> {
+    const WEIGHTS: [f64; 3] = [0.25, 0.5, 0.25];
+    (0..width * height)
+        .map(|p| {
+            let (x, y) = (p % width, p / width);
+            let (mut sum, mut weight_sum) = (0.0, 0.0);
+            for (j, wj) in WEIGHTS.iter().enumerate() {
+                for (i, wi) in WEIGHTS.iter().enumerate() {
+                    let (qx, qy) = ((x + i).wrapping_sub(1), (y + j).wrapping_sub(1));
+                    if qx < width && qy < height {
+                        sum += wi * wj * values[qy * width + qx];
+                        weight_sum += wi * wj;
+                    }
+                }
+            }
+            sum / weight_sum
+        })
+        .collect()
+}
+
+// Filters a width x height image, given row by row along with the mean first hit
+// features and the variance of the mean luminance of every pixel
+pub fn denoise(
+    width: usize,
+    height: usize,
+    colors: &[Color],
+    features: &[Features],
+    variances: &[f64],
+) -> Vec<Color> {
+    let mut irradiance: Vec<Color> = colors
+        .iter()
+        .zip(features)
+        .map(|(c, f)| {
+            let a = demodulation(f);
+            Vec3::new(c.e0 / a.e0, c.e1 / a.e1, c.e2 / a.e2)
+        })
+        .collect();
+    let mut variance = variances.to_vec();
+
+    for level in 0..LEVELS {
+        let step = 1 << level;
+        let sigmas = blur(width, height, &variance);
+        let luminances: Vec<f64> = irradiance
+            .iter()
+            .zip(features)
+            .map(|(c, f)| c.hadamard(&demodulation(f)).luminance())
+            .collect();
+        let filtered: Vec<(Color, f64)> = (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = (p % width, p / width);
+                let luminance = luminances[p];
+                let sigma = SIGMA_LUMINANCE * sigmas[p].sqrt() + 1e-10;
+                let mut color = Vec3::zero();
+                let (mut weight_sum, mut variance_sum) = (0.0, 0.0);
+                for (j, kj) in KERNEL.iter().enumerate() {
+                    let qy = (y + j * step).wrapping_sub(2 * step);
+                    for (i, ki) in KERNEL.iter().enumerate() {
+                        let qx = (x + i * step).wrapping_sub(2 * step);
+                        if qx >= width || qy >= height {
+                            continue;
+                        }
+                        let q = qy * width + qx;
+                        let luminance_weight = (-(luminance - luminances[q]).abs() / sigma).exp();
+                        let weight =
+                            ki * kj * luminance_weight * feature_weight(&features[p], &features[q]);
+                        color.add_cum(&(weight * &irradiance[q]));
+                        weight_sum += weight;
+                        variance_sum += weight * weight * variance[q];
+                    }
+                }
+                // The center tap always has a weight of one
+                (
+                    &color / weight_sum,
+                    variance_sum / (weight_sum * weight_sum),
+                )
+            })
+            .collect();
+        (irradiance, variance) = filtered.into_iter().unzip();
+    }
+
+    irradiance
+        .iter()
+        .zip(features)
+        .map(|(c, f)| c.hadamard(&demodulation(f)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::hash_to_unit;
+
+    #[test]
+    fn test_denoise_keeps_edges() {
+        // A grey wall in the left half and a white floor with a different normal in the
+        // right half, both under noise of known variance
+        let (width, height) = (32, 16);
+        let variance: f64 = 0.01;
+        let mut colors = Vec::new();
+        let mut features = Vec::new();
+        let mut variances = Vec::new();
+        for p in 0..width * height {
+            let floor = p % width >= width / 2;
+            let noise = (12.0 * variance).sqrt() * (hash_to_unit(&[p as f64]) - 0.5);
+            let (albedo, normal) = if floor {
+                (Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.0, 1.0, 0.0))
+            } else {
+                (Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0))
+            };
+            let value = if floor { 1.0 } else { 0.2 };
+            colors.push((value + noise) * &albedo);
+            variances.push(variance * albedo.luminance().powi(2));
+            features.push(Features::new(albedo, normal, 5.0));
+        }
+        let denoised = denoise(width, height, &colors, &features, &variances);
+        let error = |image: &[Color]| {
+            let squared: f64 = (0..width * height)
+                .map(|p| {
+                    let floor = p % width >= width / 2;
+                    let expected = if floor { 1.0 } else { 0.1 };
+                    (image[p].e0 - expected).powi(2)
+                })
+                .sum();
+            (squared / (width * height) as f64).sqrt()
+        };
+        assert!(error(&denoised) < 0.25 * error(&colors));
+        // Nothing bleeds over the edge
+        assert!((denoised[width / 2 - 1].e1 - 0.1).abs() < 0.05);
+        assert!((denoised[width / 2].e1 - 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_features_round_trip() {
+        let features = Features::new(Vec3::new(0.1, 0.2, 0.3), Vec3::new(0.0, 1.0, 0.0), 4.5);
+        let mut bytes = Vec::new();
+        features.write_to(&mut bytes).unwrap();
+        let read = Features::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", features));
+    }
+}
@@ -0,0 +1,145 @@
+// Minimal OpenEXR writer: a single part scanline image with uncompressed 32 bit float
+// channels. Channel names may contain a layer prefix ("normal.X"), which is how
+// compositors group the channels of a multi-layer file.
+use std::io;
+use std::io::Write;
+
+pub struct Channel {
+    pub name: String,
+    // Row by row, top row first
+    pub values: Vec<f32>,
+}
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+// Version 2, single part scanline file
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn attribute<W: Write>(out: &mut W, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    for s in [name, kind] {
+        out.write_all(s.as_bytes())?;
+        out.write_all(&[0])?;
+    }
+    out.write_all(&(value.len() as i32).to_le_bytes())?;
+    out.write_all(value)
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+pub fn write<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    mut channels: Vec<Channel>,
+) -> io::Result<()> {
+    // Readers expect the channels in alphabetical order, in the header and the pixels
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    for channel in &channels {
+        assert_eq!(channel.values.len(), width * height, "{}", channel.name);
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION);
+    let mut channel_list = Vec::new();
+    for channel in &channels {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // Not perceptually linear, three reserved bytes, no subsampling
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    attribute(&mut header, "channels", "chlist", &channel_list)?;
+    attribute(&mut header, "compression", "compression", &[0])?;
+    attribute(&mut header, "dataWindow", "box2i", &box2i(width, height))?;
+    attribute(&mut header, "displayWindow", "box2i", &box2i(width, height))?;
+    // Increasing y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    )?;
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    )?;
+    header.push(0);
+    out.write_all(&header)?;
+
+    // Uncompressed files store one scanline per block, the offset table points at each
+    let block_size = 8 + 4 * width * channels.len();
+    let first_block = header.len() + 8 * height;
+    for y in 0..height {
+        out.write_all(&((first_block + y * block_size) as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&((block_size - 8) as i32).to_le_bytes())?;
+        for channel in &channels {
+            for v in &channel.values[y * width..(y + 1) * width] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn read_u64(data: &[u8], at: usize) -> usize {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn test_layout() {
+        let (width, height) = (3, 2);
+        let channels = vec![
+            Channel {
+                name: "depth.Z".to_string(),
+                values: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            },
+            Channel {
+                name: "B".to_string(),
+                values: vec![0.5; 6],
+            },
+        ];
+        let mut data = Vec::new();
+        write(&mut data, width, height, channels).unwrap();
+        assert_eq!(data[..4], MAGIC);
+        // Sorted channel list
+        let b = data.windows(2).position(|w| w == b"B\0").unwrap();
+        let depth = data.windows(8).position(|w| w == b"depth.Z\0").unwrap();
+        assert!(b < depth);
+
+        let block_size = 8 + 4 * width * 2;
+        let first = read_u64(&data, data.len() - 2 * block_size - 16);
+        assert_eq!(first, data.len() - 2 * block_size);
+        assert_eq!(
+            read_u64(&data, data.len() - 2 * block_size - 8),
+            first + block_size
+        );
+        // Second scanline: y, size, then B and depth.Z of its pixels
+        let line = &data[first + block_size..];
+        let value = |i: usize| f32::from_le_bytes(line[8 + 4 * i..12 + 4 * i].try_into().unwrap());
+        assert_eq!(i32::from_le_bytes(line[..4].try_into().unwrap()), 1);
+        assert_eq!(value(0), 0.5);
+        assert_eq!(value(3), 4.0);
+        assert_eq!(value(5), 6.0);
+    }
+}
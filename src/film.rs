@@ -0,0 +1,505 @@
+use crate::aov::{Aov, PathRecord};
+use crate::denoise::denoise;
+use crate::filter::Filter;
+use crate::image::Image;
+use crate::sampling::ColorSampler;
+use crate::vec3::*;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Read, Write};
+
+// Rectangular block of pixels, rendered as a unit of work
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    pub fn rows(&self) -> std::ops::Range<u32> {
+        self.y0..self.y0 + self.height
+    }
+
+    pub fn pixel_count(&self) -> u64 {
+        (self.width * self.height) as u64
+    }
+}
+
+// Square tiles covering a width x height image, ordered in a spiral that starts at
+// the center where the interesting parts of an image usually are
+pub fn spiral_tiles(width: u32, height: u32, size: u32) -> Vec<Tile> {
+    let (nx, ny) = (width.div_ceil(size) as i64, height.div_ceil(size) as i64);
+    let (mut x, mut y) = ((nx - 1) / 2, (ny - 1) / 2);
+    let (mut dx, mut dy) = (1, 0);
+    let (mut leg_length, mut leg_steps, mut legs) = (1, 0, 0);
+    let mut tiles = Vec::with_capacity((nx * ny) as usize);
+    while tiles.len() < (nx * ny) as usize {
+        if (0..nx).contains(&x) && (0..ny).contains(&y) {
+            let (x0, y0) = (x as u32 * size, y as u32 * size);
+            tiles.push(Tile {
+                x0,
+                y0,
+                width: size.min(width - x0),
+                height: size.min(height - y0),
+            });
+        }
+        x += dx;
+        y += dy;
+        leg_steps += 1;
+        if leg_steps == leg_length {
+            // Turn, the legs grow after every second turn
+            leg_steps = 0;
+            (dx, dy) = (-dy, dx);
+            legs += 1;
+            if legs % 2 == 0 {
+                leg_length += 1;
+            }
+        }
+    }
+    tiles
+}
+
+// Filter weighted sum of the samples around a pixel
+#[derive(Debug, Clone)]
+struct WeightedSum {
+    color: Color,
+    weight: f64,
+}
+
+impl WeightedSum {
+    fn zero() -> WeightedSum {
+        WeightedSum {
+            color: Vec3::zero(),
+            weight: 0.0,
+        }
+    }
+
+    fn add(&mut self, other: &WeightedSum) {
+        self.color.add_cum(&other.color);
+        self.weight += other.weight;
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<WeightedSum> {
+        let mut values = [0.0; 4];
+        for v in values.iter_mut() {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            *v = f64::from_le_bytes(bytes);
+        }
+        Ok(WeightedSum {
+            color: Vec3::new(values[0], values[1], values[2]),
+            weight: values[3],
+        })
+    }
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// Filter contributions of the samples of a tile to a region that extends the tile by
+// the filter radius
+#[derive(Debug, Clone)]
+struct Splats {
+    region: Tile,
+    sums: Vec<WeightedSum>,
+}
+
+// Accumulates the samples of every pixel over any number of render passes.
+// Row 0 is the top row of the image. Every pixel keeps the statistics of its own
+// samples (for sample indices and adaptive sampling) and the summed records of their
+// paths (for the denoiser and AOVs). Every tile keeps the filtered contributions of its
+// samples, the image sums those of all tiles around a pixel.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<ColorSampler>,
+    records: Vec<PathRecord>,
+    // By the top left corner of the tile, row first. Overlapping contributions are
+    // added in this order, the order tiles finish in would change their rounding.
+    splats: BTreeMap<(u32, u32), Splats>,
+}
+
+// Work space of a tile: its pixels and the filter contributions of its samples
+pub struct TileBuffer {
+    pub tile: Tile,
+    pixels: Vec<ColorSampler>,
+    records: Vec<PathRecord>,
+    filter: Filter,
+    splats: Splats,
+}
+
+impl TileBuffer {
+    pub fn pixel(&self, x: u32, y: u32) -> &ColorSampler {
+        &self.pixels[((y - self.tile.y0) * self.tile.width + x - self.tile.x0) as usize]
+    }
+
+    // Adds a sample of pixel (x, y) that was taken at the continuous image position,
+    // pixel (x, y) covers [x, x + 1) x [y, y + 1)
+    pub fn add_sample(
+        &mut self,
+        x: u32,
+        y: u32,
+        position: (f64, f64),
+        color: &Color,
+        record: &PathRecord,
+    ) {
+        let index = ((y - self.tile.y0) * self.tile.width + x - self.tile.x0) as usize;
+        let first = self.pixels[index].sample_num() == 0;
+        self.records[index].add(record, first);
+        self.pixels[index].add(color);
+
+        // Pixels whose center is within the filter radius
+        let radius = self.filter.radius();
+        let region = &self.splats.region;
+        let range = |p: f64, start: u32, len: u32| {
+            let first = (p - 0.5 - radius).ceil().max(start as f64) as u32;
+            let last = (p - 0.5 + radius).floor().min((start + len - 1) as f64) as u32;
+            first..=last
+        };
+        for py in range(position.1, region.y0, region.height) {
+            for px in range(position.0, region.x0, region.width) {
+                let weight = self
+                    .filter
+                    .evaluate(px as f64 + 0.5 - position.0, py as f64 + 0.5 - position.1);
+                if weight != 0.0 {
+                    let sum = &mut self.splats.sums
+                        [((py - region.y0) * region.width + px - region.x0) as usize];
+                    sum.color.add_cum(&(weight * color));
+                    sum.weight += weight;
+                }
+            }
+        }
+    }
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![ColorSampler::new(); (width * height) as usize],
+            records: vec![PathRecord::zero(); (width * height) as usize],
+            splats: BTreeMap::new(),
+        }
+    }
+
+    fn copy_tile<T: Clone>(&self, values: &[T], tile: &Tile) -> Vec<T> {
+        tile.rows()
+            .flat_map(|y| {
+                let start = (y * self.width + tile.x0) as usize;
+                values[start..start + tile.width as usize].to_vec()
+            })
+            .collect()
+    }
+
+    // Copies the pixels and filter contributions of a tile into a buffer that samples
+    // can be added to. Every pass has to use the same filter.
+    pub fn tile_buffer(&self, tile: &Tile, filter: Filter) -> TileBuffer {
+        let margin = (filter.radius() + 0.5).ceil() as u32;
+        let (x0, y0) = (
+            tile.x0.saturating_sub(margin),
+            tile.y0.saturating_sub(margin),
+        );
+        let splat_region = Tile {
+            x0,
+            y0,
+            width: (tile.x0 + tile.width + margin).min(self.width) - x0,
+            height: (tile.y0 + tile.height + margin).min(self.height) - y0,
+        };
+        let splats = match self.splats.get(&(tile.y0, tile.x0)) {
+            Some(splats) => {
+                assert_eq!(
+                    splats.region, splat_region,
+                    "The filter changed between passes"
+                );
+                splats.clone()
+            }
+            None => Splats {
+                region: splat_region,
+                sums: vec![WeightedSum::zero(); splat_region.pixel_count() as usize],
+            },
+        };
+        TileBuffer {
+            tile: *tile,
+            pixels: self.copy_tile(&self.pixels, tile),
+            records: self.copy_tile(&self.records, tile),
+            filter,
+            splats,
+        }
+    }
+
+    // Takes over the pixels and filter contributions of a tile buffer
+    pub fn merge_tile(&mut self, buffer: TileBuffer) {
+        let tile = &buffer.tile;
+        let rows = buffer.pixels.chunks(tile.width as usize);
+        let record_rows = buffer.records.chunks(tile.width as usize);
+        for (y, (row, records)) in tile.rows().zip(rows.zip(record_rows)) {
+            let start = (y * self.width + tile.x0) as usize;
+            self.pixels[start..start + row.len()].clone_from_slice(row);
+            self.records[start..start + row.len()].clone_from_slice(records);
+        }
+        self.splats.insert((tile.y0, tile.x0), buffer.splats);
+    }
+
+    // Filter weighted sum of the samples around every pixel, row by row
+    fn sums(&self) -> Vec<WeightedSum> {
+        let mut sums = vec![WeightedSum::zero(); (self.width * self.height) as usize];
+        for splats in self.splats.values() {
+            let region = &splats.region;
+            for (y, row) in region.rows().zip(splats.sums.chunks(region.width as usize)) {
+                let start = (y * self.width + region.x0) as usize;
+                for (sum, added) in sums[start..start + row.len()].iter_mut().zip(row) {
+                    sum.add(added);
+                }
+            }
+        }
+        sums
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.sample_num() as u64).sum()
+    }
+
+    // Luminance that clamping removed, as a fraction of all light the samples carried,
+    // and the number of clamped light contributions
+    pub fn clamped(&self) -> (f64, u64) {
+        let (mut clamped, mut kept, mut count) = (0.0, 0.0, 0);
+        for record in &self.records {
+            clamped += record.clamped;
+            kept += (&(&record.emission + &record.direct) + &record.indirect).luminance();
+            count += record.clamped_samples as u64;
+        }
+        let total = clamped + kept;
+        (if total > 0.0 { clamped / total } else { 0.0 }, count)
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.width.to_le_bytes())?;
+        out.write_all(&self.height.to_le_bytes())?;
+        for pixel in &self.pixels {
+            pixel.write_to(out)?;
+        }
+        for record in &self.records {
+            record.write_to(out)?;
+        }
+        out.write_all(&(self.splats.len() as u32).to_le_bytes())?;
+        for ((y0, x0), splats) in &self.splats {
+            let region = &splats.region;
+            for v in [*y0, *x0, region.x0, region.y0, region.width, region.height] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+            for sum in &splats.sums {
+                for v in [sum.color.e0, sum.color.e1, sum.color.e2, sum.weight] {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Film> {
+        let width = read_u32(input)?;
+        let height = read_u32(input)?;
+        let count = width as usize * height as usize;
+        let pixels = (0..count)
+            .map(|_| ColorSampler::read_from(input))
+            .collect::<io::Result<_>>()?;
+        let records = (0..count)
+            .map(|_| PathRecord::read_from(input))
+            .collect::<io::Result<_>>()?;
+        let mut splats = BTreeMap::new();
+        for _ in 0..read_u32(input)? {
+            let key = (read_u32(input)?, read_u32(input)?);
+            let region = Tile {
+                x0: read_u32(input)?,
+                y0: read_u32(input)?,
+                width: read_u32(input)?,
+                height: read_u32(input)?,
+            };
+            if region.x0 + region.width > width || region.y0 + region.height > height {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Filter contributions outside of the film",
+                ));
+            }
+            let sums = (0..region.pixel_count())
+                .map(|_| WeightedSum::read_from(input))
+                .collect::<io::Result<_>>()?;
+            splats.insert(key, Splats { region, sums });
+        }
+        Ok(Film {
+            width,
+            height,
+            pixels,
+            records,
+            splats,
+        })
+    }
+
+    // Filtered estimate of every pixel, row by row
+    pub fn colors(&self) -> Vec<Color> {
+        self.sums()
+            .iter()
+            .map(|sum| {
+                // Negative lobes can cancel out all weight, the pixel has no estimate then
+                if sum.weight > 0.0 {
+                    &sum.color / sum.weight
+                } else {
+                    Vec3::zero()
+                }
+            })
+            .collect()
+    }
+
+    fn develop(&self, colors: &[Color], develop: impl Fn(&Color) -> Color) -> Image {
+        let mut image = Image::new(self.width as usize, self.height as usize);
+        for (i, color) in colors.iter().enumerate() {
+            let (x, y) = (i % self.width as usize, i / self.width as usize);
+            image.set(x, y, develop(color));
+        }
+        image
+    }
+
+    // Displayable image of the current estimate, develop maps a filtered pixel to [0,1]
+    pub fn image(&self, develop: impl Fn(&Color) -> Color) -> Image {
+        self.develop(&self.colors(), develop)
+    }
+
+    // Mean of an AOV over the samples of every pixel, row by row
+    pub fn aov(&self, aov: Aov) -> Vec<Vec3> {
+        self.records
+            .iter()
+            .zip(&self.pixels)
+            .map(|(sum, pixel)| aov.value(sum, pixel.sample_num()))
+            .collect()
+    }
+
+    // Like image, but with the noise filtered out guided by the first hit features
+    pub fn denoised_image(&self, develop: impl Fn(&Color) -> Color) -> Image {
+        let features: Vec<_> = self
+            .pixels
+            .iter()
+            .zip(&self.records)
+            .map(|(pixel, sum)| sum.features.scaled(1.0 / pixel.sample_num().max(1) as f64))
+            .collect();
+        // Variance of the mean luminance
+        let variances: Vec<_> = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.variance() / pixel.sample_num().max(1) as f64)
+            .collect();
+        let colors = denoise(
+            self.width as usize,
+            self.height as usize,
+            &self.colors(),
+            &features,
+            &variances,
+        );
+        self.develop(&colors, develop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spiral_covers_image_once() {
+        let (width, height) = (70, 45);
+        let tiles = spiral_tiles(width, height, 16);
+        let mut covered = vec![0; (width * height) as usize];
+        for tile in &tiles {
+            for y in tile.rows() {
+                for x in tile.x0..tile.x0 + tile.width {
+                    covered[(y * width + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+        // Starts in the middle
+        assert_eq!((tiles[0].x0, tiles[0].y0), (32, 16));
+    }
+
+    // Adds one sample per pixel at the given offset from its corner, with the row index
+    // as value
+    fn render(film: &mut Film, filter: Filter, offset: f64) {
+        for tile in spiral_tiles(film.width, film.height, 2) {
+            let mut buffer = film.tile_buffer(&tile, filter);
+            for y in tile.rows() {
+                for x in tile.x0..tile.x0 + tile.width {
+                    let position = (x as f64 + offset, y as f64 + offset);
+                    let color = Vec3::new(y as f64, 0.0, 0.0);
+                    buffer.add_sample(x, y, position, &color, &PathRecord::zero());
+                }
+            }
+            film.merge_tile(buffer);
+        }
+    }
+
+    #[test]
+    fn test_passes_accumulate() {
+        let mut film = Film::new(5, 3);
+        for _ in 0..2 {
+            render(&mut film, Filter::Box { radius: 0.5 }, 0.5);
+        }
+        assert_eq!(film.total_samples(), 30);
+        let image = film.image(|c| c / 4.0);
+        assert_eq!(image.get(1, 0), &Vec3::zero());
+        assert_eq!(image.get(4, 2), &Vec3::new(0.5, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_merge_order_does_not_matter() {
+        // Tiles of a pass finish in any order, overlapping filter contributions have to
+        // add up the same way regardless
+        let filter = Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        };
+        let render_in_order = |reverse: bool| {
+            let mut film = Film::new(7, 5);
+            let mut buffers: Vec<TileBuffer> = spiral_tiles(7, 5, 2)
+                .iter()
+                .map(|tile| {
+                    let mut buffer = film.tile_buffer(tile, filter);
+                    for y in tile.rows() {
+                        for x in tile.x0..tile.x0 + tile.width {
+                            let position = (x as f64 + 0.3, y as f64 + 0.7);
+                            let color = Vec3::new(1.0 / (x + 3) as f64, 0.1 * y as f64, 0.7);
+                            buffer.add_sample(x, y, position, &color, &PathRecord::zero());
+                        }
+                    }
+                    buffer
+                })
+                .collect();
+            if reverse {
+                buffers.reverse();
+            }
+            for buffer in buffers {
+                film.merge_tile(buffer);
+            }
+            film.colors()
+        };
+        assert_eq!(render_in_order(false), render_in_order(true));
+    }
+
+    #[test]
+    fn test_samples_reach_neighbours() {
+        // A sample on the top left corner of a pixel is shared equally by the four
+        // pixels around it, so a pixel averages its own row and the one below
+        let mut film = Film::new(5, 3);
+        render(&mut film, Filter::Tent { radius: 1.0 }, 0.0);
+        let image = film.image(|c| c.clone());
+        assert!((image.get(2, 0).e0 - 0.5).abs() < 1e-12);
+        assert!((image.get(3, 1).e0 - 1.5).abs() < 1e-12);
+        // Nothing below the last row
+        assert!((image.get(4, 2).e0 - 2.0).abs() < 1e-12);
+        // Samples of a pixel keep their own statistics
+        assert_eq!(film.total_samples(), 15);
+    }
+}
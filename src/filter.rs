@@ -0,0 +1,130 @@
+// Pixel reconstruction filters. A sample contributes to every pixel whose center lies
+// within the filter radius, weighted by the filter at the offset to that center.
+// All filters are separable, the 2D weight is the product of the 1D weights.
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // Radius 0.5 gives every pixel the plain mean of its own samples
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    // Shifted down so that it reaches zero at the radius
+    Gaussian { radius: f64, sigma: f64 },
+    // Mitchell-Netravali cubic stretched over the radius, B = C = 1/3 is the
+    // recommended trade-off between blurring and ringing
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // Windowed sinc with as many lobes as the radius
+    Lanczos { radius: f64 },
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    // Weight of a sample at offset (dx, dy) from a pixel center
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        let radius = self.radius();
+        if x >= radius {
+            // Exclusive, so that a sample on the edge of a box belongs to one pixel only
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => mitchell(2.0 * x / radius, b, c),
+            Filter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+// Mitchell-Netravali cubic on [0, 2]
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let (x2, x3) = (x * x, x * x * x);
+    let value = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)
+    } else {
+        (-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    };
+    value / 6.0
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_filters() -> Vec<Filter> {
+        vec![
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.0 },
+            Filter::Gaussian {
+                radius: 1.5,
+                sigma: 0.5,
+            },
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 3.0 },
+        ]
+    }
+
+    #[test]
+    fn test_filter_support() {
+        for filter in all_filters() {
+            let r = filter.radius();
+            assert!(filter.evaluate(0.0, 0.0) > 0.0, "{:?}", filter);
+            assert_eq!(filter.evaluate(r, 0.0), 0.0, "{:?}", filter);
+            assert_eq!(filter.evaluate(0.1, -r - 0.1), 0.0, "{:?}", filter);
+            assert_eq!(
+                filter.evaluate(0.3 * r, -0.2 * r),
+                filter.evaluate(-0.3 * r, 0.2 * r)
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_shapes() {
+        let tent = Filter::Tent { radius: 2.0 };
+        assert_eq!(tent.evaluate(1.0, 0.0), 2.0);
+        // Mitchell is continuous where the two cubics meet and has a small negative lobe
+        let mitchell = Filter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        let (below, above) = (
+            mitchell.evaluate(1.0 - 1e-9, 0.0),
+            mitchell.evaluate(1.0 + 1e-9, 0.0),
+        );
+        assert!((below - above).abs() < 1e-6);
+        assert!(mitchell.evaluate(1.5, 0.0) < 0.0);
+        assert!(Filter::Lanczos { radius: 3.0 }.evaluate(1.5, 0.0) < 0.0);
+    }
+}
@@ -0,0 +1,97 @@
+// Firefly suppression: clamping of the light that single samples carry, and Russian
+// roulette, which ends paths that carry little light early.
+use crate::aov::PathRecord;
+use crate::sampler::SampleStream;
+use crate::vec3::*;
+
+// Russian roulette leaves the first bounces alone, they carry most of the light
+pub const ROULETTE_MIN_BOUNCES: u32 = 3;
+// Keeps the weight of surviving paths bounded
+const ROULETTE_MIN_SURVIVAL: f64 = 0.05;
+
+// Scales light down so that no component exceeds the limit, which keeps its hue. The
+// luminance that was removed is recorded.
+pub fn clamp(light: Color, limit: Option<f64>, record: &mut PathRecord) -> Color {
+    let max = light.e0.max(light.e1).max(light.e2);
+    match limit {
+        Some(limit) if max > limit => {
+            let clamped = (limit / max) * &light;
+            record.clamped += light.luminance() - clamped.luminance();
+            record.clamped_samples += 1;
+            clamped
+        }
+        _ => light,
+    }
+}
+
+// Paths whose throughput (largest component) dropped below one survive with that
+// probability and carry more weight if they do, which keeps the expected value. Returns
+// the weight, None ends the path.
+pub fn roulette(bounce: u32, throughput: f64, sampler: &mut SampleStream) -> Option<f64> {
+    if bounce < ROULETTE_MIN_BOUNCES || throughput >= 1.0 {
+        return Some(1.0);
+    }
+    let survival = throughput.max(ROULETTE_MIN_SURVIVAL);
+    if sampler.get_1d() < survival {
+        Some(1.0 / survival)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn test_clamp() {
+        let mut record = PathRecord::zero();
+        let light = Vec3::new(4.0, 2.0, 1.0);
+        let clamped = clamp(light.clone(), Some(2.0), &mut record);
+        // Scaled by the largest component
+        assert_eq!(clamped, Vec3::new(2.0, 1.0, 0.5));
+        assert!((record.clamped - light.luminance() / 2.0).abs() < 1e-12);
+        assert_eq!(record.clamped_samples, 1);
+
+        // Light below the limit, or without one, passes unchanged
+        assert_eq!(clamp(light.clone(), Some(4.0), &mut record), light);
+        assert_eq!(clamp(light.clone(), None, &mut record), light);
+        assert_eq!(record.clamped_samples, 1);
+    }
+
+    #[test]
+    fn test_roulette_keeps_expected_value() {
+        let sampler = IndependentSampler::new(1);
+        let mut stream = SampleStream::new(&sampler);
+        let n = 20000;
+        let (mut survivors, mut sum) = (0, 0.0);
+        for i in 0..n {
+            stream.start_pixel_sample((0, 0), i);
+            if let Some(weight) = roulette(ROULETTE_MIN_BOUNCES, 0.25, &mut stream) {
+                // Survivors carry 1 / p
+                assert_eq!(weight, 4.0);
+                survivors += 1;
+                sum += weight;
+            }
+        }
+        let survival = survivors as f64 / n as f64;
+        assert!((survival - 0.25).abs() < 0.01, "survival {}", survival);
+        assert!(
+            (sum / n as f64 - 1.0).abs() < 0.04,
+            "mean weight {}",
+            sum / n as f64
+        );
+    }
+
+    #[test]
+    fn test_roulette_spares_first_bounces() {
+        let sampler = IndependentSampler::new(1);
+        let mut stream = SampleStream::new(&sampler);
+        for bounce in 0..ROULETTE_MIN_BOUNCES {
+            assert_eq!(roulette(bounce, 0.01, &mut stream), Some(1.0));
+        }
+        // Bright paths aren't played either
+        assert_eq!(roulette(ROULETTE_MIN_BOUNCES, 1.5, &mut stream), Some(1.0));
+    }
+}
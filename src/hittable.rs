@@ -1,24 +1,67 @@
 use crate::materials::Material;
 use crate::ray::*;
+use crate::sampling::hash_to_unit;
+use crate::stats::Stats;
+use crate::texture::TexCoord;
 use crate::vec3::*;
+use std::fmt;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Surface {
     Inside,
     Outside,
 }
 
-#[derive(Debug)]
+// Partial derivatives of the hit location with respect to the texture coordinates
+#[derive(Debug, Clone)]
+pub struct Tangents {
+    pub dpdu: Loc,
+    pub dpdv: Loc,
+}
+
+#[derive(Debug, Clone)]
 pub struct Hit<'a> {
     pub location: Loc,
     pub normal: Loc,
     pub t: f64,
     pub material: &'a dyn Material,
     pub surface: Surface,
+    pub uv: TexCoord,
+    pub tangents: Tangents,
+    // Position of the hit object in the world, counting from one
+    pub object: u32,
+}
+
+pub trait Hittable: fmt::Debug {
+    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit<'_>>;
+
+    // Adds the intersection tests that a call of hit does to the counters
+    fn count_tests(&self, _stats: &mut Stats) {}
 }
 
-pub trait Hittable {
-    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit>;
+// Decides whether a candidate intersection at ray parameter t is kept. Rays pass through
+// fractionally opaque surfaces at random; hashing the ray makes the decision repeatable.
+pub fn passes_alpha_test(
+    material: &dyn Material,
+    ray: &Ray,
+    t: f64,
+    uv: TexCoord,
+    location: &Loc,
+) -> bool {
+    let opacity = material.opacity(uv, location);
+    if opacity >= 1.0 {
+        return true;
+    }
+    opacity > 0.0
+        && hash_to_unit(&[
+            ray.orig.e0,
+            ray.orig.e1,
+            ray.orig.e2,
+            ray.dir.e0,
+            ray.dir.e1,
+            ray.dir.e2,
+            t,
+        ]) < opacity
 }
 
 impl<'a> Hit<'a> {
@@ -28,14 +71,19 @@ impl<'a> Hit<'a> {
         t: f64,
         material: &'a dyn Material,
         surface: Surface,
+        uv: TexCoord,
+        tangents: Tangents,
     ) -> Hit<'a> {
         assert!((normal.length() - 1.0).abs() <= 0.0001);
         Hit {
-            location: location,
-            normal: normal,
-            t: t,
-            material: material,
-            surface: surface,
+            location,
+            normal,
+            t,
+            material,
+            surface,
+            uv,
+            tangents,
+            object: 0,
         }
     }
 
@@ -45,12 +93,30 @@ impl<'a> Hit<'a> {
         t: f64,
         ray: &Ray,
         material: &'a dyn Material,
+        uv: TexCoord,
+        tangents: Tangents,
     ) -> Hit<'a> {
         if ray.dir.dot(&outward_normal) > 0.0 {
             assert!(ray.dir.dot(&-&(outward_normal)) < 0.0);
-            Hit::new(location, -outward_normal, t, material, Surface::Inside)
+            Hit::new(
+                location,
+                -outward_normal,
+                t,
+                material,
+                Surface::Inside,
+                uv,
+                tangents,
+            )
         } else {
-            Hit::new(location, outward_normal, t, material, Surface::Outside)
+            Hit::new(
+                location,
+                outward_normal,
+                t,
+                material,
+                Surface::Outside,
+                uv,
+                tangents,
+            )
         }
     }
 }
@@ -1,36 +1,55 @@
 use crate::hittable::*;
 use crate::ray::*;
+use crate::stats::{self, Stats};
 
+#[derive(Debug)]
 pub struct HittableList<'a> {
     objects: Vec<Box<dyn Hittable + Send + Sync + 'a>>,
+    // Intersection tests of one hit call, counted once per call instead of per object.
+    // Nested lists count their own.
+    tests: Stats,
 }
 
 impl<'a> HittableList<'a> {
     #[allow(dead_code)]
     pub fn new(vec: Vec<Box<dyn Hittable + Send + Sync + 'a>>) -> HittableList<'a> {
-        HittableList { objects: vec }
+        let mut tests = Stats::default();
+        for object in &vec {
+            object.count_tests(&mut tests);
+        }
+        HittableList {
+            objects: vec,
+            tests,
+        }
     }
 
     pub fn empty() -> HittableList<'a> {
-        HittableList { objects: vec![] }
+        HittableList {
+            objects: vec![],
+            tests: Stats::default(),
+        }
     }
 
     pub fn add<T: Hittable + Send + Sync + 'a>(&mut self, obj: T) {
+        obj.count_tests(&mut self.tests);
         self.objects.push(Box::new(obj));
     }
 
     #[allow(dead_code)]
     pub fn clear(&mut self) {
         self.objects.clear();
+        self.tests = Stats::default();
     }
 }
 
 impl<'a> Hittable for HittableList<'a> {
-    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit<'_>> {
+        stats::count(|s| s.merge(&self.tests));
         let mut closest_hit_opt: Option<Hit> = None;
 
-        for hittable in &self.objects {
-            if let Some(hit) = hittable.hit(ray, validate_t) {
+        for (i, hittable) in self.objects.iter().enumerate() {
+            if let Some(mut hit) = hittable.hit(ray, validate_t) {
+                hit.object = i as u32 + 1;
                 match closest_hit_opt {
                     Some(closest_hit) if closest_hit.t.abs() > hit.t.abs() => {
                         closest_hit_opt = Some(hit)
@@ -43,3 +62,38 @@ impl<'a> Hittable for HittableList<'a> {
         closest_hit_opt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Lambertian;
+    use crate::quad::Quad;
+    use crate::sphere::Sphere;
+    use crate::vec3::*;
+
+    #[test]
+    fn test_counts_tests_per_call() {
+        let gray = || Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let mut world = HittableList::empty();
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -2.0), 0.5, gray()));
+        world.add(Sphere::new(Vec3::new(0.0, 0.0, -4.0), 0.5, gray()));
+        let mut inner = HittableList::empty();
+        inner.add(Quad::new(
+            Vec3::new(-1.0, -1.0, -6.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            gray(),
+        ));
+        world.add(inner);
+
+        stats::take();
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0));
+        assert!(world.hit(&ray, &|t| t > 1e-3).is_some());
+        world.hit(&ray, &|t| t > 1e-3);
+        let counted = stats::take();
+        // Every object once per call, the quad through the nested list
+        assert_eq!(counted.sphere_tests, 4);
+        assert_eq!(counted.quad_tests, 2);
+        assert_eq!(counted.triangle_tests, 0);
+    }
+}
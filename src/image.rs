@@ -0,0 +1,239 @@
+use crate::vec3::*;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+// Image with color values in [0,1], row 0 is the top row
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Color>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Splits off the next whitespace separated token of a netpbm header, skipping comments
+fn next_token<'a>(data: &'a [u8], pos: &mut usize) -> io::Result<&'a [u8]> {
+    loop {
+        while *pos < data.len() && data[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if *pos < data.len() && data[*pos] == b'#' {
+            while *pos < data.len() && data[*pos] != b'\n' {
+                *pos += 1;
+            }
+        } else {
+            break;
+        }
+    }
+    let start = *pos;
+    while *pos < data.len() && !data[*pos].is_ascii_whitespace() {
+        *pos += 1;
+    }
+    if start == *pos {
+        return Err(invalid_data("Unexpected end of netpbm file"));
+    }
+    Ok(&data[start..*pos])
+}
+
+fn next_number(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    std::str::from_utf8(next_token(data, pos)?)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data("Malformed number in netpbm file"))
+}
+
+// Writes to a temporary file next to path first, readers of path never see a
+// partially written file
+pub fn write_atomically<P, F>(path: P, write: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<()>,
+{
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    write(&mut file)?;
+    file.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![Vec3::zero(); width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &Color {
+        &self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, c: Color) {
+        self.pixels[y * self.width + x] = c;
+    }
+
+    // Black width x height image with this one placed at x, y
+    pub fn embedded(&self, width: usize, height: usize, x: usize, y: usize) -> Image {
+        let mut image = Image::new(width, height);
+        for row in 0..self.height {
+            let start = (y + row) * width + x;
+            image.pixels[start..start + self.width]
+                .clone_from_slice(&self.pixels[row * self.width..(row + 1) * self.width]);
+        }
+        image
+    }
+
+    // ASCII PPM with 8 bits per channel
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "P3\n{} {}\n255", self.width, self.height)?;
+        for c in &self.pixels {
+            writeln!(out, "{}", color_string(c))?;
+        }
+        out.flush()
+    }
+
+    pub fn save_ppm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path, |out| self.write_ppm(out))
+    }
+
+    // Reads binary or ASCII PPM (color) and PGM (grayscale) files
+    pub fn read_netpbm<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+        Image::parse_netpbm(&fs::read(path)?)
+    }
+
+    pub fn parse_netpbm(data: &[u8]) -> io::Result<Image> {
+        let mut pos = 0;
+        let (channels, binary) = match next_token(data, &mut pos)? {
+            b"P2" => (1, false),
+            b"P3" => (3, false),
+            b"P5" => (1, true),
+            b"P6" => (3, true),
+            _ => return Err(invalid_data("Unsupported netpbm format")),
+        };
+        let width = next_number(data, &mut pos)?;
+        let height = next_number(data, &mut pos)?;
+        if width == 0 || height == 0 {
+            return Err(invalid_data("Empty netpbm image"));
+        }
+        let max_value = next_number(data, &mut pos)?;
+        if max_value == 0 || max_value > 65535 {
+            return Err(invalid_data("Invalid netpbm max value"));
+        }
+
+        let too_large = || invalid_data("Netpbm image too large");
+        let count = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(channels))
+            .ok_or_else(too_large)?;
+        let values: Vec<usize> = if binary {
+            // Exactly one whitespace character separates header and data
+            pos += 1;
+            let bytes_per_value = if max_value < 256 { 1 } else { 2 };
+            let end = count
+                .checked_mul(bytes_per_value)
+                .and_then(|bytes| bytes.checked_add(pos))
+                .ok_or_else(too_large)?;
+            let raw = data
+                .get(pos..end)
+                .ok_or_else(|| invalid_data("Truncated netpbm data"))?;
+            if bytes_per_value == 1 {
+                raw.iter().map(|&b| b as usize).collect()
+            } else {
+                raw.chunks(2)
+                    .map(|c| ((c[0] as usize) << 8) | c[1] as usize)
+                    .collect()
+            }
+        } else {
+            (0..count)
+                .map(|_| next_number(data, &mut pos))
+                .collect::<io::Result<_>>()?
+        };
+
+        let scale = 1.0 / max_value as f64;
+        let pixels = values
+            .chunks(channels)
+            .map(|c| {
+                if channels == 1 {
+                    let v = c[0] as f64 * scale;
+                    Vec3::new(v, v, v)
+                } else {
+                    Vec3::new(c[0] as f64, c[1] as f64, c[2] as f64) * scale
+                }
+            })
+            .collect();
+        Ok(Image {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ascii_ppm() {
+        let img = Image::parse_netpbm(b"P3\n# comment\n2 1\n255\n255 0 0  0 0 255\n").unwrap();
+        assert_eq!((img.width, img.height), (2, 1));
+        assert_eq!(img.get(0, 0), &Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(img.get(1, 0), &Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_parse_binary_pgm() {
+        let mut data = b"P5 2 1 255\n".to_vec();
+        data.extend_from_slice(&[0, 255]);
+        let img = Image::parse_netpbm(&data).unwrap();
+        assert_eq!(img.get(0, 0), &Vec3::zero());
+        assert_eq!(img.get(1, 0), &Vec3::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_write_ppm_round_trip() {
+        let mut img = Image::new(2, 1);
+        img.set(1, 0, Vec3::new(1.0, 0.0, 1.0));
+        let mut data = Vec::new();
+        img.write_ppm(&mut data).unwrap();
+        let parsed = Image::parse_netpbm(&data).unwrap();
+        assert_eq!((parsed.width, parsed.height), (2, 1));
+        assert_eq!(parsed.get(1, 0), img.get(1, 0));
+    }
+
+    #[test]
+    fn test_embedded() {
+        let mut img = Image::new(2, 1);
+        img.set(0, 0, Vec3::new(1.0, 0.0, 0.0));
+        img.set(1, 0, Vec3::new(0.0, 1.0, 0.0));
+        let frame = img.embedded(4, 3, 1, 2);
+        assert_eq!(frame.get(1, 2), img.get(0, 0));
+        assert_eq!(frame.get(2, 2), img.get(1, 0));
+        assert_eq!(frame.get(0, 2), &Vec3::zero());
+        assert_eq!(frame.get(1, 1), &Vec3::zero());
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        assert!(Image::parse_netpbm(b"P6 2 2 255\n\x00\x00").is_err());
+        assert!(Image::parse_netpbm(b"P7 2 2 255\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_size() {
+        let kind = |data: &[u8]| Image::parse_netpbm(data).unwrap_err().kind();
+        assert_eq!(kind(b"P6 0 0 255\n"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"P3 2 0 255\n"), io::ErrorKind::InvalidData);
+        let huge = format!("P6 {} {} 255\n", usize::MAX / 2, 3);
+        assert_eq!(kind(huge.as_bytes()), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,457 @@
+// Camera that traces rays through the spherical interfaces of a real lens design, which
+// shows vignetting, distortion and focus breathing. Follows the realistic camera of
+// pbrt (Kolb et al. 1995): lens data in millimeters, ordered from the scene to the film.
+use crate::camera::{orientation, Camera};
+use crate::ray::Ray;
+use crate::vec3::*;
+
+// Millimeters in scene units, which are meters
+const MM: f64 = 0.001;
+
+// Interface between two media, or the aperture stop if radius is zero
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    // Curvature radius, positive if the center lies towards the film
+    pub radius: f64,
+    // Distance along the axis to the next interface (for the last one to the film)
+    pub thickness: f64,
+    // Index of refraction behind the interface, 1 (or 0) for air
+    pub ior: f64,
+    // Diameter
+    pub aperture: f64,
+}
+
+impl LensElement {
+    fn is_stop(&self) -> bool {
+        self.radius == 0.0
+    }
+
+    fn ior(&self) -> f64 {
+        if self.ior == 0.0 {
+            1.0
+        } else {
+            self.ior
+        }
+    }
+}
+
+// Double Gauss 50mm f/2, US patent 2,673,491 (Tronnier), Modern Lens Design p. 312
+pub const DOUBLE_GAUSS: &str = "
+# radius thickness ior aperture
+29.475   3.76   1.67   25.2
+84.83    0.12   1      25.2
+19.275   4.025  1.67   23
+40.77    3.275  1.699  23
+12.75    5.705  1      18
+0        4.5    0      17.1
+-14.495  1.18   1.603  17
+40.77    6.065  1.658  20
+-20.385  0.19   1      20
+437.065  3.22   1.717  20
+-39.73   40     1      20
+";
+
+// Cooke triplet 50mm f/4.5 (SK16, F2, SK16), stop behind the negative element
+pub const COOKE_TRIPLET: &str = "
+# radius thickness ior aperture
+22.01359   3.25896   1.6204  16
+-435.7604  6.00755   1       16
+-22.21328  0.99997   1.62    12
+20.29192   1.0       1       10
+0          3.75041   0       9
+79.6836    2.95208   1.6204  14
+-18.39533  42.20778  1       14
+";
+
+// Biconvex BK7 singlet behind a stop, about 50mm, with strong spherical aberration
+pub const SINGLET: &str = "
+# radius thickness ior aperture
+0      5     0       10
+51.7   5     1.5168  20
+-51.7  45    1       20
+";
+
+// Prescription of a bundled lens
+pub fn bundled(name: &str) -> Option<&'static str> {
+    match name {
+        "double-gauss" => Some(DOUBLE_GAUSS),
+        "cooke-triplet" => Some(COOKE_TRIPLET),
+        "singlet" => Some(SINGLET),
+        _ => None,
+    }
+}
+
+// Reads a lens prescription: one interface per line with radius, thickness, ior and
+// aperture diameter in millimeters, from the scene to the film. # starts a comment.
+pub fn parse_prescription(text: &str) -> Result<Vec<LensElement>, String> {
+    let mut elements = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let values: Vec<f64> = line
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Malformed number in line {}", number + 1))?;
+        let element = match values[..] {
+            [radius, thickness, ior, aperture] => LensElement {
+                radius,
+                thickness,
+                ior,
+                aperture,
+            },
+            _ => return Err(format!("Line {} needs 4 values", number + 1)),
+        };
+        if element.thickness < 0.0 || element.aperture <= 0.0 || element.ior < 0.0 {
+            return Err(format!("Invalid lens element in line {}", number + 1));
+        }
+        elements.push(element);
+    }
+    if elements.is_empty() {
+        return Err("The lens has no elements".to_string());
+    }
+    Ok(elements)
+}
+
+// Ray in lens space: the film is the plane z = 0 and the scene lies towards negative z
+#[derive(Debug, Clone)]
+struct LensRay {
+    origin: Vec3,
+    direction: Vec3,
+}
+
+impl LensRay {
+    fn at(&self, t: f64) -> Vec3 {
+        &self.origin + t * &self.direction
+    }
+
+    // Where the ray crosses the given height above the axis, in the xz plane
+    fn z_at_height(&self, x: f64) -> f64 {
+        self.at((x - self.origin.e0) / self.direction.e0).e2
+    }
+}
+
+// Intersection with the cap of a sphere whose vertex lies on the axis, the normal faces
+// the ray
+fn intersect_interface(radius: f64, z_center: f64, ray: &LensRay) -> Option<(f64, Vec3)> {
+    let o = &ray.origin - Vec3::new(0.0, 0.0, z_center);
+    let d = &ray.direction;
+    let (a, b, c) = (
+        d.length_squared(),
+        2.0 * d.dot(&o),
+        o.length_squared() - radius * radius,
+    );
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = discriminant.sqrt();
+    let (t0, t1) = ((-b - q) / (2.0 * a), (-b + q) / (2.0 * a));
+    // The vertex side of the sphere is the first hit if the ray moves towards the center
+    let t = if (d.e2 > 0.0) != (radius < 0.0) {
+        t0
+    } else {
+        t1
+    };
+    if t < 0.0 {
+        return None;
+    }
+    let normal = (o + t * d).unit_vector();
+    let normal = if normal.dot(d) > 0.0 { -normal } else { normal };
+    Some((t, normal))
+}
+
+// Snell's law for a direction d hitting a surface with normal n facing it, eta is the
+// ratio of the refractive indices before and after. None on total internal reflection.
+fn refract(d: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+    let wi = -d.unit_vector();
+    let cos_i = n.dot(&wi);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * -wi + (eta * cos_i - cos_t) * n)
+}
+
+#[derive(Debug)]
+pub struct RealisticCamera {
+    elements: Vec<LensElement>,
+    // In millimeters
+    film_width: f64,
+    film_height: f64,
+    // Center of the film
+    origin: Loc,
+    u: Loc,
+    v: Loc,
+    w: Loc,
+    sensitivity: f64,
+}
+
+impl RealisticCamera {
+    // The film is centered at lookfrom. It is film_diagonal millimeters across (43.3 for
+    // 35mm film) and the lens is moved to focus at focus_dist from the film.
+    pub fn new(
+        lookfrom: Loc,
+        lookat: Loc,
+        vup: Loc,
+        elements: Vec<LensElement>,
+        film_diagonal: f64,
+        aspect_ratio: f64,
+        focus_dist: f64,
+    ) -> Result<RealisticCamera, String> {
+        let (u, v, w) = orientation(&lookfrom, &lookat, &vup);
+        let film_width = film_diagonal * aspect_ratio / (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let mut camera = RealisticCamera {
+            elements,
+            film_width,
+            film_height: film_width / aspect_ratio,
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            sensitivity: 1.0,
+        };
+        camera.focus(focus_dist / MM)?;
+        // Share of the rays from the center of the film that make it through the lens
+        let n = 32;
+        let passed = (0..n * n)
+            .filter(|i| {
+                let lens = (
+                    ((i % n) as f64 + 0.5) / n as f64,
+                    ((i / n) as f64 + 0.5) / n as f64,
+                );
+                camera.film_ray(0.0, 0.0, lens).is_some()
+            })
+            .count();
+        if passed == 0 {
+            return Err("No light passes through the lens".to_string());
+        }
+        camera.sensitivity = (n * n) as f64 / passed as f64;
+        Ok(camera)
+    }
+
+    fn length(&self) -> f64 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear(&self) -> &LensElement {
+        self.elements.last().expect("Lenses have elements")
+    }
+
+    // Passes a ray from the film side through all interfaces, None if it is blocked
+    fn trace_from_film(&self, mut ray: LensRay) -> Option<LensRay> {
+        let mut z = 0.0;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            z -= element.thickness;
+            let (t, normal) = if element.is_stop() {
+                if ray.direction.e2 >= 0.0 {
+                    return None;
+                }
+                ((z - ray.origin.e2) / ray.direction.e2, None)
+            } else {
+                let (t, normal) = intersect_interface(element.radius, z + element.radius, &ray)?;
+                (t, Some(normal))
+            };
+            let hit = ray.at(t);
+            if hit.e0 * hit.e0 + hit.e1 * hit.e1 > element.aperture * element.aperture / 4.0 {
+                return None;
+            }
+            ray.origin = hit;
+            if let Some(normal) = normal {
+                let outside = if i > 0 {
+                    self.elements[i - 1].ior()
+                } else {
+                    1.0
+                };
+                ray.direction = refract(&ray.direction, &normal, element.ior() / outside)?;
+            }
+        }
+        Some(ray)
+    }
+
+    // Passes a ray from the scene side through all interfaces, None if it is blocked
+    fn trace_from_scene(&self, mut ray: LensRay) -> Option<LensRay> {
+        let mut z = -self.length();
+        for (i, element) in self.elements.iter().enumerate() {
+            let (t, normal) = if element.is_stop() {
+                ((z - ray.origin.e2) / ray.direction.e2, None)
+            } else {
+                let (t, normal) = intersect_interface(element.radius, z + element.radius, &ray)?;
+                (t, Some(normal))
+            };
+            let hit = ray.at(t);
+            if hit.e0 * hit.e0 + hit.e1 * hit.e1 > element.aperture * element.aperture / 4.0 {
+                return None;
+            }
+            ray.origin = hit;
+            if let Some(normal) = normal {
+                let outside = if i > 0 {
+                    self.elements[i - 1].ior()
+                } else {
+                    1.0
+                };
+                ray.direction = refract(&ray.direction, &normal, outside / element.ior())?;
+            }
+            z += element.thickness;
+        }
+        Some(ray)
+    }
+
+    // Positions of the focal point and the principal plane, of the scene and the film
+    // side, from rays parallel to the axis close to it
+    fn cardinal_points(&self) -> Result<[f64; 4], String> {
+        let x = 0.001
+            * (self.film_width * self.film_width + self.film_height * self.film_height).sqrt();
+        let from_scene = LensRay {
+            origin: Vec3::new(x, 0.0, -self.length() - 1.0),
+            direction: Vec3::new(0.0, 0.0, 1.0),
+        };
+        let from_film = LensRay {
+            origin: Vec3::new(x, 0.0, 1.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        let blocked = || "Rays along the axis don't pass through the lens".to_string();
+        let film_side = self.trace_from_scene(from_scene).ok_or_else(blocked)?;
+        let scene_side = self.trace_from_film(from_film).ok_or_else(blocked)?;
+        Ok([
+            scene_side.z_at_height(0.0),
+            scene_side.z_at_height(x),
+            film_side.z_at_height(0.0),
+            film_side.z_at_height(x),
+        ])
+    }
+
+    // Focal length in millimeters
+    fn focal_length(&self) -> Result<f64, String> {
+        let [_, _, focus, principal] = self.cardinal_points()?;
+        Ok(focus - principal)
+    }
+
+    // Moves the lens along the axis so that objects at distance (in millimeters) from
+    // the film are sharp, using the thick lens approximation
+    fn focus(&mut self, distance: f64) -> Result<(), String> {
+        let [_, scene_principal, _, film_principal] = self.cardinal_points()?;
+        let f = self.focal_length()?;
+        if f <= 0.0 {
+            return Err("The lens doesn't converge light".to_string());
+        }
+        // Distances of object and image from their principal planes once the lens moved
+        // by delta: 1 / (a + delta) + 1 / (b - delta) = 1 / f
+        let (a, b) = (scene_principal + distance, -film_principal);
+        if a + b < 4.0 * f {
+            return Err("The lens can't focus that close".to_string());
+        }
+        let delta = ((b - a) + ((a + b) * (a + b - 4.0 * f)).sqrt()) / 2.0;
+        let rear = self.elements.len() - 1;
+        self.elements[rear].thickness -= delta;
+        if self.elements[rear].thickness < 0.0 {
+            return Err("The lens can't focus that far".to_string());
+        }
+        Ok(())
+    }
+
+    // Ray leaving the lens in lens space, for a point on the film (in millimeters from
+    // its center) and a sample of the rear element
+    fn film_ray(&self, x: f64, y: f64, lens: (f64, f64)) -> Option<LensRay> {
+        let rear = self.rear();
+        let p = 0.5 * rear.aperture * Vec3::uniform_in_unit_disk(lens);
+        let on_rear = Vec3::new(p.e0, p.e1, -rear.thickness);
+        let film = Vec3::new(x, y, 0.0);
+        self.trace_from_film(LensRay {
+            direction: &on_rear - &film,
+            origin: film,
+        })
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn get_ray(&self, s: f64, t: f64, lens: (f64, f64)) -> Option<Ray> {
+        // The lens turns the image upside down
+        let x = (0.5 - s) * self.film_width;
+        let y = (0.5 - t) * self.film_height;
+        let ray = self.film_ray(x, y, lens)?;
+        let to_world = |p: &Vec3| p.e0 * &self.u + p.e1 * &self.v + p.e2 * &self.w;
+        Some(Ray::new(
+            &self.origin + MM * to_world(&ray.origin),
+            to_world(&ray.direction),
+        ))
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.sensitivity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(prescription: &str, focus_dist: f64) -> RealisticCamera {
+        RealisticCamera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            parse_prescription(prescription).unwrap(),
+            43.27,
+            1.5,
+            focus_dist,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bundled_lenses_are_50mm() {
+        for prescription in [DOUBLE_GAUSS, COOKE_TRIPLET, SINGLET] {
+            let f = camera(prescription, 10.0).focal_length().unwrap();
+            assert!((f - 50.0).abs() < 1.5, "{}", f);
+        }
+    }
+
+    #[test]
+    fn test_focus() {
+        // Rays from the center of the film meet again on the axis at the focus distance
+        for distance in [10.0, 2.0] {
+            let camera = camera(DOUBLE_GAUSS, distance);
+            let horizontal = camera.get_ray(0.5, 0.5, (0.45, 0.5)).unwrap();
+            let vertical = camera.get_ray(0.5, 0.5, (0.5, 0.55)).unwrap();
+            let x_crossing = horizontal.at(-horizontal.orig.e0 / horizontal.dir.e0).e2;
+            let y_crossing = vertical.at(-vertical.orig.e1 / vertical.dir.e1).e2;
+            for crossing in [x_crossing, y_crossing] {
+                assert!(
+                    (crossing + distance).abs() < 0.02 * distance,
+                    "{}",
+                    crossing
+                );
+            }
+        }
+        let far = camera(DOUBLE_GAUSS, 10.0);
+        let near = camera(DOUBLE_GAUSS, 1.0);
+        // Focusing closer moves the lens away from the film
+        assert!(near.rear().thickness > far.rear().thickness);
+        assert!(RealisticCamera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            parse_prescription(DOUBLE_GAUSS).unwrap(),
+            43.27,
+            1.5,
+            0.1,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_prescription() {
+        let elements = parse_prescription("# comment\n0 5 0 10\n51.7 5 1.5 20 # front\n").unwrap();
+        assert_eq!(elements.len(), 2);
+        assert!(elements[0].is_stop());
+        assert_eq!(elements[1].ior(), 1.5);
+        assert!(parse_prescription("51.7 5 1.5").is_err());
+        assert!(parse_prescription("51.7 5 x 20").is_err());
+        assert!(parse_prescription("# nothing").is_err());
+        assert_eq!(bundled("singlet"), Some(SINGLET));
+    }
+}
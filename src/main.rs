@@ -1,61 +1,372 @@
 #[macro_use]
 extern crate impl_ops;
-use indicatif::ProgressBar;
-use rand::Rng;
-use rayon::prelude::*;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::Mutex;
 use std::time::Instant;
-use string_builder::Builder;
 
+mod animation;
+mod aov;
+mod aperture;
+mod bump;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod exr;
+mod film;
+mod filter;
+mod firefly;
 mod hittable;
 mod hittable_list;
+mod image;
+mod lens;
 mod materials;
+mod microfacet;
+mod options;
+mod preview;
+mod principled;
+mod quad;
 mod ray;
+mod sampler;
 mod sampling;
+mod scenes;
+mod spectrum;
 mod sphere;
+mod stats;
+mod stereo;
+mod texture;
+mod tonemap;
+mod triangle;
 mod vec3;
 
+// Default image size
 const ASPECT_RATIO: f64 = 3.0 / 2.0;
 const IMAGE_WIDTH: u32 = 1200;
-const IMAGE_HEIGHT: u32 = (IMAGE_WIDTH as f64 / ASPECT_RATIO) as u32;
 const MAX_REC_DEPTH: i32 = 50;
 const NUM_SAMPLES: i32 = 500;
-const GAMMA_CORRECTION: f64 = 2.0;
 const SHADOW_ACNE_TOLERANCE: f64 = 0.0001;
+// Edge length of the square blocks of pixels that are rendered as a unit
+const TILE_SIZE: u32 = 32;
+// Samples per pixel of a pass when checkpoints are written but --progressive is missing,
+// a render in one pass would only save its state once it is finished
+const CHECKPOINT_PASS_SAMPLES: u32 = 16;
+const VFOV: f64 = 20.0;
 const APERTURE: f64 = 0.12;
+// Of the realistic camera in millimeters, 35mm film
+const FILM_DIAGONAL: f64 = 43.27;
+// Focus distance unless the options derive one from the scene
 const FOCUS_DIST: f64 = 10.0;
+// Paths that never lose energy (e.g. trapped in glass) still have to end
+const ROULETTE_MAX_BOUNCES: u32 = 1000;
 
-fn ray_to_color(
+// Records what the camera ray hit first. The sky is its own albedo.
+fn record_first_hit(
+    record: &mut aov::PathRecord,
     r: &ray::Ray,
-    world: &hittable_list::HittableList,
-    recursion_depth: i32,
-    rng: &mut impl Rng,
-) -> vec3::Color {
-    use hittable::Hittable;
-    use sphere::*;
+    hit: Option<&hittable::Hit>,
+    material_ids: &aov::MaterialIds,
+) {
     use vec3::*;
 
-    if recursion_depth <= 0 {
-        return Vec3::zero();
+    match hit {
+        Some(hit) => {
+            record.features = denoise::Features::new(
+                hit.material.albedo(hit),
+                hit.normal.clone(),
+                hit.t * r.dir.length(),
+            );
+            record.object_id = hit.object;
+            record.material_id = material_ids.get(hit.material);
+        }
+        None => record.features = denoise::Features::new(sky_color(r, 1), Vec3::zero(), 0.0),
     }
+}
 
-    if let Some(hit) = world.hit(r, &interval_validator(Some(SHADOW_ACNE_TOLERANCE), None)) {
-        if let Some(scatter_result) = hit.material.scatter(r, &hit) {
-            return scatter_result.attenuation.hadamard(&ray_to_color(
-                &scatter_result.ray,
-                world,
-                recursion_depth - 1,
-                rng,
-            ));
+// Adaptive sampling checks the error of a pixel every few samples only, a single lucky
+// streak shouldn't end sampling early
+const ADAPTIVE_BATCH: u32 = 8;
+
+fn is_converged(
+    color_sampler: &sampling::ColorSampler,
+    adaptive: &Option<options::AdaptiveSampling>,
+) -> bool {
+    match adaptive {
+        Some(adaptive) => {
+            let n = color_sampler.sample_num() as u32;
+            n >= adaptive.min_samples
+                && n.is_multiple_of(ADAPTIVE_BATCH)
+                && color_sampler.relative_error() < adaptive.error_threshold
+        }
+        None => false,
+    }
+}
+
+// Everything a render pass needs to trace the samples of a pixel
+struct RenderContext<'a> {
+    camera: &'a dyn camera::Camera,
+    width: u32,
+    height: u32,
+    // Part of the image the film covers
+    region: film::Tile,
+    world: &'a hittable_list::HittableList<'a>,
+    sampler: &'a dyn sampler::Sampler,
+    filter: filter::Filter,
+    options: &'a options::Options,
+    material_ids: aov::MaterialIds,
+    preview: Option<&'a Mutex<preview::Preview>>,
+    stats: Mutex<stats::Stats>,
+}
+
+impl RenderContext<'_> {
+    // Brings every pixel of the film up to end_sample samples, unless it converged.
+    // Tiles are queued in spiral order and picked up by rayon's work stealing pool.
+    fn render_pass(&self, film: &mut film::Film, end_sample: u32, bar: &ProgressBar) {
+        let tiles = film::spiral_tiles(film.width, film.height, TILE_SIZE);
+        let film = Mutex::new(film);
+        rayon::scope_fifo(|scope| {
+            for tile in &tiles {
+                let film = &film;
+                scope.spawn_fifo(move |_| {
+                    let mut buffer = film.lock().unwrap().tile_buffer(tile, self.filter);
+                    self.render_tile(&mut buffer, end_sample);
+                    self.stats.lock().unwrap().merge(&stats::take());
+                    let mut film = film.lock().unwrap();
+                    film.merge_tile(buffer);
+                    bar.inc(tile.pixel_count());
+                    // Skipped while another worker draws the preview
+                    if let Some(Ok(mut preview)) = self.preview.map(Mutex::try_lock) {
+                        if preview.due() {
+                            // Drawing takes a while, the other workers go on meanwhile
+                            let (colors, width, height) = (film.colors(), film.width, film.height);
+                            drop(film);
+                            // A terminal that went away doesn't stop the render
+                            preview.draw(&colors, width, height, bar).ok();
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    fn render_tile(&self, buffer: &mut film::TileBuffer, end_sample: u32) {
+        let mut samples = sampler::SampleStream::new(self.sampler);
+        let tile = buffer.tile;
+        // Film pixels x, y show image pixels row and col. The camera counts rows from the
+        // bottom.
+        for y in tile.rows() {
+            let col = self.height - 1 - (self.region.y0 + y);
+            for x in tile.x0..tile.x0 + tile.width {
+                let row = self.region.x0 + x;
+                for sample_index in buffer.pixel(x, y).sample_num() as u32..end_sample {
+                    if is_converged(buffer.pixel(x, y), &self.options.adaptive) {
+                        break;
+                    }
+                    samples.start_pixel_sample((row, col), sample_index);
+                    let camera_sample = samples.camera_sample();
+                    let (color, record) = self.trace(&mut samples, row, col, &camera_sample);
+                    let (dx, dy) = camera_sample.film;
+                    let position = (x as f64 + dx, y as f64 + 1.0 - dy);
+                    buffer.add_sample(x, y, position, &color, &record);
+                }
+            }
+        }
+    }
+
+    // Clamps light that reached the camera after the given number of bounces and adds
+    // it to the record
+    fn add_light(
+        &self,
+        record: &mut aov::PathRecord,
+        bounces: u32,
+        light: vec3::Color,
+    ) -> vec3::Color {
+        let limit = match bounces {
+            0 => None,
+            1 => self.options.clamp_direct,
+            _ => self.options.clamp_indirect,
+        };
+        let light = firefly::clamp(light, limit, record);
+        record.add_light(bounces, &light);
+        light
+    }
+
+    // Weight of the path after Russian roulette if it is enabled, None ends the path
+    fn roulette(
+        &self,
+        bounce: u32,
+        throughput: f64,
+        sampler: &mut sampler::SampleStream,
+    ) -> Option<f64> {
+        if !self.options.russian_roulette {
+            return Some(1.0);
+        }
+        firefly::roulette(bounce, throughput, sampler)
+    }
+
+    fn max_bounces(&self) -> u32 {
+        if self.options.russian_roulette {
+            ROULETTE_MAX_BOUNCES
         } else {
-            return Vec3::zero();
+            MAX_REC_DEPTH as u32
+        }
+    }
+
+    fn ray_to_color(
+        &self,
+        r: ray::Ray,
+        sampler: &mut sampler::SampleStream,
+        record: &mut aov::PathRecord,
+    ) -> vec3::Color {
+        use hittable::Hittable;
+        use sphere::*;
+        use vec3::*;
+
+        let mut throughput = self.camera.sensitivity() * Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r;
+
+        for bounce in 0..self.max_bounces() {
+            let validator = interval_validator(Some(SHADOW_ACNE_TOLERANCE), None);
+            stats::count(|s| s.rays += 1);
+            let hit = match self.world.hit(&ray, &validator) {
+                Some(hit) => hit,
+                None => {
+                    stats::count(|s| s.escaped += 1);
+                    if bounce == 0 {
+                        record_first_hit(record, &ray, None, &self.material_ids);
+                    }
+                    let sky = sky_color(&ray, MAX_REC_DEPTH - bounce as i32);
+                    return self.add_light(record, bounce, throughput.hadamard(&sky));
+                }
+            };
+            sampler.start_bounce(bounce);
+            if bounce == 0 {
+                record_first_hit(record, &ray, Some(&hit), &self.material_ids);
+            }
+            let scatter_result = match hit.material.scatter(&ray, &hit, sampler) {
+                Some(scatter_result) => scatter_result,
+                None => {
+                    stats::count(|s| s.absorbed += 1);
+                    return Vec3::zero();
+                }
+            };
+            throughput = throughput.hadamard(&scatter_result.attenuation);
+            let max = throughput.e0.max(throughput.e1).max(throughput.e2);
+            match self.roulette(bounce, max, sampler) {
+                Some(weight) => throughput = weight * throughput,
+                None => {
+                    stats::count(|s| s.roulette += 1);
+                    return Vec3::zero();
+                }
+            }
+            ray = scatter_result.ray;
+        }
+        stats::count(|s| s.depth_limit += 1);
+        Vec3::zero()
+    }
+
+    // Traces a ray carrying sampled wavelengths, returns linear sRGB via CIE XYZ
+    fn ray_to_color_spectral(
+        &self,
+        r: ray::Ray,
+        sampler: &mut sampler::SampleStream,
+        record: &mut aov::PathRecord,
+    ) -> vec3::Color {
+        use hittable::Hittable;
+        use spectrum::*;
+        use sphere::*;
+
+        let mut wavelengths = r.wavelengths.expect("Spectral ray without wavelengths");
+        let mut throughput = SampledSpectrum::constant(self.camera.sensitivity());
+        let mut ray = r;
+
+        for bounce in 0..self.max_bounces() {
+            let validator = interval_validator(Some(SHADOW_ACNE_TOLERANCE), None);
+            stats::count(|s| s.rays += 1);
+            let hit = match self.world.hit(&ray, &validator) {
+                Some(hit) => hit,
+                None => {
+                    stats::count(|s| s.escaped += 1);
+                    if bounce == 0 {
+                        record_first_hit(record, &ray, None, &self.material_ids);
+                    }
+                    let sky = SampledSpectrum::from_illuminant(
+                        &sky_color(&ray, MAX_REC_DEPTH - bounce as i32),
+                        &wavelengths,
+                    );
+                    let light = xyz_to_linear_srgb(&wavelengths.to_xyz(&throughput.hadamard(&sky)));
+                    return self.add_light(record, bounce, light);
+                }
+            };
+            sampler.start_bounce(bounce);
+            if bounce == 0 {
+                record_first_hit(record, &ray, Some(&hit), &self.material_ids);
+            }
+            let scatter_result = match hit.material.scatter(&ray, &hit, sampler) {
+                Some(scatter_result) => scatter_result,
+                None => {
+                    stats::count(|s| s.absorbed += 1);
+                    return vec3::Vec3::zero();
+                }
+            };
+            throughput = throughput.hadamard(&SampledSpectrum::from_reflectance(
+                &scatter_result.attenuation,
+                &wavelengths,
+            ));
+            match self.roulette(bounce, throughput.max(), sampler) {
+                Some(weight) => throughput = throughput.scaled(weight),
+                None => {
+                    stats::count(|s| s.roulette += 1);
+                    return vec3::Vec3::zero();
+                }
+            }
+            // Materials only set wavelengths if they changed (e.g. dispersion)
+            if let Some(changed) = scatter_result.ray.wavelengths {
+                wavelengths = changed;
+            }
+            ray = scatter_result.ray.with_wavelengths(wavelengths);
         }
+        stats::count(|s| s.depth_limit += 1);
+        vec3::Vec3::zero()
     }
 
+    fn trace(
+        &self,
+        samples: &mut sampler::SampleStream,
+        row: u32,
+        col: u32,
+        camera_sample: &sampler::CameraSample,
+    ) -> (vec3::Color, aov::PathRecord) {
+        use spectrum::SampledWavelengths;
+
+        let u = (row as f64 + camera_sample.film.0) / self.width as f64;
+        let v = (col as f64 + camera_sample.film.1) / self.height as f64;
+        let mut record = aov::PathRecord::zero();
+        stats::count(|s| s.samples += 1);
+        let ray = match self
+            .camera
+            .get_ray_at(u, v, camera_sample.lens, camera_sample.time)
+        {
+            Some(ray) => ray,
+            None => return (vec3::Vec3::zero(), record),
+        };
+        stats::count(|s| s.camera_rays += 1);
+        let color = if self.options.spectral {
+            let wavelengths = SampledWavelengths::sample_uniform(samples.wavelength_sample());
+            let ray = ray.with_wavelengths(wavelengths);
+            self.ray_to_color_spectral(ray, samples, &mut record)
+        } else {
+            self.ray_to_color(ray, samples, &mut record)
+        };
+        (color, record)
+    }
+}
+
+fn sky_color(r: &ray::Ray, recursion_depth: i32) -> vec3::Color {
+    use vec3::*;
+
     let dir = r.unit_direction();
     let t = 0.5 * (dir.e1 + 1.0);
     assert!(
-        t >= 0.0 && t <= 1.0,
+        (0.0..=1.0).contains(&t),
         "t was: {} ray {:?}, recursion_depth: {}",
         t,
         r,
@@ -64,128 +375,572 @@ fn ray_to_color(
     (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.2, 0.4, 1.0)
 }
 
-fn gen_random_scene() -> hittable_list::HittableList<'static> {
-    use hittable_list::*;
-    use materials::*;
-    use sphere::*;
-    use vec3::*;
-
-    let mut rng = rand::thread_rng();
-    let mut world = HittableList::empty();
-
-    let ground_material = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
-    let ground_sphere = Sphere::new(
-        Vec3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        Box::new(ground_material),
-    );
+// Parts of the camera that may come from files
+struct Optics {
+    aperture: aperture::Aperture,
+    // Empty unless the camera is realistic
+    lens: Vec<lens::LensElement>,
+}
 
-    world.add(ground_sphere);
-    for a in -11..11 {
-        for b in -11..11 {
-            let material_num: f64 = rng.gen();
-            let center = Vec3::new(
-                a as f64 + 0.9 * rng.gen::<f64>(),
-                0.2,
-                b as f64 + 0.9 * rng.gen::<f64>(),
-            );
+// Camera of the kind chosen in the options, or one eye of a stereo rig of such cameras
+fn make_camera(
+    options: &options::Options,
+    pose: &animation::Pose,
+    vup: &vec3::Loc,
+    aspect_ratio: f64,
+    optics: &Optics,
+    eye: Option<(&stereo::StereoRig, stereo::Eye)>,
+) -> Result<Box<dyn camera::Camera>, String> {
+    use camera::*;
+    use options::*;
 
-            if (&center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                let material: Box<dyn Material> = if material_num < 0.8 {
-                    // diffuse
-                    let albedo = Vec3::random_range(0.0, 1.0, &mut rng)
-                        .hadamard(&Vec3::random_range(0.0, 1.0, &mut rng));
-                    Box::new(Lambertian::new(albedo))
-                } else if material_num < 0.95 {
-                    // metal
-                    let albedo = Vec3::random_range(0.5, 1.0, &mut rng);
-                    let fuzz = rng.gen_range(0.0..0.5);
-                    Box::new(Metal::new(albedo, fuzz))
-                } else {
-                    // glass
-                    Box::new(Dielectric::new(1.5))
-                };
-                world.add(Sphere::new(center, 0.2, material));
-            }
+    let (lookfrom, lookat) = (&pose.lookfrom, &pose.lookat);
+    let fov = pose.fov.or(options.fov);
+    let focus_dist = pose.focus_dist.unwrap_or(FOCUS_DIST);
+    // What the perspective camera sees at lookat
+    let default_view_height = 2.0 * (VFOV.to_radians() / 2.0).tan() * (lookfrom - lookat).length();
+    // Panoramas turn the eyes with the view direction, other cameras move as a whole
+    let view = match eye {
+        Some((rig, eye)) if options.camera != CameraKind::Equirectangular => {
+            rig.eye_view(eye, lookfrom, lookat, vup)
         }
-    }
-    let material_dielec = Dielectric::new(1.5);
-    world.add(Sphere::new(
-        Vec3::new(0.0, 1.0, 0.0),
-        1.0,
-        Box::new(material_dielec),
-    ));
-    let material_lamb = Lambertian::new(Vec3::new(0.4, 0.2, 0.1));
-    world.add(Sphere::new(
-        Vec3::new(-4.0, 1.0, 0.0),
-        1.0,
-        Box::new(material_lamb),
-    ));
-    let material_metal = Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0);
-    world.add(Sphere::new(
-        Vec3::new(4.0, 1.0, 0.0),
-        1.0,
-        Box::new(material_metal),
-    ));
-    world
+        _ => stereo::EyeView {
+            lookfrom: lookfrom.clone(),
+            lookat: lookat.clone(),
+            shift: 0.0,
+        },
+    };
+    let (lookfrom, lookat, vup) = (view.lookfrom, view.lookat, vup.clone());
+    let fisheye = |mapping| {
+        FisheyeCamera::new(
+            lookfrom.clone(),
+            lookat.clone(),
+            vup.clone(),
+            fov.unwrap_or(180.0),
+            mapping,
+            aspect_ratio,
+        )
+    };
+    Ok(match options.camera {
+        CameraKind::Perspective if fov >= Some(180.0) => {
+            return Err("Perspective cameras see less than 180 degrees".to_string())
+        }
+        CameraKind::Perspective => Box::new(
+            PerspectiveCamera::new(
+                lookfrom,
+                lookat,
+                vup,
+                fov.unwrap_or(VFOV),
+                aspect_ratio,
+                APERTURE,
+                focus_dist,
+            )
+            .with_aperture(optics.aperture.clone())
+            .shifted(view.shift),
+        ),
+        CameraKind::Orthographic => {
+            let view_height = match options.view_size {
+                Some(ViewSize::Width(width)) => width / aspect_ratio,
+                Some(ViewSize::Height(height)) => height,
+                None => default_view_height,
+            };
+            Box::new(OrthographicCamera::new(
+                lookfrom,
+                lookat,
+                vup,
+                view_height,
+                aspect_ratio,
+            ))
+        }
+        CameraKind::Equirectangular => {
+            let offset = eye.map_or(0.0, |(rig, eye)| rig.offset(eye));
+            Box::new(EquirectangularCamera::new(lookfrom, lookat, vup)?.with_eye_offset(offset))
+        }
+        CameraKind::Fisheye => Box::new(fisheye(FisheyeMapping::Equidistant)),
+        CameraKind::FisheyeEquisolid => Box::new(fisheye(FisheyeMapping::Equisolid)),
+        CameraKind::Realistic => Box::new(lens::RealisticCamera::new(
+            lookfrom,
+            lookat,
+            vup,
+            optics.lens.clone(),
+            options.film_diagonal.unwrap_or(FILM_DIAGONAL),
+            aspect_ratio,
+            focus_dist,
+        )?),
+    })
 }
 
 fn main() {
+    use animation::{CameraPath, Keyframe, MotionBlurCamera, Pose};
+    use aperture::*;
     use camera::*;
-    use sampling::ColorSampler;
+    use checkpoint::Checkpoint;
+    use film::Film;
+    use filter::Filter;
+    use options::*;
+    use sampler::*;
+    use std::io::Write;
+    use stereo::*;
+    use tonemap::{PostProcess, Tonemap};
     use vec3::*;
 
-    // Camera
-    let camera = Camera::new(
-        Vec3::new(13.0, 2.0, 3.0),
-        Vec3::new(0.0, 0.0, 0.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        20.0,
-        ASPECT_RATIO,
-        APERTURE,
-        FOCUS_DIST,
-    );
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        eprintln!("{}", USAGE);
+        return;
+    }
 
-    // World
-    let world = gen_random_scene();
-
-    let bar = ProgressBar::new((IMAGE_HEIGHT).into());
-    println!("P3\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT);
-
-    let now = Instant::now();
-    let image_data = (0..IMAGE_HEIGHT)
-        .into_par_iter()
-        .rev()
-        .map(|col| {
-            bar.inc(1);
-            let mut rng = rand::thread_rng();
-            let mut row_output_builder = Builder::default();
-            // Anti-Aliasing
-            let mut color_sampler = ColorSampler::new();
-            for row in 0..IMAGE_WIDTH {
-                for _ in 0..NUM_SAMPLES {
-                    let u = (row as f64 + rng.gen::<f64>()) / ((IMAGE_WIDTH - 1) as f64);
-                    let v = (col as f64 + rng.gen::<f64>()) / ((IMAGE_HEIGHT - 1) as f64);
-                    let sample_color =
-                        ray_to_color(&camera.get_ray(u, v), &world, MAX_REC_DEPTH, &mut rng);
-                    color_sampler.add(&sample_color);
+    // Camera
+    let (width, height) = options.resolution.unwrap_or_else(|| {
+        let aspect_ratio = match options.camera {
+            CameraKind::Equirectangular => 2.0,
+            CameraKind::Fisheye | CameraKind::FisheyeEquisolid => 1.0,
+            _ => ASPECT_RATIO,
+        };
+        let eye_height = (IMAGE_WIDTH as f64 / aspect_ratio) as u32;
+        match options.stereo {
+            None => (IMAGE_WIDTH, eye_height),
+            Some(Packing::SideBySide) => (2 * IMAGE_WIDTH, eye_height),
+            Some(Packing::TopBottom) => (IMAGE_WIDTH, 2 * eye_height),
+        }
+    });
+    // Of the view of a single eye
+    let aspect_ratio = match options.stereo {
+        None => width as f64 / height as f64,
+        Some(Packing::SideBySide) => width as f64 / 2.0 / height as f64,
+        Some(Packing::TopBottom) => 2.0 * width as f64 / height as f64,
+    };
+    // Corners of the part of the image to render, clipped to the image
+    let (x0, y0, x1, y1) = match options.region {
+        None => (0, 0, width, height),
+        Some(Region::Pixels {
+            x,
+            y,
+            width: w,
+            height: h,
+        }) => (x, y, x.saturating_add(w), y.saturating_add(h)),
+        Some(Region::Window {
+            left,
+            top,
+            right,
+            bottom,
+        }) => {
+            let (w, h) = (width as f64, height as f64);
+            let (x0, x1) = ((left * w).floor() as u32, (right * w).ceil() as u32);
+            let (y0, y1) = ((top * h).floor() as u32, (bottom * h).ceil() as u32);
+            (x0, y0, x1, y1)
+        }
+    };
+    let (x1, y1) = (x1.min(width), y1.min(height));
+    if x0 >= x1 || y0 >= y1 {
+        eprintln!("The region lies outside of the {}x{} image", width, height);
+        std::process::exit(1);
+    }
+    let region = film::Tile {
+        x0,
+        y0,
+        width: x1 - x0,
+        height: y1 - y0,
+    };
+    let shape = match (&options.aperture_image, options.aperture_blades) {
+        (Some(path), _) => match ApertureImage::open(path) {
+            Ok(image) => Shape::Image(image),
+            Err(e) => {
+                eprintln!("Could not read aperture image {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(blades)) => Shape::Polygon {
+            blades,
+            rotation: options.aperture_rotation,
+        },
+        (None, None) => Shape::Circle,
+    };
+    let lens = match options.camera {
+        CameraKind::Realistic => {
+            let name = options.lens.as_deref().unwrap_or("double-gauss");
+            let prescription = match lens::bundled(name) {
+                Some(prescription) => Ok(prescription.to_string()),
+                None => std::fs::read_to_string(name).map_err(|e| e.to_string()),
+            };
+            match prescription.and_then(|p| lens::parse_prescription(&p)) {
+                Ok(elements) => elements,
+                Err(e) => {
+                    eprintln!("Could not read lens {}: {}", name, e);
+                    std::process::exit(1);
                 }
-                let pixel_color = color_sampler
-                    .get_and_reset()
-                    .gamma_correct(GAMMA_CORRECTION);
+            }
+        }
+        _ => Vec::new(),
+    };
+    let optics = Optics {
+        aperture: Aperture::new(shape, options.anamorphic),
+        lens,
+    };
+    // World
+    let world = match options.scene {
+        Scene::Cover => scenes::cover(),
+        Scene::Materials => scenes::materials(),
+        Scene::Cutouts => scenes::cutouts(),
+    };
 
-                row_output_builder.append(color_string(&pixel_color));
-                row_output_builder.append("\n");
+    let vup = Vec3::new(0.0, 1.0, 0.0);
+    let lensed_camera = |pose: &Pose, optics: &Optics| match options.stereo {
+        None => make_camera(&options, pose, &vup, aspect_ratio, optics, None),
+        Some(packing) => {
+            let convergence_distance = options
+                .convergence_distance
+                .or(pose.focus_dist)
+                .unwrap_or(FOCUS_DIST);
+            let rig = StereoRig {
+                interocular: options.interocular.unwrap_or(convergence_distance / 30.0),
+                convergence: options.convergence.unwrap_or(match options.camera {
+                    CameraKind::Perspective => Convergence::OffAxis,
+                    _ => Convergence::Parallel,
+                }),
+                convergence_distance,
+            };
+            let eye = |eye| {
+                make_camera(
+                    &options,
+                    pose,
+                    &vup,
+                    aspect_ratio,
+                    optics,
+                    Some((&rig, eye)),
+                )
+            };
+            eye(Eye::Left).and_then(|left| {
+                let stereo = StereoCamera::new(left, eye(Eye::Right)?, packing);
+                Ok(Box::new(stereo) as Box<dyn Camera>)
+            })
+        }
+    };
+    // Pose with the focus distance chosen in the options, measured along the view
+    // direction
+    let focused = |pose: &Pose| -> Result<Pose, String> {
+        let point = match options.focus {
+            None => return Ok(pose.clone()),
+            Some(Focus::Distance(distance)) => {
+                return Ok(Pose {
+                    focus_dist: Some(distance),
+                    ..pose.clone()
+                })
+            }
+            Some(Focus::Lookat) => pose.lookat.clone(),
+            Some(Focus::Point(x, y, z)) => Vec3::new(x, y, z),
+            Some(Focus::Pixel(x, y)) => {
+                if x >= width || y >= height {
+                    return Err(format!("Pixel {},{} is outside of the image", x, y));
+                }
+                // Through the center of the lens, where the focus doesn't matter
+                let pinhole = Optics {
+                    aperture: Aperture::circle(),
+                    lens: optics.lens.clone(),
+                };
+                let camera = lensed_camera(pose, &pinhole)?;
+                let s = (x as f64 + 0.5) / width as f64;
+                let t = 1.0 - (y as f64 + 0.5) / height as f64;
+                camera::visible_point(&*camera, &world, s, t)
+                    .ok_or_else(|| format!("Nothing to focus on at pixel {},{}", x, y))?
             }
-            row_output_builder.string().unwrap()
+        };
+        let distance = camera::focus_distance(&pose.lookfrom, &pose.lookat, &point)?;
+        Ok(Pose {
+            focus_dist: Some(distance),
+            ..pose.clone()
         })
-        .collect::<Vec<String>>()
-        .join("\n");
-    bar.finish();
-
-    println!("{}", image_data);
-    eprintln!(
-        "Finished. Rendering took {} seconds.",
-        now.elapsed().as_secs()
+    };
+    let camera_at = |pose: &Pose| lensed_camera(&focused(pose)?, &optics);
+    // A still image is a single frame
+    let path = match &options.keyframes {
+        Some(path) => {
+            let keyframes = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| animation::parse_keyframes(&text));
+            match keyframes {
+                Ok(keyframes) => CameraPath::new(keyframes, options.interpolation),
+                Err(e) => {
+                    eprintln!("Could not read keyframes {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {
+            let still = Keyframe {
+                frame: 0.0,
+                pose: Pose {
+                    lookfrom: Vec3::new(13.0, 2.0, 3.0),
+                    lookat: Vec3::new(0.0, 0.0, 0.0),
+                    fov: None,
+                    focus_dist: None,
+                },
+            };
+            CameraPath::new(vec![still], options.interpolation)
+        }
+    };
+
+    let max_samples = match options.adaptive {
+        Some(adaptive) => adaptive.max_samples,
+        None => NUM_SAMPLES as u32,
+    };
+
+    // Sample generator, seeded identically for every render
+    let sampler: Box<dyn Sampler> = match options.sampler {
+        SamplerKind::Independent => Box::new(IndependentSampler::new(0)),
+        SamplerKind::Stratified => Box::new(StratifiedSampler::new(max_samples, 0)),
+        SamplerKind::Halton => Box::new(HaltonSampler::new(0)),
+        SamplerKind::Sobol => Box::new(SobolSampler::new(0)),
+    };
+
+    let radius = |default| options.filter_radius.unwrap_or(default);
+    let filter = match options.filter {
+        FilterKind::Box => Filter::Box {
+            radius: radius(0.5),
+        },
+        FilterKind::Tent => Filter::Tent {
+            radius: radius(1.0),
+        },
+        FilterKind::Gaussian => Filter::Gaussian {
+            radius: radius(1.5),
+            sigma: radius(1.5) / 3.0,
+        },
+        FilterKind::Mitchell => Filter::Mitchell {
+            radius: radius(2.0),
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        },
+        FilterKind::Lanczos => Filter::Lanczos {
+            radius: radius(3.0),
+        },
+    };
+
+    let pass_samples = match (options.progressive, &options.checkpoint) {
+        (Some(pass_samples), _) => pass_samples,
+        (None, Some(_)) => CHECKPOINT_PASS_SAMPLES,
+        (None, None) => max_samples,
+    }
+    .min(max_samples);
+    let passes = max_samples.div_ceil(pass_samples);
+    let tonemap = match options.tonemap {
+        TonemapKind::Clamp => Tonemap::Clamp,
+        TonemapKind::Reinhard => Tonemap::Reinhard,
+        TonemapKind::ExtendedReinhard => Tonemap::ExtendedReinhard {
+            white: options.white_point,
+        },
+        TonemapKind::Aces => Tonemap::Aces,
+        TonemapKind::Agx => Tonemap::Agx,
+    };
+    let post = PostProcess::new(
+        options.exposure,
+        options.white_balance,
+        tonemap,
+        options.transfer,
     );
+    let snapshot_post = post.with_transfer(options.snapshot_transfer.unwrap_or(options.transfer));
+    let develop = |film: &Film, post: &PostProcess| {
+        let image = if options.denoise {
+            film.denoised_image(|c| post.apply(c))
+        } else {
+            film.image(|c| post.apply(c))
+        };
+        if options.crop {
+            return image;
+        }
+        let (w, h, x, y) = (width, height, region.x0, region.y0);
+        image.embedded(w as usize, h as usize, x as usize, y as usize)
+    };
+
+    let preview = options
+        .preview
+        .map(|mode| Mutex::new(preview::Preview::new(mode, &post)));
+
+    let (first_frame, last_frame) = options.frames.unwrap_or_else(|| path.frames());
+    for frame in first_frame..=last_frame {
+        // Camera at the time the shutter opens, and where it moved to when it closes
+        let time = frame as f64;
+        let camera = camera_at(&path.pose_at(time)).and_then(|camera| {
+            if options.shutter == 0.0 {
+                return Ok(camera);
+            }
+            let close = camera_at(&path.pose_at(time + options.shutter))?;
+            Ok(Box::new(MotionBlurCamera::new(camera, close)) as Box<dyn Camera>)
+        });
+        let camera = camera.unwrap_or_else(|e| {
+            eprintln!("Could not set up the camera: {}", e);
+            std::process::exit(1);
+        });
+        let context = RenderContext {
+            camera: &*camera,
+            width,
+            height,
+            region,
+            world: &world,
+            sampler: &*sampler,
+            filter,
+            options: &options,
+            material_ids: aov::MaterialIds::new(options.aovs.contains(&aov::Aov::MaterialId)),
+            preview: preview.as_ref(),
+            stats: Mutex::new(stats::Stats::default()),
+        };
+
+        // Everything that changes the rendered image, the pass size and output paths don't
+        let fingerprint = checkpoint::fingerprint(&[
+            &world,
+            &camera,
+            &(width, height, region, MAX_REC_DEPTH, max_samples),
+            &(options.sampler, options.spectral, options.adaptive, filter),
+            &(
+                options.clamp_direct,
+                options.clamp_indirect,
+                options.russian_roulette,
+            ),
+        ]);
+        let (mut film, resumed_samples) = match &options.checkpoint {
+            Some(path) if options.resume => match Checkpoint::load(path) {
+                Ok(checkpoint) if checkpoint.fingerprint == fingerprint => {
+                    (checkpoint.film, checkpoint.end_sample)
+                }
+                Ok(_) => {
+                    eprintln!(
+                        "Checkpoint {} belongs to a different scene or settings, refusing to resume",
+                        path.display()
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Could not read checkpoint {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            },
+            _ => (Film::new(region.width, region.height), 0),
+        };
+        let first_pass = (resumed_samples / pass_samples).min(passes);
+
+        // Frames of animations are named in the messages
+        let label = match options.keyframes {
+            Some(_) => format!("frame {} ", frame),
+            None => String::new(),
+        };
+        let pixels_per_pass = region.pixel_count();
+        let bar = ProgressBar::new(pixels_per_pass * (passes - first_pass) as u64);
+        bar.set_style(
+            ProgressStyle::default_bar().template("{wide_bar} {percent}% {msg} ETA {eta}"),
+        );
+        // The preview shows the progress instead
+        if preview.is_some() {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        let now = Instant::now();
+        let mut last_save: Option<Instant> = None;
+        for pass in first_pass..passes {
+            let end_sample = ((pass + 1) * pass_samples).min(max_samples);
+            let message = format!("{}pass {}/{}", label, pass + 1, passes);
+            bar.set_message(&message);
+            if let Some(preview) = &preview {
+                preview.lock().unwrap().set_message(&message);
+            }
+            context.render_pass(&mut film, end_sample, &bar);
+
+            let due = match (options.snapshot_interval, last_save) {
+                (Some(interval), Some(last)) => last.elapsed() >= interval,
+                _ => true,
+            };
+            if !due && pass + 1 < passes {
+                continue;
+            }
+            if let Some(path) = &options.snapshot {
+                if let Err(e) = develop(&film, &snapshot_post).save_ppm(path) {
+                    bar.println(format!(
+                        "Could not write snapshot {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+            if let Some(path) = &options.checkpoint {
+                let checkpoint = Checkpoint {
+                    fingerprint,
+                    end_sample,
+                    film,
+                };
+                if let Err(e) = checkpoint.save(path) {
+                    bar.println(format!(
+                        "Could not write checkpoint {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+                film = checkpoint.film;
+            }
+            last_save = Some(Instant::now());
+        }
+        bar.finish();
+        // Rates cover the sampling only, not developing and writing the image
+        let seconds = now.elapsed().as_secs_f64();
+        if let Some(preview) = &preview {
+            let mut preview = preview.lock().unwrap();
+            preview
+                .draw(&film.colors(), film.width, film.height, &bar)
+                .ok();
+            preview.finish();
+        }
+
+        let image = develop(&film, &post);
+        let written = match options.keyframes {
+            Some(_) => {
+                let path = animation::frame_path(&options.output, frame);
+                image
+                    .save_ppm(&path)
+                    .map_err(|e| format!("Could not write frame {}: {}", path.display(), e))
+            }
+            None => {
+                let stdout = std::io::stdout();
+                let written = image.write_ppm(&mut stdout.lock());
+                written.map_err(|e| format!("Could not write image: {}", e))
+            }
+        };
+        if let Err(e) = written {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        if let Some(path) = &options.aov_output {
+            if let Err(e) = aov::save(path, &film, &options.aovs, &post) {
+                eprintln!("Could not write AOVs to {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        eprintln!(
+            "{}. Rendering took {} seconds, {:.1} samples per pixel on average.",
+            format!("Finished {}", label).trim_end(),
+            now.elapsed().as_secs(),
+            film.total_samples() as f64 / region.pixel_count() as f64
+        );
+        if options.clamp_direct.is_some() || options.clamp_indirect.is_some() {
+            let (fraction, count) = film.clamped();
+            eprintln!(
+                "Clamping removed {:.3}% of the light, from {} of {} samples.",
+                100.0 * fraction,
+                count,
+                film.total_samples()
+            );
+        }
+        let threads = rayon::current_num_threads();
+        let stats = context.stats.into_inner().unwrap();
+        eprintln!("{}", stats.summary(seconds, threads));
+        if let Some(pattern) = &options.stats_json {
+            let path = animation::frame_path(pattern, frame);
+            let json = stats.to_json(seconds, threads);
+            if let Err(e) = image::write_atomically(&path, |out| out.write_all(json.as_bytes())) {
+                eprintln!("Could not write statistics to {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
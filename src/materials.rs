@@ -1,12 +1,27 @@
 use crate::hittable::*;
+use crate::microfacet::{fresnel_dielectric, refract};
 use crate::ray::*;
+use crate::sampler::SampleStream;
+use crate::spectrum::SampledWavelengths;
+use crate::texture::{TexCoord, Texture};
 use crate::vec3::*;
-use rand::thread_rng;
-use rand::Rng;
 use std::fmt;
 
 pub trait Material: fmt::Debug + Send + Sync {
-    fn scatter(&self, ray_in: &Ray, hit: &Hit) -> Option<ScatterResult>;
+    // Random decisions draw their values from the sample stream of the current bounce
+    fn scatter(&self, ray_in: &Ray, hit: &Hit, sampler: &mut SampleStream)
+        -> Option<ScatterResult>;
+
+    // Fraction of rays that hit the surface instead of passing through (alpha)
+    fn opacity(&self, _uv: TexCoord, _location: &Loc) -> f64 {
+        1.0
+    }
+
+    // Color of the surface without its lighting, a guide for the denoiser. Smooth
+    // specular surfaces are white, what they reflect is part of their lighting.
+    fn albedo(&self, _hit: &Hit) -> Color {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -16,7 +31,7 @@ pub struct Lambertian {
 
 impl Lambertian {
     pub fn new(albedo: Color) -> Lambertian {
-        Lambertian { albedo: albedo }
+        Lambertian { albedo }
     }
 }
 
@@ -28,23 +43,79 @@ pub struct Metal {
 
 impl Metal {
     pub fn new(albedo: Color, fuzziness: f64) -> Metal {
-        Metal {
-            albedo: albedo,
-            fuzziness: fuzziness,
+        Metal { albedo, fuzziness }
+    }
+}
+
+// Wavelength dependent index of refraction, wavelengths are given in nm
+#[derive(Debug, Clone)]
+pub enum IorCurve {
+    Constant(f64),
+    // n = a + b / lambda^2, lambda in micrometers
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i), lambda in micrometers
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+// Wavelength of the Fraunhofer d-line, used to specify IORs of glasses
+const REFERENCE_WAVELENGTH: f64 = 587.6;
+
+impl IorCurve {
+    pub fn crown_glass() -> IorCurve {
+        // Schott N-BK7
+        IorCurve::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    pub fn flint_glass() -> IorCurve {
+        // Schott SF11
+        IorCurve::Sellmeier {
+            b: [1.73759695, 0.313747346, 1.89878101],
+            c: [0.013188707, 0.0623068142, 155.23629],
+        }
+    }
+
+    pub fn diamond() -> IorCurve {
+        IorCurve::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0],
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, IorCurve::Constant(_))
+    }
+
+    pub fn at(&self, lambda: f64) -> f64 {
+        let lambda_um = lambda / 1000.0;
+        let lambda_sq = lambda_um * lambda_um;
+        match self {
+            IorCurve::Constant(n) => *n,
+            IorCurve::Cauchy { a, b } => a + b / lambda_sq,
+            IorCurve::Sellmeier { b, c } => (1.0
+                + b.iter()
+                    .zip(c.iter())
+                    .map(|(b_i, c_i)| b_i * lambda_sq / (lambda_sq - c_i))
+                    .sum::<f64>())
+            .sqrt(),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Dielectric {
-    refractive_index: f64,
+    ior: IorCurve,
 }
 
 impl Dielectric {
     pub fn new(refractive_index: f64) -> Dielectric {
-        Dielectric {
-            refractive_index: refractive_index,
-        }
+        Dielectric::with_ior(IorCurve::Constant(refractive_index))
+    }
+
+    pub fn with_ior(ior: IorCurve) -> Dielectric {
+        Dielectric { ior }
     }
 
     fn reflectance(cosine: f64, eta_ratio: f64) -> f64 {
@@ -56,18 +127,23 @@ impl Dielectric {
     // Assumes self and normal to be unit vectors
     // Returns None if no refraction is possible (total reflection)
     #[allow(non_snake_case)]
-    fn refract(&self, incoming: &Vec3, normal: &Vec3, eta_ratio: f64) -> Option<Vec3> {
-        let mut rng = thread_rng();
+    fn refract(
+        &self,
+        incoming: &Vec3,
+        normal: &Vec3,
+        eta_ratio: f64,
+        sampler: &mut SampleStream,
+    ) -> Option<Vec3> {
         let cos_theta = incoming.dot(&-normal).min(1.0);
 
-        if Self::reflectance(cos_theta, eta_ratio) > rng.gen::<f64>() {
+        if Self::reflectance(cos_theta, eta_ratio) > sampler.get_1d() {
             return None;
         }
 
         let sin_theta = (1.0 - cos_theta.powf(2.0)).sqrt();
 
         if eta_ratio * sin_theta <= 1.0 {
-            let R_orth_prime = eta_ratio * (normal - cos_theta * normal);
+            let R_orth_prime = eta_ratio * (incoming + cos_theta * normal);
             let R_par_prime = -(1.0 - R_orth_prime.length_squared()).sqrt() * normal;
             Some(R_orth_prime + R_par_prime)
         } else {
@@ -76,6 +152,89 @@ impl Dielectric {
     }
 }
 
+// Blend of two materials, mask = 0 selects a, mask = 1 selects b
+#[derive(Debug)]
+pub struct MixMaterial {
+    a: Box<dyn Material>,
+    b: Box<dyn Material>,
+    mask: Box<dyn Texture>,
+}
+
+impl MixMaterial {
+    pub fn new<T: Texture + 'static>(
+        a: Box<dyn Material>,
+        b: Box<dyn Material>,
+        mask: T,
+    ) -> MixMaterial {
+        MixMaterial {
+            a,
+            b,
+            mask: Box::new(mask),
+        }
+    }
+}
+
+// Reflections between the base and the inside of the coating before Russian roulette
+// may end the walk, and the highest probability to continue it after that
+const COATING_ROULETTE_BOUNCES: usize = 3;
+const COATING_MAX_SURVIVAL: f64 = 0.95;
+
+// Smooth dielectric layer on top of an arbitrary base material. Light that enters the
+// layer is tinted on every pass and may be reflected back onto the base several times.
+#[derive(Debug)]
+pub struct Coated {
+    base: Box<dyn Material>,
+    ior: f64,
+    // Transmittance of a single pass through the layer at normal incidence
+    tint: Color,
+}
+
+impl Coated {
+    pub fn new(base: Box<dyn Material>, ior: f64, tint: Color) -> Coated {
+        Coated { base, ior, tint }
+    }
+
+    // Beer-Lambert attenuation of a pass through the layer in direction dir
+    fn layer_transmittance(&self, dir: &Vec3, normal: &Vec3) -> Color {
+        let path_length = 1.0 / dir.unit_vector().dot(normal).abs().max(1e-4);
+        Vec3::new(
+            self.tint.e0.powf(path_length),
+            self.tint.e1.powf(path_length),
+            self.tint.e2.powf(path_length),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    // Fully opaque at or above the cutoff, fully transparent below
+    Mask(f64),
+    // Fractional alpha, rays pass through stochastically
+    Blend,
+}
+
+// Cutout of a base material by an alpha texture, e.g. for foliage cards and fences
+#[derive(Debug)]
+pub struct AlphaMasked {
+    base: Box<dyn Material>,
+    alpha: Box<dyn Texture>,
+    mode: AlphaMode,
+}
+
+impl AlphaMasked {
+    pub fn new<T: Texture + 'static>(
+        base: Box<dyn Material>,
+        alpha: T,
+        mode: AlphaMode,
+    ) -> AlphaMasked {
+        AlphaMasked {
+            base,
+            alpha: Box::new(alpha),
+            mode,
+        }
+    }
+}
+
 pub struct ScatterResult {
     pub attenuation: Color,
     pub ray: Ray,
@@ -83,25 +242,20 @@ pub struct ScatterResult {
 
 impl ScatterResult {
     pub fn new(attenuation: Color, ray: Ray) -> ScatterResult {
-        ScatterResult {
-            attenuation: attenuation,
-            ray: ray,
-        }
+        ScatterResult { attenuation, ray }
     }
 }
 
 impl Vec3 {
     // Assumes self and normal to be unit vectors
-    fn reflect(&self, normal: &Vec3) -> Vec3 {
+    pub fn reflect(&self, normal: &Vec3) -> Vec3 {
         self - 2.0 * self.dot(normal) * normal
     }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _: &Ray, hit: &Hit) -> Option<ScatterResult> {
-        let mut rng = thread_rng();
-
-        let uncorrected_scatter_dir = &hit.normal + Vec3::random_unit_vector(&mut rng);
+    fn scatter(&self, _: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        let uncorrected_scatter_dir = &hit.normal + Vec3::uniform_unit_vector(sampler.get_2d());
         // case of scatter direction being exactly opposite
         let scatter_direction = if uncorrected_scatter_dir.is_near_zero() {
             &hit.normal
@@ -112,14 +266,16 @@ impl Material for Lambertian {
         let scattered_ray = Ray::new(hit.location.clone(), scatter_direction.clone());
         Some(ScatterResult::new(self.albedo.clone(), scattered_ray))
     }
+
+    fn albedo(&self, _hit: &Hit) -> Color {
+        self.albedo.clone()
+    }
 }
 
 impl Material for Metal {
-    fn scatter(&self, r: &Ray, hit: &Hit) -> Option<ScatterResult> {
-        let mut rng = thread_rng();
-
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
         let reflected_direction = r.unit_direction().reflect(&hit.normal)
-            + self.fuzziness * Vec3::random_in_unit_sphere(&mut rng);
+            + self.fuzziness * Vec3::uniform_in_unit_sphere(sampler.get_2d(), sampler.get_1d());
         if reflected_direction.dot(&hit.normal) > 0.0 {
             let scattered_ray = Ray::new(hit.location.clone(), reflected_direction);
             Some(ScatterResult::new(self.albedo.clone(), scattered_ray))
@@ -128,28 +284,283 @@ impl Material for Metal {
             None
         }
     }
+
+    fn albedo(&self, _hit: &Hit) -> Color {
+        self.albedo.clone()
+    }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r: &Ray, hit: &Hit) -> Option<ScatterResult> {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
         let attenuation = Vec3::new(1.0, 1.0, 1.0);
 
+        // In spectral mode a dispersive IOR is evaluated at the hero wavelength only,
+        // the other wavelengths can't follow the same direction.
+        let (refractive_index, wavelengths) = match r.wavelengths {
+            Some(wavelengths) if self.ior.is_dispersive() => (
+                self.ior.at(wavelengths.hero()),
+                Some(wavelengths.terminate_secondary()),
+            ),
+            _ => (self.ior.at(REFERENCE_WAVELENGTH), None),
+        };
+
         let eta_frac = if hit.surface == Surface::Inside {
-            self.refractive_index
+            refractive_index
         } else {
-            1.0 / self.refractive_index
+            1.0 / refractive_index
         };
 
         let direction = if let Some(refracted_direction) =
-            self.refract(&r.unit_direction(), &hit.normal, eta_frac)
+            self.refract(&r.unit_direction(), &hit.normal, eta_frac, sampler)
         {
             refracted_direction // refraction case
         } else {
             r.unit_direction().reflect(&hit.normal) // total reflection case
         };
+        let scattered_ray = Ray::new(hit.location.clone(), direction);
         Some(ScatterResult::new(
             attenuation,
-            Ray::new(hit.location.clone(), direction),
+            match wavelengths {
+                Some(wavelengths) => scattered_ray.with_wavelengths(wavelengths),
+                None => scattered_ray,
+            },
         ))
     }
 }
+
+impl Material for MixMaterial {
+    // Choosing a child with the probability of its blend weight and keeping the child's
+    // own weight is an unbiased estimate of the blended material.
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        let mask = self.mask.scalar(hit.uv, &hit.location).clamp(0.0, 1.0);
+        if sampler.get_1d() < mask {
+            self.b.scatter(r, hit, sampler)
+        } else {
+            self.a.scatter(r, hit, sampler)
+        }
+    }
+
+    fn albedo(&self, hit: &Hit) -> Color {
+        let mask = self.mask.scalar(hit.uv, &hit.location).clamp(0.0, 1.0);
+        (1.0 - mask) * self.a.albedo(hit) + mask * self.b.albedo(hit)
+    }
+
+    fn opacity(&self, uv: TexCoord, location: &Loc) -> f64 {
+        let mask = self.mask.scalar(uv, location).clamp(0.0, 1.0);
+        (1.0 - mask) * self.a.opacity(uv, location) + mask * self.b.opacity(uv, location)
+    }
+}
+
+impl Material for Coated {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        if hit.surface == Surface::Inside {
+            return self.base.scatter(r, hit, sampler);
+        }
+        let normal = &hit.normal;
+        let wo = -r.unit_direction();
+
+        if sampler.get_1d() < fresnel_dielectric(wo.dot(normal), self.ior) {
+            let reflected = r.unit_direction().reflect(normal);
+            return Some(ScatterResult::new(
+                Vec3::new(1.0, 1.0, 1.0),
+                Ray::new(hit.location.clone(), reflected),
+            ));
+        }
+
+        // Random walk between the base and the inside of the interface
+        let mut dir = refract(&wo, normal, self.ior)?;
+        let mut attenuation = self.layer_transmittance(&dir, normal);
+        let mut wavelengths: Option<SampledWavelengths> = r.wavelengths;
+        let mut bounce = 0;
+        loop {
+            let ray_to_base = Ray {
+                orig: hit.location.clone(),
+                dir: dir.clone(),
+                wavelengths,
+            };
+            let base_result = self.base.scatter(&ray_to_base, hit, sampler)?;
+            if base_result.ray.wavelengths.is_some() {
+                wavelengths = base_result.ray.wavelengths;
+            }
+            attenuation = attenuation.hadamard(&base_result.attenuation);
+            let up = base_result.ray.unit_direction();
+            let cos_up = up.dot(normal);
+            if cos_up <= 0.0 {
+                // The base transmitted the light, it leaves the layer downwards
+                return Some(ScatterResult::new(attenuation, base_result.ray));
+            }
+            attenuation = attenuation.hadamard(&self.layer_transmittance(&up, normal));
+
+            let exit = if sampler.get_1d() < fresnel_dielectric(cos_up, 1.0 / self.ior) {
+                None
+            } else {
+                refract(&-&up, &-normal, 1.0 / self.ior)
+            };
+            match exit {
+                Some(exit_dir) => {
+                    let mut scattered_ray = Ray::new(hit.location.clone(), exit_dir);
+                    if wavelengths != r.wavelengths {
+                        scattered_ray.wavelengths = wavelengths;
+                    }
+                    return Some(ScatterResult::new(attenuation, scattered_ray));
+                }
+                // Reflected at the inside of the interface, back onto the base
+                None => {
+                    dir = up.reflect(normal);
+                    attenuation = attenuation.hadamard(&self.layer_transmittance(&dir, normal));
+                }
+            }
+            // Walks that carry little light end early, the survivors make up for them
+            bounce += 1;
+            if bounce > COATING_ROULETTE_BOUNCES {
+                let survival = attenuation
+                    .e0
+                    .max(attenuation.e1)
+                    .max(attenuation.e2)
+                    .min(COATING_MAX_SURVIVAL);
+                if sampler.get_1d() >= survival {
+                    return None;
+                }
+                attenuation = attenuation / survival;
+            }
+        }
+    }
+
+    // Seen through the tinted layer
+    fn albedo(&self, hit: &Hit) -> Color {
+        self.base.albedo(hit).hadamard(&self.tint)
+    }
+
+    fn opacity(&self, uv: TexCoord, location: &Loc) -> f64 {
+        self.base.opacity(uv, location)
+    }
+}
+
+impl Material for AlphaMasked {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        self.base.scatter(r, hit, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Color {
+        self.base.albedo(hit)
+    }
+
+    fn opacity(&self, uv: TexCoord, location: &Loc) -> f64 {
+        let alpha = self.alpha.scalar(uv, location).clamp(0.0, 1.0);
+        match self.mode {
+            AlphaMode::Mask(cutoff) if alpha >= cutoff => 1.0,
+            AlphaMode::Mask(_) => 0.0,
+            AlphaMode::Blend => alpha,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    const SAMPLES: u32 = 20000;
+
+    fn hit_on_plane(material: &dyn Material) -> Hit<'_> {
+        Hit::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            material,
+            Surface::Outside,
+            (0.5, 0.5),
+            Tangents {
+                dpdu: Vec3::new(1.0, 0.0, 0.0),
+                dpdv: Vec3::new(0.0, 1.0, 0.0),
+            },
+        )
+    }
+
+    // Weights of the scattered rays for light arriving from 45 degrees, None where the
+    // material absorbed the light
+    fn scatter_weights(material: &dyn Material) -> Vec<Option<Color>> {
+        let hit = hit_on_plane(material);
+        let r = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0));
+        let sampler = IndependentSampler::new(5);
+        let mut stream = SampleStream::new(&sampler);
+        (0..SAMPLES)
+            .map(|i| {
+                stream.start_pixel_sample((0, 0), i);
+                stream.start_bounce(0);
+                material
+                    .scatter(&r, &hit, &mut stream)
+                    .map(|result| result.attenuation)
+            })
+            .collect()
+    }
+
+    fn red() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(1.0, 0.0, 0.0)))
+    }
+
+    fn blue() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(0.0, 0.0, 1.0)))
+    }
+
+    #[test]
+    fn test_mix_extremes_pick_one_child() {
+        let only_a = MixMaterial::new(red(), blue(), 0.0);
+        assert!(scatter_weights(&only_a)
+            .iter()
+            .all(|w| w == &Some(Vec3::new(1.0, 0.0, 0.0))));
+        let only_b = MixMaterial::new(red(), blue(), 1.0);
+        assert!(scatter_weights(&only_b)
+            .iter()
+            .all(|w| w == &Some(Vec3::new(0.0, 0.0, 1.0))));
+    }
+
+    #[test]
+    fn test_mix_picks_children_by_mask() {
+        let half = MixMaterial::new(red(), blue(), 0.5);
+        let weights = scatter_weights(&half);
+        // Each child keeps its own, unscaled weight
+        let reds = weights
+            .iter()
+            .filter(|w| *w == &Some(Vec3::new(1.0, 0.0, 0.0)))
+            .count();
+        let blues = weights
+            .iter()
+            .filter(|w| *w == &Some(Vec3::new(0.0, 0.0, 1.0)))
+            .count();
+        assert_eq!(reds + blues, SAMPLES as usize);
+        let fraction = reds as f64 / SAMPLES as f64;
+        assert!((fraction - 0.5).abs() < 0.02, "red fraction {}", fraction);
+    }
+
+    #[test]
+    fn test_wrappers_keep_opacity() {
+        let cutout = |alpha: f64| -> Box<dyn Material> {
+            Box::new(AlphaMasked::new(red(), alpha, AlphaMode::Blend))
+        };
+        let p = Vec3::zero();
+        let coated = Coated::new(cutout(0.3), 1.5, Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(coated.opacity((0.5, 0.5), &p), 0.3);
+        // Blended by the mask like everything else
+        let mix = MixMaterial::new(cutout(0.2), blue(), 0.25);
+        assert!((mix.opacity((0.5, 0.5), &p) - 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_clear_coating_keeps_energy() {
+        // White furnace: a clear layer over a white base neither absorbs nor adds light
+        let coated = Coated::new(
+            Box::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0))),
+            1.5,
+            Vec3::new(1.0, 1.0, 1.0),
+        );
+        let sum = scatter_weights(&coated)
+            .into_iter()
+            .flatten()
+            .fold(Vec3::zero(), |sum, w| sum + w);
+        let mean = sum / SAMPLES as f64;
+        for v in [mean.e0, mean.e1, mean.e2] {
+            assert!(v > 0.99 && v < 1.005, "white furnace gives {:?}", mean);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// Building blocks for microfacet based materials. Directions in the local shading frame
+// have the surface normal along z and point away from the surface.
+use crate::vec3::*;
+use std::f64::consts::PI;
+
+pub struct Frame {
+    t: Vec3,
+    b: Vec3,
+    n: Vec3,
+}
+
+impl Frame {
+    pub fn from_normal(n: &Vec3) -> Frame {
+        let (t, b) = n.orthonormal_basis();
+        Frame { t, b, n: n.clone() }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.t), v.dot(&self.b), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        v.e0 * &self.t + v.e1 * &self.b + v.e2 * &self.n
+    }
+}
+
+// Roughness is perceptually linear, GGX expects alpha = roughness^2. Perfect mirrors
+// are approximated by a tiny alpha to keep the math finite.
+pub fn roughness_to_alpha(roughness: f64) -> f64 {
+    (roughness * roughness).max(1e-3)
+}
+
+pub fn schlick_weight(cos_theta: f64) -> f64 {
+    (1.0 - cos_theta.clamp(0.0, 1.0)).powf(5.0)
+}
+
+pub fn fresnel_schlick(f0: f64, cos_theta: f64) -> f64 {
+    f0 + (1.0 - f0) * schlick_weight(cos_theta)
+}
+
+pub fn fresnel_schlick_color(f0: &Color, cos_theta: f64) -> Color {
+    let w = schlick_weight(cos_theta);
+    f0 + w * (Vec3::new(1.0, 1.0, 1.0) - f0)
+}
+
+// Unpolarized Fresnel reflectance of a dielectric interface. eta is the ratio of the
+// IOR on the far side over the IOR on the side of the incoming direction.
+pub fn fresnel_dielectric(cos_theta_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_theta_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0; // total internal reflection
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+pub fn reflect(wo: &Vec3, n: &Vec3) -> Vec3 {
+    -wo + 2.0 * wo.dot(n) * n
+}
+
+// Refracts wo (pointing away from the surface, on the side n points to) through
+// an interface with relative IOR eta. Returns None on total internal reflection.
+pub fn refract(wo: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = wo.dot(n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * n)
+}
+
+// Smith masking auxiliary function for GGX
+pub fn smith_lambda(w: &Vec3, alpha: f64) -> f64 {
+    let cos2 = w.e2 * w.e2;
+    if cos2 == 0.0 {
+        return f64::INFINITY;
+    }
+    let tan2 = (w.e0 * w.e0 + w.e1 * w.e1) / cos2;
+    ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) / 2.0
+}
+
+// G2(wo, wi) / G1(wo), the weight of a direction sampled from the visible normals
+pub fn smith_g2_over_g1(wo: &Vec3, wi: &Vec3, alpha: f64) -> f64 {
+    let lambda_o = smith_lambda(wo, alpha);
+    (1.0 + lambda_o) / (1.0 + lambda_o + smith_lambda(wi, alpha))
+}
+
+// Samples a microfacet normal from the distribution of GGX normals visible from wo
+// (Heitz 2018, "Sampling the GGX Distribution of Visible Normals"), u in [0,1)^2
+pub fn sample_ggx_vndf(wo: &Vec3, alpha: f64, u: (f64, f64)) -> Vec3 {
+    let vh = Vec3::new(alpha * wo.e0, alpha * wo.e1, wo.e2).unit_vector();
+    let len_sq = vh.e0 * vh.e0 + vh.e1 * vh.e1;
+    let t1 = if len_sq > 0.0 {
+        Vec3::new(-vh.e1, vh.e0, 0.0) / len_sq.sqrt()
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let t2 = vh.cross(&t1);
+
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.e2);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    Vec3::new(alpha * nh.e0, alpha * nh.e1, nh.e2.max(0.0)).unit_vector()
+}
+
+// Cosine weighted direction in the upper hemisphere, u in [0,1)^2
+pub fn sample_cosine_hemisphere(u: (f64, f64)) -> Vec3 {
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u.0).max(0.0).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fresnel_dielectric() {
+        // Normal incidence on glass reflects ((n - 1) / (n + 1))^2
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-12);
+        // Total internal reflection when leaving glass at a grazing angle
+        assert_eq!(fresnel_dielectric(0.1, 1.0 / 1.5), 1.0);
+    }
+
+    #[test]
+    fn test_refract_snell() {
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new(0.5, 0.0, 0.75_f64.sqrt());
+        let wt = refract(&wo, &n, 1.5).unwrap();
+        assert!((wt.length() - 1.0).abs() < 1e-12);
+        // sin theta_t = sin theta_i / eta, on the other side of the surface
+        assert!((wt.e0 + 0.5 / 1.5).abs() < 1e-12);
+        assert!(wt.e2 < 0.0);
+        assert!(refract(&wo, &n, 1.0 / 1.5).is_some());
+        assert!(refract(&Vec3::new(0.9, 0.0, 0.19_f64.sqrt()), &n, 1.0 / 1.5).is_none());
+    }
+
+    #[test]
+    fn test_vndf_samples_face_wo() {
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        for i in 0..16 {
+            for j in 0..16 {
+                let u = ((i as f64 + 0.5) / 16.0, (j as f64 + 0.5) / 16.0);
+                let h = sample_ggx_vndf(&wo, 0.5, u);
+                assert!((h.length() - 1.0).abs() < 1e-9);
+                assert!(h.e2 >= 0.0 && h.dot(&wo) >= 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = Frame::from_normal(&Vec3::new(1.0, 1.0, 0.0).unit_vector());
+        let v = Vec3::new(0.3, -0.2, 0.9);
+        assert!((&frame.to_world(&frame.to_local(&v)) - &v).length() < 1e-12);
+        assert!((frame.to_local(&Vec3::new(1.0, 1.0, 0.0).unit_vector()).e2 - 1.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,1110 @@
+use crate::animation::Interpolation;
+use crate::aov::Aov;
+use crate::preview::PreviewMode;
+use crate::stereo::{Convergence, Packing};
+use crate::tonemap::Transfer;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: raytracing [OPTIONS] > image.ppm
+
+Options:
+    --scene NAME        Scene to render: cover (default), materials or cutouts
+    --camera NAME       Projection: perspective (default), orthographic,
+                        equirectangular (360 panorama), fisheye (equidistant),
+                        fisheye-equisolid or realistic (traced through a lens)
+    --fov DEGREES       Vertical field of view of the perspective camera (default
+                        20), or angle across the fisheye circle (default 180)
+    --lens NAME         Lens of the realistic camera: double-gauss (default),
+                        cooke-triplet, singlet, or a prescription file with one
+                        interface per line: radius, thickness, ior and aperture
+                        diameter in mm, from the scene to the film
+    --film-diagonal MM  Film size of the realistic camera (default 43.27, 35mm)
+    --resolution WxH    Image size in pixels (default 1200 wide, 3:2, or 2:1 for
+                        panoramas and 1:1 for fisheyes)
+    --region X,Y,W,H    Render only the W x H pixels whose top left corner is at
+                        pixel X,Y, the rest of the image stays black
+    --region-window LEFT,TOP,RIGHT,BOTTOM
+                        Same for a window in fractions of the image size, from 0
+                        (left or top edge) to 1
+    --crop              Write only the region instead of the whole image (AOVs
+                        always cover the region only)
+    --view-width W      Width of the scene an orthographic camera sees
+    --view-height H     Height of the scene an orthographic camera sees (default
+                        what the perspective camera sees at the point it looks at)
+    --aperture-blades N Polygonal aperture of N iris blades, instead of a round one
+    --aperture-rotation DEGREES
+                        Rotation of the polygonal aperture
+    --aperture-image PATH
+                        Aperture shaped like a grayscale PGM/PPM image, white
+                        is open
+    --anamorphic SQUEEZE
+                        Squeeze the aperture horizontally, giving oval bokeh
+                        (e.g. 2 for a 2x anamorphic lens)
+    --focus-distance D  Distance of the sharp plane from the camera (default 10)
+    --focus-lookat      Focus on the point the camera looks at
+    --focus-point X,Y,Z Focus on a point of the scene
+    --focus-pixel X,Y   Focus on what the scene shows at a pixel, counted from the
+                        top left corner of the image
+    --stereo PACKING    Render both eyes of a stereo rig into one image, packed
+                        side-by-side (left eye left) or top-bottom (left eye on
+                        top). Panoramas use omni-directional stereo.
+    --interocular D     Distance between the eyes (default 1/30 of the
+                        convergence distance)
+    --convergence NAME  How the eyes converge: parallel, toe-in or off-axis
+                        (default for the perspective camera, the only one that
+                        can shift its image window; parallel for the others)
+    --convergence-distance D
+                        Distance that appears at screen depth (default the
+                        focus distance)
+    --keyframes PATH    Render an animation along a camera path, with one keyframe
+                        per line: frame, lookfrom x y z, lookat x y z and
+                        optionally the field of view and the focus distance
+    --interpolation NAME
+                        Movement between keyframes: linear or catmull-rom
+                        (default)
+    --frames FIRST..LAST
+                        Frames to render, or a single frame (default all frames
+                        from the first to the last keyframe)
+    --output PATTERN    Files of the frames, the #s are replaced by the frame
+                        number (default frame####.ppm)
+    --shutter FRACTION  Part of a frame the shutter is open for, the camera
+                        moves meanwhile (default 0, no motion blur)
+    --spectral          Trace wavelengths instead of RGB (enables dispersion)
+    --sampler NAME      Sample generator: independent (default), stratified,
+                        halton or sobol (converges fastest)
+    --filter NAME       Pixel filter: box (default), tent, gaussian, mitchell or lanczos
+    --filter-radius R   Filter radius in pixels, at least 0.5 (defaults: box 0.5,
+                        tent 1, gaussian 1.5, mitchell 2, lanczos 3)
+    --clamp-direct L    Limit light that reached the camera after one bounce to L
+                        per sample (per color component)
+    --clamp-indirect L  Same for light after more bounces, removes fireflies
+    --russian-roulette  End paths randomly once they carry little light, instead
+                        of after a fixed number of bounces
+    --adaptive          Stop sampling pixels once their noise is below a threshold
+    --min-spp N         Samples every pixel gets in adaptive mode (default 16)
+    --max-spp N         Upper bound of samples per pixel in adaptive mode (default 1024)
+    --error E           Relative error at which a pixel counts as converged
+                        (default 0.05), the last three imply --adaptive
+    --progressive N     Render in passes of N samples per pixel
+    --snapshot PATH     Write the current image to PATH (PPM) after each pass
+    --snapshot-interval SECONDS
+                        Skip snapshots and checkpoints until SECONDS passed
+                        since the last one
+    --preview MODE      Draw the image into the terminal while rendering, instead of
+                        the progress bar: half-blocks (24-bit colors), sixel or
+                        auto (sixel where the terminal is known to support it)
+    --denoise           Filter the noise out of the image and snapshots
+    --exposure EV       Scale the image by 2^EV before tonemapping (default 0)
+    --white-balance K   Color temperature in Kelvin that should appear white
+    --tonemap NAME      Highlight compression: clamp (default), reinhard,
+                        reinhard-extended, aces or agx
+    --white-point L     Luminance that reinhard-extended maps to white (default 4)
+    --transfer NAME     Encoding of the output: srgb (default), linear or gammaG
+                        (e.g. gamma2.2)
+    --snapshot-transfer NAME
+                        Encoding of snapshots (defaults to --transfer)
+    --aov NAMES         Extra outputs, comma separated: depth, normal, albedo,
+                        object-id, material-id, direct, indirect, emission or all
+    --aov-output PATH   Write the AOVs as layers of PATH if it ends in .exr, or else
+                        next to it as PPMs named after each AOV (all AOVs if --aov
+                        is missing)
+    --stats-json PATH   Write the render statistics to PATH as JSON, a run of #s is
+                        replaced by the frame number
+    --checkpoint PATH   Save the render state to PATH after each pass (passes of 16
+                        samples per pixel unless --progressive sets their size)
+    --resume            Continue the render saved in the checkpoint
+    -h, --help          Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scene {
+    Cover,
+    Materials,
+    Cutouts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraKind {
+    Perspective,
+    Orthographic,
+    Equirectangular,
+    Fisheye,
+    FisheyeEquisolid,
+    Realistic,
+}
+
+// How the distance of the plane that is in focus is found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Focus {
+    Distance(f64),
+    Lookat,
+    Point(f64, f64, f64),
+    Pixel(u32, u32),
+}
+
+// Part of the image to render, measured from the top left corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Window {
+        left: f64,
+        top: f64,
+        right: f64,
+        bottom: f64,
+    },
+}
+
+// Extent of the scene shown by an orthographic camera, along one side of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewSize {
+    Width(f64),
+    Height(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TonemapKind {
+    Clamp,
+    Reinhard,
+    ExtendedReinhard,
+    Aces,
+    Agx,
+}
+
+// Per pixel sample budget in adaptive mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // Standard error of the pixel luminance relative to the luminance
+    pub error_threshold: f64,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> AdaptiveSampling {
+        AdaptiveSampling {
+            min_samples: 16,
+            max_samples: 1024,
+            error_threshold: 0.05,
+        }
+    }
+}
+
+// Settings of a single render, parsed from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub scene: Scene,
+    pub camera: CameraKind,
+    // None frames the scene like the perspective camera
+    pub view_size: Option<ViewSize>,
+    // None uses the default of the camera
+    pub fov: Option<f64>,
+    // Width and height, None picks a default for the camera
+    pub resolution: Option<(u32, u32)>,
+    // None renders the whole image
+    pub region: Option<Region>,
+    pub crop: bool,
+    // Name of a bundled lens or path of a prescription, None is the default lens
+    pub lens: Option<String>,
+    pub film_diagonal: Option<f64>,
+    // Defocus blur shapes, None is a round aperture
+    pub aperture_blades: Option<u32>,
+    pub aperture_rotation: f64,
+    pub aperture_image: Option<PathBuf>,
+    pub anamorphic: f64,
+    // None keeps the focus of the camera path, or the default distance
+    pub focus: Option<Focus>,
+    // None renders a single view
+    pub stereo: Option<Packing>,
+    pub interocular: Option<f64>,
+    // None is off-axis for perspective cameras, parallel for the others
+    pub convergence: Option<Convergence>,
+    pub convergence_distance: Option<f64>,
+    // None renders a single image from the default camera position to stdout
+    pub keyframes: Option<PathBuf>,
+    pub interpolation: Interpolation,
+    // First and last frame, None renders all frames of the camera path
+    pub frames: Option<(u32, u32)>,
+    pub output: String,
+    pub shutter: f64,
+    pub spectral: bool,
+    pub sampler: SamplerKind,
+    pub filter: FilterKind,
+    // None uses the default radius of the filter
+    pub filter_radius: Option<f64>,
+    // Largest component of the light a sample may contribute, None doesn't clamp
+    pub clamp_direct: Option<f64>,
+    pub clamp_indirect: Option<f64>,
+    pub russian_roulette: bool,
+    // None renders a fixed number of samples per pixel
+    pub adaptive: Option<AdaptiveSampling>,
+    // Samples per pixel of a pass, None renders all samples in one pass
+    pub progressive: Option<u32>,
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Option<Duration>,
+    // None shows a progress bar only
+    pub preview: Option<PreviewMode>,
+    pub denoise: bool,
+    pub exposure: f64,
+    // None leaves the colors as rendered
+    pub white_balance: Option<f64>,
+    pub tonemap: TonemapKind,
+    pub white_point: f64,
+    pub transfer: Transfer,
+    // None encodes snapshots like the final image
+    pub snapshot_transfer: Option<Transfer>,
+    pub aovs: Vec<Aov>,
+    pub aov_output: Option<PathBuf>,
+    pub stats_json: Option<String>,
+    pub checkpoint: Option<PathBuf>,
+    pub resume: bool,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            scene: Scene::Cover,
+            camera: CameraKind::Perspective,
+            view_size: None,
+            fov: None,
+            resolution: None,
+            region: None,
+            crop: false,
+            lens: None,
+            film_diagonal: None,
+            aperture_blades: None,
+            aperture_rotation: 0.0,
+            aperture_image: None,
+            anamorphic: 1.0,
+            focus: None,
+            stereo: None,
+            interocular: None,
+            convergence: None,
+            convergence_distance: None,
+            keyframes: None,
+            interpolation: Interpolation::CatmullRom,
+            frames: None,
+            output: "frame####.ppm".to_string(),
+            shutter: 0.0,
+            spectral: false,
+            sampler: SamplerKind::Independent,
+            filter: FilterKind::Box,
+            filter_radius: None,
+            clamp_direct: None,
+            clamp_indirect: None,
+            russian_roulette: false,
+            adaptive: None,
+            progressive: None,
+            snapshot: None,
+            snapshot_interval: None,
+            preview: None,
+            denoise: false,
+            exposure: 0.0,
+            white_balance: None,
+            tonemap: TonemapKind::Clamp,
+            white_point: 4.0,
+            transfer: Transfer::Srgb,
+            snapshot_transfer: None,
+            aovs: Vec::new(),
+            aov_output: None,
+            stats_json: None,
+            checkpoint: None,
+            resume: false,
+            help: false,
+        }
+    }
+}
+
+fn value_of(arg: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("Missing value for {}", arg))
+}
+
+fn number_of<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+    let value = value_of(arg, value)?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
+
+// Comma separated list of count numbers
+fn numbers_of<T: std::str::FromStr>(
+    arg: &str,
+    value: Option<String>,
+    count: usize,
+) -> Result<Vec<T>, String> {
+    let value = value_of(arg, value)?;
+    let numbers: Vec<T> = value
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))?;
+    if numbers.len() != count {
+        return Err(format!("{} needs {} values", arg, count));
+    }
+    Ok(numbers)
+}
+
+fn clamp_of(arg: &str, value: Option<String>) -> Result<Option<f64>, String> {
+    let limit: f64 = number_of(arg, value)?;
+    if limit.is_nan() || limit <= 0.0 {
+        return Err("Clamping limits have to be positive".to_string());
+    }
+    Ok(Some(limit))
+}
+
+fn transfer_of(arg: &str, value: Option<String>) -> Result<Transfer, String> {
+    let value = value_of(arg, value)?;
+    match value.as_str() {
+        "srgb" => Ok(Transfer::Srgb),
+        "linear" => Ok(Transfer::Linear),
+        other => match other.strip_prefix("gamma").map(str::parse::<f64>) {
+            Some(Ok(gamma)) if gamma > 0.0 => Ok(Transfer::Gamma(gamma)),
+            _ => Err(format!("Unknown transfer function: {}", value)),
+        },
+    }
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        Options::parse(env::args().skip(1))
+    }
+
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => {
+                    options.scene = match value_of(&arg, args.next())?.as_str() {
+                        "cover" => Scene::Cover,
+                        "materials" => Scene::Materials,
+                        "cutouts" => Scene::Cutouts,
+                        other => return Err(format!("Unknown scene: {}", other)),
+                    }
+                }
+                "--camera" => {
+                    options.camera = match value_of(&arg, args.next())?.as_str() {
+                        "perspective" => CameraKind::Perspective,
+                        "orthographic" => CameraKind::Orthographic,
+                        "equirectangular" => CameraKind::Equirectangular,
+                        "fisheye" => CameraKind::Fisheye,
+                        "fisheye-equisolid" => CameraKind::FisheyeEquisolid,
+                        "realistic" => CameraKind::Realistic,
+                        other => return Err(format!("Unknown camera: {}", other)),
+                    }
+                }
+                "--view-width" | "--view-height" => {
+                    let size: f64 = number_of(&arg, args.next())?;
+                    if size.is_nan() || size <= 0.0 {
+                        return Err("The view size has to be positive".to_string());
+                    }
+                    options.view_size = Some(if arg == "--view-width" {
+                        ViewSize::Width(size)
+                    } else {
+                        ViewSize::Height(size)
+                    });
+                }
+                "--fov" => {
+                    let fov: f64 = number_of(&arg, args.next())?;
+                    if !(fov > 0.0 && fov <= 360.0) {
+                        return Err(format!("Field of view out of range: {}", fov));
+                    }
+                    options.fov = Some(fov);
+                }
+                "--lens" => options.lens = Some(value_of(&arg, args.next())?),
+                "--film-diagonal" => {
+                    let diagonal: f64 = number_of(&arg, args.next())?;
+                    if diagonal.is_nan() || diagonal <= 0.0 {
+                        return Err("The film diagonal has to be positive".to_string());
+                    }
+                    options.film_diagonal = Some(diagonal);
+                }
+                "--resolution" => {
+                    let value = value_of(&arg, args.next())?;
+                    let size = value
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+                    options.resolution = match size {
+                        Some((width, height)) if width > 0 && height > 0 => Some((width, height)),
+                        _ => return Err(format!("Invalid resolution: {}", value)),
+                    };
+                }
+                "--region" => {
+                    let r = numbers_of(&arg, args.next(), 4)?;
+                    if r[2] == 0 || r[3] == 0 {
+                        return Err("The region can't be empty".to_string());
+                    }
+                    options.region = Some(Region::Pixels {
+                        x: r[0],
+                        y: r[1],
+                        width: r[2],
+                        height: r[3],
+                    });
+                }
+                "--region-window" => {
+                    let w: Vec<f64> = numbers_of(&arg, args.next(), 4)?;
+                    let within = |low: f64, high: f64| 0.0 <= low && low < high && high <= 1.0;
+                    if !within(w[0], w[2]) || !within(w[1], w[3]) {
+                        return Err(
+                            "The window needs 0 <= left < right <= 1 and 0 <= top < bottom <= 1"
+                                .to_string(),
+                        );
+                    }
+                    options.region = Some(Region::Window {
+                        left: w[0],
+                        top: w[1],
+                        right: w[2],
+                        bottom: w[3],
+                    });
+                }
+                "--crop" => options.crop = true,
+                "--aperture-blades" => {
+                    let blades: u32 = number_of(&arg, args.next())?;
+                    if blades < 3 {
+                        return Err("An aperture needs at least 3 blades".to_string());
+                    }
+                    options.aperture_blades = Some(blades);
+                }
+                "--aperture-rotation" => options.aperture_rotation = number_of(&arg, args.next())?,
+                "--aperture-image" => {
+                    options.aperture_image = Some(value_of(&arg, args.next())?.into())
+                }
+                "--anamorphic" => {
+                    let squeeze: f64 = number_of(&arg, args.next())?;
+                    if squeeze.is_nan() || squeeze < 1.0 {
+                        return Err("The anamorphic squeeze has to be at least 1".to_string());
+                    }
+                    options.anamorphic = squeeze;
+                }
+                "--focus-distance" => {
+                    let distance: f64 = number_of(&arg, args.next())?;
+                    if distance.is_nan() || distance <= 0.0 {
+                        return Err("The focus distance has to be positive".to_string());
+                    }
+                    options.focus = Some(Focus::Distance(distance));
+                }
+                "--focus-lookat" => options.focus = Some(Focus::Lookat),
+                "--focus-point" => {
+                    let p = numbers_of(&arg, args.next(), 3)?;
+                    options.focus = Some(Focus::Point(p[0], p[1], p[2]));
+                }
+                "--focus-pixel" => {
+                    let p = numbers_of(&arg, args.next(), 2)?;
+                    options.focus = Some(Focus::Pixel(p[0], p[1]));
+                }
+                "--stereo" => {
+                    options.stereo = match value_of(&arg, args.next())?.as_str() {
+                        "side-by-side" => Some(Packing::SideBySide),
+                        "top-bottom" => Some(Packing::TopBottom),
+                        other => return Err(format!("Unknown stereo packing: {}", other)),
+                    }
+                }
+                "--interocular" => {
+                    let distance: f64 = number_of(&arg, args.next())?;
+                    if distance.is_nan() || distance < 0.0 {
+                        return Err("The interocular distance can't be negative".to_string());
+                    }
+                    options.interocular = Some(distance);
+                }
+                "--convergence" => {
+                    options.convergence = match value_of(&arg, args.next())?.as_str() {
+                        "parallel" => Some(Convergence::Parallel),
+                        "toe-in" => Some(Convergence::ToeIn),
+                        "off-axis" => Some(Convergence::OffAxis),
+                        other => return Err(format!("Unknown convergence: {}", other)),
+                    }
+                }
+                "--convergence-distance" => {
+                    let distance: f64 = number_of(&arg, args.next())?;
+                    if distance.is_nan() || distance <= 0.0 {
+                        return Err("The convergence distance has to be positive".to_string());
+                    }
+                    options.convergence_distance = Some(distance);
+                }
+                "--keyframes" => options.keyframes = Some(value_of(&arg, args.next())?.into()),
+                "--interpolation" => {
+                    options.interpolation = match value_of(&arg, args.next())?.as_str() {
+                        "linear" => Interpolation::Linear,
+                        "catmull-rom" => Interpolation::CatmullRom,
+                        other => return Err(format!("Unknown interpolation: {}", other)),
+                    }
+                }
+                "--frames" => {
+                    let value = value_of(&arg, args.next())?;
+                    let range = match value.split_once("..") {
+                        Some((first, last)) => first.parse().ok().zip(last.parse().ok()),
+                        None => value.parse().ok().map(|frame| (frame, frame)),
+                    };
+                    options.frames = match range {
+                        Some((first, last)) if first <= last => Some((first, last)),
+                        _ => return Err(format!("Invalid frame range: {}", value)),
+                    };
+                }
+                "--output" => {
+                    options.output = value_of(&arg, args.next())?;
+                    if !options.output.contains('#') {
+                        return Err("The output pattern needs a # for the frame number".to_string());
+                    }
+                }
+                "--shutter" => {
+                    let shutter: f64 = number_of(&arg, args.next())?;
+                    if !(0.0..=1.0).contains(&shutter) {
+                        return Err("The shutter is open for 0 to 1 frames".to_string());
+                    }
+                    options.shutter = shutter;
+                }
+                "--spectral" => options.spectral = true,
+                "--sampler" => {
+                    options.sampler = match value_of(&arg, args.next())?.as_str() {
+                        "independent" => SamplerKind::Independent,
+                        "stratified" => SamplerKind::Stratified,
+                        "halton" => SamplerKind::Halton,
+                        "sobol" => SamplerKind::Sobol,
+                        other => return Err(format!("Unknown sampler: {}", other)),
+                    }
+                }
+                "--filter" => {
+                    options.filter = match value_of(&arg, args.next())?.as_str() {
+                        "box" => FilterKind::Box,
+                        "tent" => FilterKind::Tent,
+                        "gaussian" => FilterKind::Gaussian,
+                        "mitchell" => FilterKind::Mitchell,
+                        "lanczos" => FilterKind::Lanczos,
+                        other => return Err(format!("Unknown filter: {}", other)),
+                    }
+                }
+                "--filter-radius" => {
+                    let radius: f64 = number_of(&arg, args.next())?;
+                    // Smaller filters would leave pixels without any samples
+                    if radius.is_nan() || radius < 0.5 {
+                        return Err("The filter radius has to be at least 0.5".to_string());
+                    }
+                    options.filter_radius = Some(radius);
+                }
+                "--clamp-direct" => options.clamp_direct = clamp_of(&arg, args.next())?,
+                "--clamp-indirect" => options.clamp_indirect = clamp_of(&arg, args.next())?,
+                "--russian-roulette" => options.russian_roulette = true,
+                "--adaptive" => {
+                    options
+                        .adaptive
+                        .get_or_insert_with(AdaptiveSampling::default);
+                }
+                "--min-spp" => {
+                    let adaptive = options
+                        .adaptive
+                        .get_or_insert_with(AdaptiveSampling::default);
+                    adaptive.min_samples = number_of(&arg, args.next())?;
+                }
+                "--max-spp" => {
+                    let adaptive = options
+                        .adaptive
+                        .get_or_insert_with(AdaptiveSampling::default);
+                    adaptive.max_samples = number_of(&arg, args.next())?;
+                }
+                "--error" => {
+                    let threshold: f64 = number_of(&arg, args.next())?;
+                    // Pixels would never reach a threshold of zero
+                    if !threshold.is_finite() || threshold <= 0.0 {
+                        return Err("The error threshold has to be positive".to_string());
+                    }
+                    let adaptive = options
+                        .adaptive
+                        .get_or_insert_with(AdaptiveSampling::default);
+                    adaptive.error_threshold = threshold;
+                }
+                "--progressive" => {
+                    let pass_samples: u32 = number_of(&arg, args.next())?;
+                    if pass_samples == 0 {
+                        return Err("Passes need at least one sample per pixel".to_string());
+                    }
+                    options.progressive = Some(pass_samples);
+                }
+                "--snapshot" => options.snapshot = Some(value_of(&arg, args.next())?.into()),
+                "--snapshot-interval" => {
+                    let seconds: f64 = number_of(&arg, args.next())?;
+                    options.snapshot_interval = Duration::try_from_secs_f64(seconds)
+                        .map(Some)
+                        .map_err(|_| format!("Invalid value for {}: {}", arg, seconds))?;
+                }
+                "--preview" => {
+                    options.preview = match value_of(&arg, args.next())?.as_str() {
+                        "auto" => Some(PreviewMode::Auto),
+                        "half-blocks" => Some(PreviewMode::HalfBlocks),
+                        "sixel" => Some(PreviewMode::Sixel),
+                        other => return Err(format!("Unknown preview: {}", other)),
+                    }
+                }
+                "--denoise" => options.denoise = true,
+                "--exposure" => {
+                    let stops: f64 = number_of(&arg, args.next())?;
+                    if !stops.is_finite() {
+                        return Err(format!("Invalid exposure: {}", stops));
+                    }
+                    options.exposure = stops;
+                }
+                "--white-balance" => {
+                    let kelvin: f64 = number_of(&arg, args.next())?;
+                    if !(1000.0..=40000.0).contains(&kelvin) {
+                        return Err(format!("Color temperature out of range: {}K", kelvin));
+                    }
+                    options.white_balance = Some(kelvin);
+                }
+                "--tonemap" => {
+                    options.tonemap = match value_of(&arg, args.next())?.as_str() {
+                        "clamp" => TonemapKind::Clamp,
+                        "reinhard" => TonemapKind::Reinhard,
+                        "reinhard-extended" => TonemapKind::ExtendedReinhard,
+                        "aces" => TonemapKind::Aces,
+                        "agx" => TonemapKind::Agx,
+                        other => return Err(format!("Unknown tonemapping operator: {}", other)),
+                    }
+                }
+                "--white-point" => {
+                    let white: f64 = number_of(&arg, args.next())?;
+                    if white.is_nan() || white <= 0.0 {
+                        return Err("The white point has to be positive".to_string());
+                    }
+                    options.white_point = white;
+                }
+                "--transfer" => options.transfer = transfer_of(&arg, args.next())?,
+                "--snapshot-transfer" => {
+                    options.snapshot_transfer = Some(transfer_of(&arg, args.next())?)
+                }
+                "--aov" => {
+                    for name in value_of(&arg, args.next())?.split(',') {
+                        let aovs = match name {
+                            "all" => Aov::ALL.to_vec(),
+                            _ => vec![Aov::from_name(name)
+                                .ok_or_else(|| format!("Unknown AOV: {}", name))?],
+                        };
+                        for aov in aovs {
+                            if !options.aovs.contains(&aov) {
+                                options.aovs.push(aov);
+                            }
+                        }
+                    }
+                }
+                "--aov-output" => options.aov_output = Some(value_of(&arg, args.next())?.into()),
+                "--stats-json" => options.stats_json = Some(value_of(&arg, args.next())?),
+                "--checkpoint" => options.checkpoint = Some(value_of(&arg, args.next())?.into()),
+                "--resume" => options.resume = true,
+                "-h" | "--help" => options.help = true,
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        if let Some(adaptive) = options.adaptive {
+            if adaptive.min_samples < 2 || adaptive.min_samples > adaptive.max_samples {
+                return Err("Adaptive sampling needs 2 <= min-spp <= max-spp".to_string());
+            }
+        }
+        if options.crop && options.region.is_none() {
+            return Err("--crop needs a --region to crop to".to_string());
+        }
+        if options.view_size.is_some() && options.camera != CameraKind::Orthographic {
+            return Err("The view size needs --camera orthographic".to_string());
+        }
+        if options.aperture_blades.is_some() && options.aperture_image.is_some() {
+            return Err("The aperture can't have blades and an image".to_string());
+        }
+        let rig_options = options.interocular.is_some()
+            || options.convergence_distance.is_some()
+            || options.convergence.is_some();
+        if rig_options && options.stereo.is_none() {
+            return Err("Stereo rig settings need --stereo".to_string());
+        }
+        // Panoramas turn the eyes instead, whatever the convergence
+        let shifts_window = matches!(
+            options.camera,
+            CameraKind::Perspective | CameraKind::Equirectangular
+        );
+        if options.convergence == Some(Convergence::OffAxis) && !shifts_window {
+            return Err("Off-axis convergence needs --camera perspective".to_string());
+        }
+        let lens_options = options.lens.is_some() || options.film_diagonal.is_some();
+        if lens_options && options.camera != CameraKind::Realistic {
+            return Err("Lens settings need --camera realistic".to_string());
+        }
+        let focusing = matches!(
+            options.camera,
+            CameraKind::Perspective | CameraKind::Realistic
+        );
+        if options.focus.is_some() && !focusing {
+            return Err("The camera has no focus to set".to_string());
+        }
+        let animation_options = options.interpolation != Interpolation::CatmullRom
+            || options.frames.is_some()
+            || options.output != Options::default().output
+            || options.shutter > 0.0;
+        if animation_options && options.keyframes.is_none() {
+            return Err("Animation settings need --keyframes".to_string());
+        }
+        if options.keyframes.is_some()
+            && (options.checkpoint.is_some() || options.aov_output.is_some())
+        {
+            return Err("Animations can't write checkpoints or AOVs".to_string());
+        }
+        match (options.camera, options.fov) {
+            (CameraKind::Perspective, Some(fov)) if fov >= 180.0 => {
+                return Err("Perspective cameras see less than 180 degrees".to_string())
+            }
+            (
+                CameraKind::Orthographic | CameraKind::Equirectangular | CameraKind::Realistic,
+                Some(_),
+            ) => return Err("The camera has no field of view to set".to_string()),
+            _ => (),
+        }
+        match (&options.aov_output, options.aovs.is_empty()) {
+            (Some(_), true) => options.aovs = Aov::ALL.to_vec(),
+            (None, false) => return Err("--aov needs an --aov-output to write to".to_string()),
+            _ => (),
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume needs a --checkpoint to resume from".to_string());
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+    }
+
+    #[test]
+    fn test_flags() {
+        let options =
+            parse(&["--spectral", "--scene", "materials", "--sampler", "halton"]).unwrap();
+        assert!(options.spectral);
+        assert_eq!(options.scene, Scene::Materials);
+        assert_eq!(options.sampler, SamplerKind::Halton);
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--scene"]).is_err());
+        assert!(parse(&["--scene", "nowhere"]).is_err());
+        assert!(parse(&["--sampler", "dice"]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let options = parse(&["--filter", "mitchell", "--filter-radius", "1.5"]).unwrap();
+        assert_eq!(options.filter, FilterKind::Mitchell);
+        assert_eq!(options.filter_radius, Some(1.5));
+        assert!(parse(&["--filter", "median"]).is_err());
+        assert!(parse(&["--filter-radius", "0.2"]).is_err());
+        assert!(parse(&["--filter-radius", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn test_adaptive() {
+        assert_eq!(
+            parse(&["--adaptive"]).unwrap().adaptive,
+            Some(AdaptiveSampling::default())
+        );
+        let adaptive = parse(&["--max-spp", "64", "--error", "0.1"])
+            .unwrap()
+            .adaptive
+            .unwrap();
+        assert_eq!(adaptive.max_samples, 64);
+        assert_eq!(adaptive.error_threshold, 0.1);
+        assert!(parse(&["--min-spp", "many"]).is_err());
+        assert!(parse(&["--min-spp", "32", "--max-spp", "16"]).is_err());
+        for error in ["0", "-0.1", "nan", "inf"] {
+            assert!(parse(&["--error", error]).is_err(), "accepted {}", error);
+        }
+    }
+
+    #[test]
+    fn test_progressive() {
+        let options = parse(&[
+            "--progressive",
+            "8",
+            "--snapshot",
+            "out.ppm",
+            "--snapshot-interval",
+            "1.5",
+        ])
+        .unwrap();
+        assert_eq!(options.progressive, Some(8));
+        assert_eq!(options.snapshot, Some(PathBuf::from("out.ppm")));
+        assert_eq!(options.snapshot_interval, Some(Duration::from_millis(1500)));
+        assert!(parse(&["--progressive", "0"]).is_err());
+        assert!(parse(&["--snapshot-interval", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_camera() {
+        let options = parse(&["--view-width", "6", "--camera", "orthographic"]).unwrap();
+        assert_eq!(options.camera, CameraKind::Orthographic);
+        assert_eq!(options.view_size, Some(ViewSize::Width(6.0)));
+        assert_eq!(parse(&[]).unwrap().camera, CameraKind::Perspective);
+        assert!(parse(&["--view-height", "2"]).is_err());
+        assert!(parse(&["--camera", "orthographic", "--view-height", "-2"]).is_err());
+        assert!(parse(&["--camera", "pinhole"]).is_err());
+
+        let options = parse(&[
+            "--camera",
+            "fisheye-equisolid",
+            "--fov",
+            "220",
+            "--resolution",
+            "640x480",
+        ])
+        .unwrap();
+        assert_eq!(options.camera, CameraKind::FisheyeEquisolid);
+        assert_eq!(options.fov, Some(220.0));
+        assert_eq!(options.resolution, Some((640, 480)));
+        assert!(parse(&["--fov", "220"]).is_err());
+        assert!(parse(&["--camera", "equirectangular", "--fov", "90"]).is_err());
+        assert!(parse(&["--resolution", "640x0"]).is_err());
+        assert!(parse(&["--resolution", "640"]).is_err());
+
+        let options = parse(&["--camera", "realistic", "--lens", "singlet"]).unwrap();
+        assert_eq!(options.camera, CameraKind::Realistic);
+        assert_eq!(options.lens, Some("singlet".to_string()));
+        assert!(parse(&["--lens", "singlet"]).is_err());
+        assert!(parse(&["--camera", "realistic", "--film-diagonal", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_aperture() {
+        let options = parse(&[
+            "--aperture-blades",
+            "7",
+            "--aperture-rotation",
+            "15",
+            "--anamorphic",
+            "1.33",
+        ])
+        .unwrap();
+        assert_eq!(options.aperture_blades, Some(7));
+        assert_eq!(options.aperture_rotation, 15.0);
+        assert_eq!(options.anamorphic, 1.33);
+        assert!(parse(&["--aperture-blades", "2"]).is_err());
+        assert!(parse(&["--anamorphic", "0.5"]).is_err());
+        assert!(parse(&["--aperture-blades", "5", "--aperture-image", "heart.pgm"]).is_err());
+    }
+
+    #[test]
+    fn test_stereo() {
+        let options = parse(&[
+            "--stereo",
+            "top-bottom",
+            "--interocular",
+            "0.065",
+            "--convergence",
+            "toe-in",
+            "--convergence-distance",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(options.stereo, Some(Packing::TopBottom));
+        assert_eq!(options.interocular, Some(0.065));
+        assert_eq!(options.convergence, Some(Convergence::ToeIn));
+        assert_eq!(options.convergence_distance, Some(3.0));
+        assert_eq!(parse(&[]).unwrap().stereo, None);
+        assert!(parse(&["--interocular", "0.065"]).is_err());
+        assert!(parse(&["--stereo", "anaglyph"]).is_err());
+        assert!(parse(&["--stereo", "side-by-side", "--convergence-distance", "0"]).is_err());
+        // Only perspective cameras can shift their image window
+        let off_axis = |camera| {
+            parse(&[
+                "--stereo",
+                "side-by-side",
+                "--convergence",
+                "off-axis",
+                "--camera",
+                camera,
+            ])
+        };
+        assert!(off_axis("perspective").is_ok());
+        for camera in ["orthographic", "fisheye", "fisheye-equisolid", "realistic"] {
+            assert!(off_axis(camera).is_err(), "accepted {}", camera);
+        }
+        assert!(parse(&["--stereo", "side-by-side", "--camera", "fisheye"]).is_ok());
+    }
+
+    #[test]
+    fn test_region() {
+        let options = parse(&["--region", "100,50,64,32", "--crop"]).unwrap();
+        assert_eq!(
+            options.region,
+            Some(Region::Pixels {
+                x: 100,
+                y: 50,
+                width: 64,
+                height: 32
+            })
+        );
+        assert!(options.crop);
+        let window = parse(&["--region-window", "0.25,0,0.5,0.75"]).unwrap();
+        assert_eq!(
+            window.region,
+            Some(Region::Window {
+                left: 0.25,
+                top: 0.0,
+                right: 0.5,
+                bottom: 0.75
+            })
+        );
+        assert!(!window.crop);
+        assert!(parse(&["--region", "0,0,0,10"]).is_err());
+        assert!(parse(&["--region-window", "0.5,0,0.25,1"]).is_err());
+        assert!(parse(&["--region-window", "0,0,1,1.5"]).is_err());
+        assert!(parse(&["--crop"]).is_err());
+    }
+
+    #[test]
+    fn test_focus() {
+        let focus = |args: &[&str]| parse(args).map(|options| options.focus);
+        assert_eq!(focus(&[]), Ok(None));
+        assert_eq!(
+            focus(&["--focus-distance", "4"]),
+            Ok(Some(Focus::Distance(4.0)))
+        );
+        assert_eq!(focus(&["--focus-lookat"]), Ok(Some(Focus::Lookat)));
+        assert_eq!(
+            focus(&["--focus-point", "1,-2,0.5"]),
+            Ok(Some(Focus::Point(1.0, -2.0, 0.5)))
+        );
+        assert_eq!(
+            focus(&["--focus-pixel", "600,400"]),
+            Ok(Some(Focus::Pixel(600, 400)))
+        );
+        assert!(focus(&["--focus-point", "1,2"]).is_err());
+        assert!(focus(&["--focus-pixel", "-1,2"]).is_err());
+        assert!(focus(&["--focus-distance", "0"]).is_err());
+        assert!(focus(&["--camera", "fisheye", "--focus-lookat"]).is_err());
+    }
+
+    #[test]
+    fn test_animation() {
+        let options = parse(&[
+            "--keyframes",
+            "path.txt",
+            "--interpolation",
+            "linear",
+            "--frames",
+            "10..20",
+            "--output",
+            "out/f###.ppm",
+            "--shutter",
+            "0.5",
+        ])
+        .unwrap();
+        assert_eq!(options.keyframes, Some(PathBuf::from("path.txt")));
+        assert_eq!(options.interpolation, Interpolation::Linear);
+        assert_eq!(options.frames, Some((10, 20)));
+        assert_eq!(options.output, "out/f###.ppm");
+        assert_eq!(options.shutter, 0.5);
+        let frame = parse(&["--keyframes", "path.txt", "--frames", "7"]).unwrap();
+        assert_eq!(frame.frames, Some((7, 7)));
+        assert!(parse(&["--frames", "7"]).is_err());
+        assert!(parse(&["--keyframes", "path.txt", "--frames", "20..10"]).is_err());
+        assert!(parse(&["--keyframes", "path.txt", "--output", "out.ppm"]).is_err());
+        assert!(parse(&["--keyframes", "path.txt", "--shutter", "2"]).is_err());
+        assert!(parse(&["--keyframes", "path.txt", "--checkpoint", "render.ckpt"]).is_err());
+    }
+
+    #[test]
+    fn test_fireflies() {
+        let options = parse(&[
+            "--clamp-direct",
+            "20",
+            "--clamp-indirect",
+            "2.5",
+            "--russian-roulette",
+        ])
+        .unwrap();
+        assert_eq!(options.clamp_direct, Some(20.0));
+        assert_eq!(options.clamp_indirect, Some(2.5));
+        assert!(options.russian_roulette);
+        assert_eq!(parse(&[]).unwrap().clamp_indirect, None);
+        assert!(parse(&["--clamp-indirect", "0"]).is_err());
+        assert!(parse(&["--clamp-direct", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn test_tonemapping() {
+        let options = parse(&[
+            "--denoise",
+            "--exposure",
+            "-1.5",
+            "--tonemap",
+            "agx",
+            "--transfer",
+            "gamma2.2",
+            "--snapshot-transfer",
+            "linear",
+        ])
+        .unwrap();
+        assert!(options.denoise);
+        assert_eq!(options.exposure, -1.5);
+        assert_eq!(options.tonemap, TonemapKind::Agx);
+        assert_eq!(options.transfer, Transfer::Gamma(2.2));
+        assert_eq!(options.snapshot_transfer, Some(Transfer::Linear));
+        assert!(parse(&["--tonemap", "filmic"]).is_err());
+        assert!(parse(&["--transfer", "gamma"]).is_err());
+        assert!(parse(&["--transfer", "gamma-1"]).is_err());
+        assert!(parse(&["--white-balance", "20"]).is_err());
+        assert!(parse(&["--white-point", "0"]).is_err());
+        assert!(parse(&["--exposure", "nan"]).is_err());
+        assert!(parse(&["--exposure", "-inf"]).is_err());
+    }
+
+    #[test]
+    fn test_aovs() {
+        let options = parse(&[
+            "--aov",
+            "depth,normal",
+            "--aov",
+            "depth,emission",
+            "--aov-output",
+            "render.exr",
+        ])
+        .unwrap();
+        assert_eq!(options.aovs, vec![Aov::Depth, Aov::Normal, Aov::Emission]);
+        assert_eq!(options.aov_output, Some(PathBuf::from("render.exr")));
+        let all = parse(&["--aov-output", "render.ppm"]).unwrap();
+        assert_eq!(all.aovs, Aov::ALL.to_vec());
+        assert!(parse(&["--aov", "depth"]).is_err());
+        assert!(parse(&["--aov", "beauty", "--aov-output", "render.exr"]).is_err());
+    }
+
+    #[test]
+    fn test_resume() {
+        let options = parse(&["--checkpoint", "render.ckpt", "--resume"]).unwrap();
+        assert_eq!(options.checkpoint, Some(PathBuf::from("render.ckpt")));
+        assert!(options.resume);
+        assert!(parse(&["--resume"]).is_err());
+    }
+}
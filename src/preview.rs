@@ -0,0 +1,323 @@
+// Preview of the film drawn into the terminal on stderr, refreshed in place while the
+// render progresses. Terminals show it with 24-bit colored half blocks (two pixels per
+// character cell) or as a Sixel image.
+use crate::tonemap::{PostProcess, Transfer};
+use crate::vec3::*;
+use indicatif::ProgressBar;
+use std::env;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Time between refreshes, drawing a large preview takes a while
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+// Size of a character cell in pixels that Sixel previews assume
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 20;
+// Levels per channel of the Sixel palette
+const SIXEL_LEVELS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreviewMode {
+    // Sixel if the terminal is known to support it, half blocks otherwise
+    Auto,
+    HalfBlocks,
+    Sixel,
+}
+
+// Terminals that announce themselves in TERM or TERM_PROGRAM and draw Sixel images
+fn supports_sixel() -> bool {
+    let term = env::var("TERM").unwrap_or_default();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    term.contains("sixel")
+        || ["mlterm", "foot", "yaft-256color"].contains(&term.as_str())
+        || ["WezTerm", "iTerm.app", "mintty"].contains(&program.as_str())
+}
+
+// Columns and rows of the terminal, from the environment or stty
+fn terminal_size() -> (usize, usize) {
+    let var = |name| env::var(name).ok().and_then(|v: String| v.parse().ok());
+    if let (Some(columns), Some(rows)) = (var("COLUMNS"), var("LINES")) {
+        return (columns, rows);
+    }
+    let stty = std::fs::File::open("/dev/tty").and_then(|tty| {
+        std::process::Command::new("stty")
+            .arg("size")
+            .stdin(tty)
+            .output()
+    });
+    let size = stty.ok().and_then(|output| {
+        let text = String::from_utf8(output.stdout).ok()?;
+        let (rows, columns) = text.trim().split_once(' ')?;
+        Some((columns.parse().ok()?, rows.parse().ok()?))
+    });
+    size.unwrap_or((80, 24))
+}
+
+// Mean colors of the width x height blocks that cover an image of colors, row by row
+fn downsampled(colors: &[Color], size: (usize, usize), width: usize, height: usize) -> Vec<Color> {
+    let (source_width, source_height) = size;
+    let mut result = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let (y0, y1) = (y * source_height / height, (y + 1) * source_height / height);
+        for x in 0..width {
+            let (x0, x1) = (x * source_width / width, (x + 1) * source_width / width);
+            let mut sum = Vec3::zero();
+            for row in y0..y1.max(y0 + 1) {
+                for column in x0..x1.max(x0 + 1) {
+                    sum = sum + &colors[row * source_width + column];
+                }
+            }
+            let count = (y1.max(y0 + 1) - y0) * (x1.max(x0 + 1) - x0);
+            result.push(sum / count as f64);
+        }
+    }
+    result
+}
+
+fn rgb(c: &Color) -> (u8, u8, u8) {
+    let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (channel(c.e0), channel(c.e1), channel(c.e2))
+}
+
+// Rows of characters whose upper half shows one pixel row (foreground color) and lower
+// half the next (background color)
+fn half_blocks(pixels: &[Color], width: usize) -> Vec<String> {
+    let rows: Vec<&[Color]> = pixels.chunks(width).collect();
+    rows.chunks(2)
+        .map(|pair| {
+            let mut line = String::new();
+            for x in 0..width {
+                let (r, g, b) = rgb(&pair[0][x]);
+                let (br, bg, bb) = rgb(&pair.get(1).unwrap_or(&pair[0])[x]);
+                write!(line, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+                write!(line, "\x1b[48;2;{};{};{}m\u{2580}", br, bg, bb).unwrap();
+            }
+            line.push_str("\x1b[0m");
+            line
+        })
+        .collect()
+}
+
+// Sixel image with a palette of evenly spaced levels per channel. Every band of six pixel
+// rows is drawn once for each color in it.
+fn sixel(pixels: &[Color], width: usize) -> String {
+    let height = pixels.len() / width;
+    let level = |v: f64| (v.clamp(0.0, 1.0) * (SIXEL_LEVELS - 1) as f64).round() as usize;
+    let indices: Vec<usize> = pixels
+        .iter()
+        .map(|c| (level(c.e0) * SIXEL_LEVELS + level(c.e1)) * SIXEL_LEVELS + level(c.e2))
+        .collect();
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    let percent = |level: usize| 100 * level / (SIXEL_LEVELS - 1);
+    for i in 0..SIXEL_LEVELS.pow(3) {
+        let (r, g, b) = (
+            i / SIXEL_LEVELS.pow(2),
+            i / SIXEL_LEVELS % SIXEL_LEVELS,
+            i % SIXEL_LEVELS,
+        );
+        write!(out, "#{};2;{};{};{}", i, percent(r), percent(g), percent(b)).unwrap();
+    }
+    for band in 0..height.div_ceil(6) {
+        // Bits of the six rows that have each color, per column
+        let mut bits = vec![Vec::new(); SIXEL_LEVELS.pow(3)];
+        for dy in 0..6.min(height - 6 * band) {
+            let y = 6 * band + dy;
+            for x in 0..width {
+                let color = &mut bits[indices[y * width + x]];
+                color.resize(width, 0u8);
+                color[x] |= 1 << dy;
+            }
+        }
+        for (i, columns) in bits.iter().enumerate().filter(|(_, c)| !c.is_empty()) {
+            write!(out, "#{}", i).unwrap();
+            // Runs of the same sixel are written once with a repeat count
+            let mut x = 0;
+            while x < width {
+                let run = columns[x..]
+                    .iter()
+                    .take_while(|&&c| c == columns[x])
+                    .count();
+                let sixel = (b'?' + columns[x]) as char;
+                match run {
+                    1..=3 => (0..run).for_each(|_| out.push(sixel)),
+                    _ => write!(out, "!{}{}", run, sixel).unwrap(),
+                }
+                x += run;
+            }
+            // Back to the start of the band for the next color
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+pub struct Preview {
+    sixel: bool,
+    // Character cells the preview may cover, including the status line
+    columns: usize,
+    rows: usize,
+    post: PostProcess,
+    message: String,
+    // When space was made for the preview and the cursor position saved at its top,
+    // None until the first refresh
+    started: Option<Instant>,
+    last_refresh: Option<Instant>,
+}
+
+impl Preview {
+    // Colors are processed like the final image, but always sRGB encoded
+    pub fn new(mode: PreviewMode, post: &PostProcess) -> Preview {
+        let (columns, rows) = terminal_size();
+        Preview {
+            sixel: match mode {
+                PreviewMode::Auto => supports_sixel(),
+                PreviewMode::HalfBlocks => false,
+                PreviewMode::Sixel => true,
+            },
+            columns: columns.max(1),
+            rows: rows.max(2),
+            post: post.with_transfer(Transfer::Srgb),
+            message: String::new(),
+            started: None,
+            last_refresh: None,
+        }
+    }
+
+    // Shown in the status line below the image, like the message of the progress bar
+    pub fn set_message(&mut self, message: &str) {
+        self.message = message.to_string();
+    }
+
+    pub fn due(&self) -> bool {
+        self.last_refresh
+            .is_none_or(|last| last.elapsed() >= REFRESH_INTERVAL)
+    }
+
+    // Draws the colors of a film over the previous preview, with the progress of the bar
+    // below
+    pub fn draw(
+        &mut self,
+        colors: &[Color],
+        width: u32,
+        height: u32,
+        bar: &ProgressBar,
+    ) -> io::Result<()> {
+        let (film_width, film_height) = (width as usize, height as usize);
+        // Pixels per character cell
+        let (cell_width, cell_height) = if self.sixel {
+            (CELL_WIDTH, CELL_HEIGHT)
+        } else {
+            (1, 2)
+        };
+        let max_width = self.columns * cell_width;
+        let max_height = (self.rows - 1) * cell_height;
+        let scale = (max_width as f64 / film_width as f64)
+            .min(max_height as f64 / film_height as f64)
+            .min(1.0);
+        let width = ((film_width as f64 * scale) as usize).max(1);
+        let height = ((film_height as f64 * scale) as usize).max(1);
+        let pixels: Vec<Color> = downsampled(colors, (film_width, film_height), width, height)
+            .iter()
+            .map(|c| self.post.apply(c))
+            .collect();
+
+        let mut out = String::new();
+        if self.started.is_none() {
+            // Scrolls the terminal up front, so the saved position stays the top
+            let lines = height.div_ceil(cell_height) + 1;
+            write!(out, "{}\x1b[{}A\x1b7", "\n".repeat(lines), lines).unwrap();
+            self.started = Some(Instant::now());
+        }
+        out.push_str("\x1b8");
+        if self.sixel {
+            out.push_str(&sixel(&pixels, width));
+            out.push('\r');
+        } else {
+            for line in half_blocks(&pixels, width) {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        let progress = bar.position() as f64 / bar.length().max(1) as f64;
+        let elapsed = self
+            .started
+            .map_or(0.0, |start| start.elapsed().as_secs_f64());
+        let eta = if progress > 0.0 {
+            elapsed * (1.0 - progress) / progress
+        } else {
+            0.0
+        };
+        write!(
+            out,
+            "\x1b[K{:.0}% {} ETA {:.0}s",
+            100.0 * progress,
+            self.message,
+            eta
+        )
+        .unwrap();
+
+        self.last_refresh = Some(Instant::now());
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        stderr.write_all(out.as_bytes())?;
+        stderr.flush()
+    }
+
+    // Leaves the cursor below the preview for the messages that follow
+    pub fn finish(&mut self) {
+        if self.started.is_some() {
+            eprintln!();
+        }
+        self.started = None;
+        self.last_refresh = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downsampled() {
+        let gray = |v| Vec3::new(v, v, v);
+        // 4x2 image, each half has the mean of its two columns
+        let colors: Vec<Color> = [0.0, 1.0, 0.5, 0.5, 0.0, 1.0, 0.25, 0.75]
+            .iter()
+            .map(|&v| gray(v))
+            .collect();
+        let small = downsampled(&colors, (4, 2), 2, 1);
+        assert_eq!(small, vec![gray(0.5), gray(0.5)]);
+        // Enlarging repeats pixels
+        assert_eq!(downsampled(&colors[..1], (1, 1), 2, 2), vec![gray(0.0); 4]);
+    }
+
+    #[test]
+    fn test_encodings() {
+        let red = Vec3::new(1.0, 0.0, 0.0);
+        let blue = Vec3::new(0.0, 0.0, 1.0);
+        // Three rows: red, blue, red
+        let pixels = vec![
+            red.clone(),
+            red.clone(),
+            blue.clone(),
+            blue,
+            red.clone(),
+            red,
+        ];
+        let lines = half_blocks(&pixels, 2);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}"));
+        assert!(lines[1].starts_with("\x1b[38;2;255;0;0m\x1b[48;2;255;0;0m\u{2580}"));
+
+        let image = sixel(&pixels, 2);
+        assert!(image.starts_with("\x1bPq\"1;1;2;3#0;2;0;0;0"));
+        assert!(image.ends_with("\x1b\\"));
+        // Pure red is color 180 and covers rows 0 and 2, blue is color 5 in row 1
+        assert!(image.contains("#180DD$"));
+        assert!(image.contains("#5AA$"));
+    }
+}
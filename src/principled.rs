@@ -0,0 +1,464 @@
+use crate::hittable::*;
+use crate::materials::*;
+use crate::microfacet::*;
+use crate::ray::*;
+use crate::sampler::SampleStream;
+use crate::texture::*;
+use crate::vec3::*;
+use std::f64::consts::PI;
+
+// Principled material in the spirit of the Disney BSDF (Burley 2012/2015). All lobes
+// are importance sampled; one lobe is chosen per scatter event.
+#[derive(Debug)]
+pub struct Principled {
+    base_color: Box<dyn Texture>,
+    metallic: Box<dyn Texture>,
+    roughness: Box<dyn Texture>,
+    // Scales the reflectance of non-metals, 0.5 corresponds to an IOR of 1.5
+    specular: Box<dyn Texture>,
+    clearcoat: Box<dyn Texture>,
+    clearcoat_roughness: Box<dyn Texture>,
+    // Color of the retro-reflective sheen at grazing angles, black disables it
+    sheen: Box<dyn Texture>,
+    transmission: Box<dyn Texture>,
+    ior: f64,
+}
+
+impl Principled {
+    pub fn new<T: Texture + 'static>(base_color: T) -> Principled {
+        Principled {
+            base_color: Box::new(base_color),
+            metallic: Box::new(0.0),
+            roughness: Box::new(0.5),
+            specular: Box::new(0.5),
+            clearcoat: Box::new(0.0),
+            clearcoat_roughness: Box::new(0.03),
+            sheen: Box::new(0.0),
+            transmission: Box::new(0.0),
+            ior: 1.5,
+        }
+    }
+
+    pub fn metallic<T: Texture + 'static>(mut self, metallic: T) -> Principled {
+        self.metallic = Box::new(metallic);
+        self
+    }
+
+    pub fn roughness<T: Texture + 'static>(mut self, roughness: T) -> Principled {
+        self.roughness = Box::new(roughness);
+        self
+    }
+
+    pub fn specular<T: Texture + 'static>(mut self, specular: T) -> Principled {
+        self.specular = Box::new(specular);
+        self
+    }
+
+    pub fn clearcoat<A: Texture + 'static, B: Texture + 'static>(
+        mut self,
+        clearcoat: A,
+        roughness: B,
+    ) -> Principled {
+        self.clearcoat = Box::new(clearcoat);
+        self.clearcoat_roughness = Box::new(roughness);
+        self
+    }
+
+    pub fn sheen<T: Texture + 'static>(mut self, sheen: T) -> Principled {
+        self.sheen = Box::new(sheen);
+        self
+    }
+
+    pub fn transmission<T: Texture + 'static>(mut self, transmission: T, ior: f64) -> Principled {
+        self.transmission = Box::new(transmission);
+        self.ior = ior;
+        self
+    }
+
+    pub fn from_gltf(material: GltfMaterial) -> Principled {
+        let [r, g, b, _] = material.base_color_factor;
+        let base_factor = Vec3::new(r, g, b);
+        // The base color texture is the only sRGB encoded one, the others hold data
+        let base_color: Box<dyn Texture> = match material.base_color_texture {
+            Some(texture) => Box::new(Scaled::new(texture.decode_srgb(), base_factor)),
+            None => Box::new(base_factor),
+        };
+
+        // glTF stores roughness in the green and metalness in the blue channel
+        let (metallic, roughness): (Box<dyn Texture>, Box<dyn Texture>) =
+            match material.metallic_roughness_texture {
+                Some(texture) => (
+                    Box::new(Scaled::new(
+                        Channel::new(texture.clone(), 2),
+                        Vec3::new(1.0, 1.0, 1.0) * material.metallic_factor,
+                    )),
+                    Box::new(Scaled::new(
+                        Channel::new(texture, 1),
+                        Vec3::new(1.0, 1.0, 1.0) * material.roughness_factor,
+                    )),
+                ),
+                None => (
+                    Box::new(material.metallic_factor),
+                    Box::new(material.roughness_factor),
+                ),
+            };
+
+        let [sr, sg, sb] = material.sheen_color_factor;
+        Principled {
+            base_color,
+            metallic,
+            roughness,
+            specular: Box::new(0.5 * material.specular_factor),
+            clearcoat: Box::new(material.clearcoat_factor),
+            clearcoat_roughness: Box::new(material.clearcoat_roughness_factor),
+            sheen: Box::new(Vec3::new(sr, sg, sb)),
+            transmission: Box::new(material.transmission_factor),
+            ior: material.ior,
+        }
+    }
+
+    // Rough dielectric interface, reflects or refracts according to Fresnel
+    fn sample_transmission(
+        &self,
+        wo: &Vec3,
+        alpha: f64,
+        eta: f64,
+        tint: &Color,
+        sampler: &mut SampleStream,
+    ) -> Option<(Color, Vec3)> {
+        let h = sample_ggx_vndf(wo, alpha, sampler.get_2d());
+        let reflectance = fresnel_dielectric(wo.dot(&h), eta);
+        if sampler.get_1d() < reflectance {
+            let wi = reflect(wo, &h);
+            if wi.e2 <= 0.0 {
+                return None;
+            }
+            let g = smith_g2_over_g1(wo, &wi, alpha);
+            Some((Vec3::new(g, g, g), wi))
+        } else {
+            let wi = refract(wo, &h, eta)?;
+            if wi.e2 >= 0.0 {
+                return None;
+            }
+            Some((smith_g2_over_g1(wo, &wi, alpha) * tint, wi))
+        }
+    }
+}
+
+// GGX reflection off a microfacet sampled from the visible normals.
+// Returns the sampled direction, the microfacet normal and the shadowing weight.
+fn sample_ggx_reflection(
+    wo: &Vec3,
+    alpha: f64,
+    sampler: &mut SampleStream,
+) -> Option<(Vec3, Vec3, f64)> {
+    let h = sample_ggx_vndf(wo, alpha, sampler.get_2d());
+    let wi = reflect(wo, &h);
+    if wi.e2 <= 0.0 {
+        return None;
+    }
+    let g = smith_g2_over_g1(wo, &wi, alpha);
+    Some((wi, h, g))
+}
+
+impl Material for Principled {
+    fn scatter(&self, r: &Ray, hit: &Hit, sampler: &mut SampleStream) -> Option<ScatterResult> {
+        let (uv, p) = (hit.uv, &hit.location);
+
+        let frame = Frame::from_normal(&hit.normal);
+        let wo = frame.to_local(&-r.unit_direction());
+        let base_color = self.base_color.value(uv, p);
+        let alpha = roughness_to_alpha(self.roughness.scalar(uv, p).clamp(0.0, 1.0));
+
+        let (weight, wi) = if hit.surface == Surface::Inside {
+            // Only transmitted rays travel inside, they have to leave through the interface.
+            // They were tinted on the way in already.
+            let white = Vec3::new(1.0, 1.0, 1.0);
+            self.sample_transmission(&wo, alpha, 1.0 / self.ior, &white, sampler)?
+        } else {
+            let clearcoat = self.clearcoat.scalar(uv, p).clamp(0.0, 1.0);
+            let metallic = self.metallic.scalar(uv, p).clamp(0.0, 1.0);
+            let transmission = self.transmission.scalar(uv, p).clamp(0.0, 1.0);
+
+            if sampler.get_1d() < clearcoat * fresnel_schlick(0.04, wo.e2) {
+                let cc_alpha =
+                    roughness_to_alpha(self.clearcoat_roughness.scalar(uv, p).clamp(0.0, 1.0));
+                let (wi, _, g) = sample_ggx_reflection(&wo, cc_alpha, sampler)?;
+                (Vec3::new(g, g, g), wi)
+            } else if sampler.get_1d() < metallic {
+                let (wi, h, g) = sample_ggx_reflection(&wo, alpha, sampler)?;
+                (g * fresnel_schlick_color(&base_color, wo.dot(&h)), wi)
+            } else if sampler.get_1d() < transmission {
+                self.sample_transmission(&wo, alpha, self.ior, &base_color, sampler)?
+            } else {
+                let f0 = 0.08 * self.specular.scalar(uv, p).clamp(0.0, 1.0);
+                if sampler.get_1d() < fresnel_schlick(f0, wo.e2) {
+                    // Untinted specular layer of a non-metal
+                    let (wi, _, g) = sample_ggx_reflection(&wo, alpha, sampler)?;
+                    (Vec3::new(g, g, g), wi)
+                } else {
+                    let wi = sample_cosine_hemisphere(sampler.get_2d());
+                    let h = (&wo + &wi).unit_vector();
+                    // Cosine sampling cancels the Lambertian 1/pi, the sheen lobe has none
+                    let sheen = PI * schlick_weight(wi.dot(&h)) * self.sheen.value(uv, p);
+                    (base_color + sheen, wi)
+                }
+            }
+        };
+
+        let scattered_ray = Ray::new(hit.location.clone(), frame.to_world(&wi));
+        Some(ScatterResult::new(weight, scattered_ray))
+    }
+
+    fn albedo(&self, hit: &Hit) -> Color {
+        self.base_color.value(hit.uv, &hit.location)
+    }
+}
+
+// Subset of a glTF 2.0 material: the metallic-roughness core model plus the
+// KHR_materials_{specular,clearcoat,sheen,transmission,ior} extensions.
+// Field defaults follow the glTF specification.
+#[derive(Debug, Clone)]
+pub struct GltfMaterial {
+    // Linear RGB and alpha, which only alpha_mode uses
+    pub base_color_factor: [f64; 4],
+    // As stored in the file, sRGB encoded
+    pub base_color_texture: Option<ImageTexture>,
+    pub metallic_factor: f64,
+    pub roughness_factor: f64,
+    pub metallic_roughness_texture: Option<ImageTexture>,
+    pub specular_factor: f64,
+    pub clearcoat_factor: f64,
+    pub clearcoat_roughness_factor: f64,
+    pub sheen_color_factor: [f64; 3],
+    pub transmission_factor: f64,
+    pub ior: f64,
+    // None is glTF's OPAQUE mode, which ignores alpha. Images have no alpha channel, the
+    // alpha of the base color factor is used alone.
+    pub alpha_mode: Option<AlphaMode>,
+}
+
+impl Default for GltfMaterial {
+    fn default() -> GltfMaterial {
+        GltfMaterial {
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            specular_factor: 1.0,
+            clearcoat_factor: 0.0,
+            clearcoat_roughness_factor: 0.0,
+            sheen_color_factor: [0.0, 0.0, 0.0],
+            transmission_factor: 0.0,
+            ior: 1.5,
+            alpha_mode: None,
+        }
+    }
+}
+
+impl GltfMaterial {
+    // Named materials for scenes, as a glTF exporter would describe them
+    pub fn preset(name: &str) -> Option<GltfMaterial> {
+        let metal = |[r, g, b]: [f64; 3], roughness_factor| GltfMaterial {
+            base_color_factor: [r, g, b, 1.0],
+            roughness_factor,
+            ..GltfMaterial::default()
+        };
+        let dielectric = |[r, g, b]: [f64; 3], roughness_factor| GltfMaterial {
+            base_color_factor: [r, g, b, 1.0],
+            metallic_factor: 0.0,
+            roughness_factor,
+            ..GltfMaterial::default()
+        };
+        Some(match name {
+            "gold" => metal([1.0, 0.766, 0.336], 0.25),
+            "silver" => metal([0.972, 0.960, 0.915], 0.25),
+            "copper" => metal([0.955, 0.638, 0.538], 0.3),
+            "aluminium" => metal([0.913, 0.922, 0.924], 0.4),
+            "plastic" => dielectric([0.05, 0.3, 0.6], 0.2),
+            "rubber" => dielectric([0.05, 0.05, 0.05], 0.9),
+            "car-paint" => GltfMaterial {
+                metallic_factor: 0.3,
+                clearcoat_factor: 1.0,
+                clearcoat_roughness_factor: 0.02,
+                ..dielectric([0.6, 0.02, 0.02], 0.6)
+            },
+            "velvet" => GltfMaterial {
+                specular_factor: 0.0,
+                sheen_color_factor: [0.5, 0.4, 0.8],
+                ..dielectric([0.1, 0.05, 0.3], 1.0)
+            },
+            "frosted-glass" => GltfMaterial {
+                transmission_factor: 1.0,
+                ..dielectric([0.9, 1.0, 0.95], 0.15)
+            },
+            _ => return None,
+        })
+    }
+
+    // Principled material, cut out by the base color alpha unless the mode is opaque
+    pub fn into_material(self) -> Box<dyn Material> {
+        let alpha = self.base_color_factor[3];
+        match self.alpha_mode {
+            Some(mode) => Box::new(AlphaMasked::new(
+                Box::new(Principled::from_gltf(self)),
+                alpha,
+                mode,
+            )),
+            None => Box::new(Principled::from_gltf(self)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+    use crate::sampler::IndependentSampler;
+
+    const SAMPLES: u32 = 20000;
+
+    // Mean weight of the scattered rays for light arriving from 45 degrees, absorbed
+    // rays count as black. A white furnace gives 1 if the material loses no energy.
+    fn mean_weight(material: &Principled, surface: Surface) -> Color {
+        let hit = Hit::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            material,
+            surface,
+            (0.5, 0.5),
+            Tangents {
+                dpdu: Vec3::new(1.0, 0.0, 0.0),
+                dpdv: Vec3::new(0.0, 1.0, 0.0),
+            },
+        );
+        let r = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0));
+        let sampler = IndependentSampler::new(3);
+        let mut stream = SampleStream::new(&sampler);
+        let mut sum = Vec3::zero();
+        for i in 0..SAMPLES {
+            stream.start_pixel_sample((0, 0), i);
+            stream.start_bounce(0);
+            if let Some(result) = material.scatter(&r, &hit, &mut stream) {
+                let w = &result.attenuation;
+                assert!(w.e0.max(w.e1).max(w.e2) <= 1.0 + 1e-9, "gained energy");
+                sum = sum + result.attenuation;
+            }
+        }
+        sum / SAMPLES as f64
+    }
+
+    fn assert_furnace(mean: &Color, min: f64) {
+        for v in [mean.e0, mean.e1, mean.e2] {
+            assert!(v > min && v <= 1.0 + 1e-9, "white furnace gives {:?}", mean);
+        }
+    }
+
+    #[test]
+    fn test_gltf_channels() {
+        // Roughness in green, metalness in blue, both scaled by their factors
+        let mut image = Image::new(1, 1);
+        image.set(0, 0, Vec3::new(0.0, 0.5, 0.8));
+        let material = Principled::from_gltf(GltfMaterial {
+            base_color_factor: [0.5, 0.25, 1.0, 1.0],
+            metallic_factor: 0.5,
+            metallic_roughness_texture: Some(ImageTexture::new(image)),
+            ..GltfMaterial::default()
+        });
+        let p = Vec3::zero();
+        assert_eq!(
+            material.base_color.value((0.5, 0.5), &p),
+            Vec3::new(0.5, 0.25, 1.0)
+        );
+        assert_eq!(material.metallic.scalar((0.5, 0.5), &p), 0.4);
+        assert_eq!(material.roughness.scalar((0.5, 0.5), &p), 0.5);
+    }
+
+    #[test]
+    fn test_gltf_base_color_is_decoded() {
+        let mut image = Image::new(1, 1);
+        image.set(0, 0, Vec3::new(0.5, 0.5, 0.5));
+        let material = Principled::from_gltf(GltfMaterial {
+            base_color_texture: Some(ImageTexture::new(image.clone())),
+            metallic_roughness_texture: Some(ImageTexture::new(image)),
+            ..GltfMaterial::default()
+        });
+        let p = Vec3::zero();
+        // Mid-grey in sRGB is about a fifth of the light
+        let base = material.base_color.value((0.5, 0.5), &p);
+        assert!((base.e0 - 0.214).abs() < 1e-3, "{:?}", base);
+        assert_eq!(base.e0, base.e2);
+        // Data textures stay linear
+        assert_eq!(material.roughness.scalar((0.5, 0.5), &p), 0.5);
+    }
+
+    #[test]
+    fn test_gltf_presets() {
+        let gold = GltfMaterial::preset("gold").unwrap();
+        assert_eq!(gold.metallic_factor, 1.0);
+        assert_eq!(GltfMaterial::preset("velvet").unwrap().metallic_factor, 0.0);
+        assert!(GltfMaterial::preset("unobtainium").is_none());
+    }
+
+    #[test]
+    fn test_gltf_alpha() {
+        let p = Vec3::zero();
+        let material = |alpha_mode| {
+            GltfMaterial {
+                base_color_factor: [1.0, 1.0, 1.0, 0.4],
+                alpha_mode,
+                ..GltfMaterial::default()
+            }
+            .into_material()
+        };
+        // Opaque materials ignore alpha
+        assert_eq!(material(None).opacity((0.5, 0.5), &p), 1.0);
+        assert_eq!(
+            material(Some(AlphaMode::Blend)).opacity((0.5, 0.5), &p),
+            0.4
+        );
+        let cutoff = |c| material(Some(AlphaMode::Mask(c))).opacity((0.5, 0.5), &p);
+        assert_eq!(cutoff(0.5), 0.0);
+        assert_eq!(cutoff(0.3), 1.0);
+    }
+
+    #[test]
+    fn test_smooth_metal_keeps_energy() {
+        let mirror = Principled::new(Vec3::new(1.0, 1.0, 1.0))
+            .metallic(1.0)
+            .roughness(0.0);
+        assert_furnace(&mean_weight(&mirror, Surface::Outside), 0.99);
+    }
+
+    #[test]
+    fn test_transmission_keeps_energy() {
+        let glass = Principled::new(Vec3::new(1.0, 1.0, 1.0))
+            .roughness(0.0)
+            .transmission(1.0, 1.5);
+        assert_furnace(&mean_weight(&glass, Surface::Outside), 0.99);
+        assert_furnace(&mean_weight(&glass, Surface::Inside), 0.99);
+    }
+
+    #[test]
+    fn test_transmission_tints_once() {
+        // Straight through the interface at normal incidence, without reflections
+        let glass = Principled::new(Vec3::new(0.5, 0.5, 0.5))
+            .roughness(0.0)
+            .transmission(1.0, 1.0);
+        let entry = mean_weight(&glass, Surface::Outside);
+        let exit = mean_weight(&glass, Surface::Inside);
+        assert!((entry.e0 - 0.5).abs() < 0.01, "entry weight {:?}", entry);
+        assert!((exit.e0 - 1.0).abs() < 0.01, "exit weight {:?}", exit);
+    }
+
+    #[test]
+    fn test_clearcoat_keeps_energy() {
+        // The coat reflects some light, the white diffuse base the rest
+        let coated = Principled::new(Vec3::new(1.0, 1.0, 1.0))
+            .specular(0.0)
+            .clearcoat(1.0, 0.0);
+        assert_furnace(&mean_weight(&coated, Surface::Outside), 0.99);
+    }
+}
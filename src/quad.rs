@@ -0,0 +1,99 @@
+use crate::hittable::*;
+use crate::materials::*;
+use crate::ray::*;
+use crate::stats::Stats;
+use crate::vec3::*;
+
+// Parallelogram spanned by two edges from a corner, u runs along edge_u and v along
+// edge_v. The normal is edge_u x edge_v.
+#[derive(Debug)]
+pub struct Quad {
+    pub corner: Loc,
+    pub edge_u: Loc,
+    pub edge_v: Loc,
+    pub material: Box<dyn Material>,
+}
+
+impl Quad {
+    pub fn new(corner: Loc, edge_u: Loc, edge_v: Loc, material: Box<dyn Material>) -> Quad {
+        Quad {
+            corner,
+            edge_u,
+            edge_v,
+            material,
+        }
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit<'_>> {
+        let n = self.edge_u.cross(&self.edge_v);
+        let denom = n.dot(&ray.dir);
+        if denom.abs() < 1e-12 {
+            return None; // ray parallel to the plane
+        }
+        let t = n.dot(&(&self.corner - &ray.orig)) / denom;
+        if !validate_t(t) {
+            return None;
+        }
+
+        // Planar coordinates of the hit with respect to the edges
+        let location = ray.at(t);
+        let rel = &location - &self.corner;
+        let w = &n / n.dot(&n);
+        let u = w.dot(&rel.cross(&self.edge_v));
+        let v = w.dot(&self.edge_u.cross(&rel));
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+        if !passes_alpha_test(&*self.material, ray, t, (u, v), &location) {
+            return None;
+        }
+        Some(Hit::from_ray(
+            location,
+            n.unit_vector(),
+            t,
+            ray,
+            &*self.material,
+            (u, v),
+            Tangents {
+                dpdu: self.edge_u.clone(),
+                dpdv: self.edge_v.clone(),
+            },
+        ))
+    }
+
+    fn count_tests(&self, stats: &mut Stats) {
+        stats.quad_tests += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::interval_validator;
+
+    #[test]
+    fn quad_hits() {
+        let quad = Quad::new(
+            Vec3::new(-1.0, 0.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 4.0),
+            Box::new(Lambertian::new(Vec3::zero())),
+        );
+        let validator = interval_validator(Some(0.0), None);
+
+        let ray = Ray::new(Vec3::new(0.5, 3.0, 1.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = quad.hit(&ray, &validator).expect("Ray should've hit quad");
+        assert_eq!(hit.t, 3.0);
+        assert_eq!(hit.uv, (0.75, 0.5));
+        // edge_u x edge_v points down
+        assert_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(hit.surface, Surface::Inside);
+
+        let ray = Ray::new(Vec3::new(1.5, 3.0, 1.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(quad.hit(&ray, &validator).is_none());
+        let ray = Ray::new(Vec3::new(0.5, 3.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(quad.hit(&ray, &validator).is_none());
+    }
+}
@@ -1,18 +1,30 @@
+use crate::spectrum::SampledWavelengths;
 use crate::vec3::*;
 
 #[derive(Debug)]
 pub struct Ray {
     pub orig: Loc,
     pub dir: Loc,
+    // Only set in spectral mode
+    pub wavelengths: Option<SampledWavelengths>,
 }
 
 impl Ray {
     pub const fn new(orig: Loc, dir: Loc) -> Ray {
         Ray {
-            orig: orig,
-            dir: dir,
+            orig,
+            dir,
+            wavelengths: None,
         }
     }
+
+    pub fn with_wavelengths(self, wavelengths: SampledWavelengths) -> Ray {
+        Ray {
+            wavelengths: Some(wavelengths),
+            ..self
+        }
+    }
+
     pub fn at(&self, t: f64) -> Loc {
         &self.orig + t * &self.dir
     }
@@ -34,7 +46,7 @@ mod tests {
 
     #[test]
     fn test_unit_direction() {
-        let sqrt_one_half = 1.0 / (2.0 as f64).sqrt();
+        let sqrt_one_half = 1.0 / 2.0_f64.sqrt();
         assert_eq!(
             RAY.unit_direction(),
             Vec3::new(-sqrt_one_half, 0.0, sqrt_one_half)
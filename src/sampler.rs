@@ -0,0 +1,457 @@
+// Sample generators for the integrals over pixel area, lens, wavelength and the
+// directions at every bounce. Every pixel sample is a point in a high dimensional unit
+// cube, a Sampler returns its coordinates. Samplers are stateless, all values are
+// derived from (pixel, sample index, dimension), which keeps renders reproducible
+// independent of the thread that traces a pixel.
+use crate::sampling::mix_bits;
+
+pub type Pixel = (u32, u32);
+
+pub trait Sampler: Send + Sync {
+    fn get_1d(&self, pixel: Pixel, index: u32, dimension: u32) -> f64;
+
+    // Coordinates dimension and dimension + 1, stratified jointly
+    fn get_2d(&self, pixel: Pixel, index: u32, dimension: u32) -> (f64, f64) {
+        (
+            self.get_1d(pixel, index, dimension),
+            self.get_1d(pixel, index, dimension + 1),
+        )
+    }
+}
+
+// Dimension layout of a pixel sample
+const FILM_DIMENSION: u32 = 0; // 2D
+const LENS_DIMENSION: u32 = 2; // 2D
+const TIME_DIMENSION: u32 = 4; // 1D
+const WAVELENGTH_DIMENSION: u32 = 5; // 1D
+const FIRST_BOUNCE_DIMENSION: u32 = 6;
+// Enough for the lobe choices and direction of the principled material inside a mix
+const DIMENSIONS_PER_BOUNCE: u32 = 8;
+// Materials that need more dimensions at a bounce (e.g. a random walk through a
+// coating) continue in a separate range, so they never reuse the next bounce's values
+const OVERFLOW_DIMENSION: u32 = 1 << 16;
+const OVERFLOW_PER_BOUNCE: u32 = 1 << 10;
+
+// Offset of the sample inside the pixel and position on the lens, both in [0,1)^2, and
+// the moment in [0,1) while the shutter is open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraSample {
+    pub film: (f64, f64),
+    pub lens: (f64, f64),
+    pub time: f64,
+}
+
+// Walks through the dimensions of a single pixel sample. Every bounce starts at a fixed
+// dimension so that the same decisions of different paths get correlated values.
+pub struct SampleStream<'a> {
+    sampler: &'a dyn Sampler,
+    pixel: Pixel,
+    index: u32,
+    dimension: u32,
+    bounce_end: u32,
+    overflow: u32,
+}
+
+impl<'a> SampleStream<'a> {
+    pub fn new(sampler: &'a dyn Sampler) -> SampleStream<'a> {
+        SampleStream {
+            sampler,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+            bounce_end: FIRST_BOUNCE_DIMENSION,
+            overflow: OVERFLOW_DIMENSION,
+        }
+    }
+
+    pub fn start_pixel_sample(&mut self, pixel: Pixel, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.bounce_end = FIRST_BOUNCE_DIMENSION;
+        self.overflow = OVERFLOW_DIMENSION;
+    }
+
+    pub fn camera_sample(&mut self) -> CameraSample {
+        CameraSample {
+            film: self.sampler.get_2d(self.pixel, self.index, FILM_DIMENSION),
+            lens: self.sampler.get_2d(self.pixel, self.index, LENS_DIMENSION),
+            time: self.sampler.get_1d(self.pixel, self.index, TIME_DIMENSION),
+        }
+    }
+
+    pub fn wavelength_sample(&mut self) -> f64 {
+        self.sampler
+            .get_1d(self.pixel, self.index, WAVELENGTH_DIMENSION)
+    }
+
+    // Bounces are counted from 0 at the first surface hit
+    pub fn start_bounce(&mut self, bounce: u32) {
+        self.dimension = FIRST_BOUNCE_DIMENSION + bounce * DIMENSIONS_PER_BOUNCE;
+        self.bounce_end = self.dimension + DIMENSIONS_PER_BOUNCE;
+        self.overflow = OVERFLOW_DIMENSION + bounce * OVERFLOW_PER_BOUNCE;
+    }
+
+    fn next_dimensions(&mut self, count: u32) -> u32 {
+        let dimension = if self.dimension + count <= self.bounce_end {
+            &mut self.dimension
+        } else {
+            &mut self.overflow
+        };
+        let d = *dimension;
+        *dimension += count;
+        d
+    }
+
+    pub fn get_1d(&mut self) -> f64 {
+        let d = self.next_dimensions(1);
+        self.sampler.get_1d(self.pixel, self.index, d)
+    }
+
+    pub fn get_2d(&mut self) -> (f64, f64) {
+        let d = self.next_dimensions(2);
+        self.sampler.get_2d(self.pixel, self.index, d)
+    }
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x9e3779b97f4a7c15, |h, &v| mix_bits(h ^ v))
+}
+
+fn pixel_hash(pixel: Pixel, dimension: u32, seed: u64) -> u64 {
+    hash(&[pixel.0 as u64, pixel.1 as u64, dimension as u64, seed])
+}
+
+fn to_unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn to_unit_u32(bits: u32) -> f64 {
+    bits as f64 / (1u64 << 32) as f64
+}
+
+// Uniform random numbers, no stratification at all
+#[derive(Debug, Clone)]
+pub struct IndependentSampler {
+    seed: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler { seed }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn get_1d(&self, pixel: Pixel, index: u32, dimension: u32) -> f64 {
+        to_unit(hash(&[
+            pixel.0 as u64,
+            pixel.1 as u64,
+            index as u64,
+            dimension as u64,
+            self.seed,
+        ]))
+    }
+}
+
+// Jittered strata. The samples of a pixel are spread over a grid of strata, every
+// dimension visits the strata in its own random order (padding).
+#[derive(Debug, Clone)]
+pub struct StratifiedSampler {
+    x_strata: u32,
+    y_strata: u32,
+    seed: u64,
+}
+
+impl StratifiedSampler {
+    // Strata are chosen to cover samples_per_pixel samples as evenly as possible
+    pub fn new(samples_per_pixel: u32, seed: u64) -> StratifiedSampler {
+        let x_strata = ((samples_per_pixel as f64).sqrt() as u32).max(1);
+        let y_strata = samples_per_pixel.max(1).div_ceil(x_strata);
+        StratifiedSampler {
+            x_strata,
+            y_strata,
+            seed,
+        }
+    }
+
+    fn jitter(&self, pixel: Pixel, index: u32, dimension: u32) -> f64 {
+        to_unit(hash(&[
+            pixel_hash(pixel, dimension, self.seed),
+            index as u64,
+        ]))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn get_1d(&self, pixel: Pixel, index: u32, dimension: u32) -> f64 {
+        let n = self.x_strata * self.y_strata;
+        let permutation_seed = pixel_hash(pixel, dimension, self.seed) as u32;
+        let stratum = permutation_element(index % n, n, permutation_seed);
+        (stratum as f64 + self.jitter(pixel, index, dimension)) / n as f64
+    }
+
+    fn get_2d(&self, pixel: Pixel, index: u32, dimension: u32) -> (f64, f64) {
+        let n = self.x_strata * self.y_strata;
+        let permutation_seed = pixel_hash(pixel, dimension, self.seed) as u32;
+        let stratum = permutation_element(index % n, n, permutation_seed);
+        let (x, y) = (stratum % self.x_strata, stratum / self.x_strata);
+        (
+            (x as f64 + self.jitter(pixel, index, dimension)) / self.x_strata as f64,
+            (y as f64 + self.jitter(pixel, index, dimension + 1)) / self.y_strata as f64,
+        )
+    }
+}
+
+// Element i of a random permutation of 0..n, Kensler's "Correlated Multi-Jittered
+// Sampling" (2013)
+fn permutation_element(mut i: u32, n: u32, p: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % n
+}
+
+// Number of dimensions that get their own prime base, later ones fall back to random
+// values as Halton points in large bases are poorly distributed anyway
+const HALTON_DIMENSIONS: usize = 64;
+
+fn primes() -> &'static [u64] {
+    static PRIMES: std::sync::OnceLock<Vec<u64>> = std::sync::OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let mut primes = Vec::with_capacity(HALTON_DIMENSIONS);
+        let mut candidate = 2;
+        while primes.len() < HALTON_DIMENSIONS {
+            if primes.iter().all(|p| candidate % p != 0) {
+                primes.push(candidate);
+            }
+            candidate += 1;
+        }
+        primes
+    })
+}
+
+// Radical inverse with every digit permuted depending on the digits below it (Owen
+// scrambling). Plain radical inverses of large bases are strongly correlated between
+// dimensions for the first base samples. Digits are generated until they no longer
+// change the result.
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed: u64 = 0;
+    while 1.0 - inv_base_m < 1.0 {
+        let digit = index % base;
+        index /= base;
+        let digit_seed = mix_bits(seed ^ reversed) as u32;
+        let digit = permutation_element(digit as u32, base as u32, digit_seed) as u64;
+        reversed = reversed * base + digit;
+        inv_base_m *= inv_base;
+    }
+    (reversed as f64 * inv_base_m).min(1.0 - f64::EPSILON / 2.0)
+}
+
+// Halton sequence, Owen-scrambled per pixel and dimension
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    seed: u64,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler { seed }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn get_1d(&self, pixel: Pixel, index: u32, dimension: u32) -> f64 {
+        let seed = pixel_hash(pixel, dimension, self.seed);
+        match primes().get(dimension as usize) {
+            Some(&base) => owen_scrambled_radical_inverse(base, index as u64, seed),
+            None => to_unit(hash(&[seed, index as u64])),
+        }
+    }
+}
+
+// Owen-scrambled Sobol points in the style of Burley's "Practical Hash-based Owen
+// Scrambling" (2020). Each 2D request uses the first two Sobol dimensions with its own
+// scrambling seed, the sample index is shuffled so that dimensions are decorrelated.
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    seed: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler { seed }
+    }
+
+    fn scrambled(&self, pixel: Pixel, index: u32, dimension: u32) -> (u32, u32) {
+        let seed = pixel_hash(pixel, dimension, self.seed);
+        let index = nested_uniform_scramble(index, seed as u32);
+        (
+            nested_uniform_scramble(sobol_0(index), mix_bits(seed ^ 1) as u32),
+            nested_uniform_scramble(sobol_1(index), mix_bits(seed ^ 2) as u32),
+        )
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn get_1d(&self, pixel: Pixel, index: u32, dimension: u32) -> f64 {
+        to_unit_u32(self.scrambled(pixel, index, dimension).0)
+    }
+
+    fn get_2d(&self, pixel: Pixel, index: u32, dimension: u32) -> (f64, f64) {
+        let (x, y) = self.scrambled(pixel, index, dimension);
+        (to_unit_u32(x), to_unit_u32(y))
+    }
+}
+
+// First Sobol dimension, the van der Corput sequence in base 2
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+// Second Sobol dimension, its direction numbers follow v_k = v_{k-1} ^ (v_{k-1} >> 1)
+fn sobol_1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Owen scrambling: random bit flips that only depend on the higher bits
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samplers(spp: u32) -> Vec<(&'static str, Box<dyn Sampler>)> {
+        vec![
+            ("independent", Box::new(IndependentSampler::new(1))),
+            ("stratified", Box::new(StratifiedSampler::new(spp, 1))),
+            ("halton", Box::new(HaltonSampler::new(1))),
+            ("sobol", Box::new(SobolSampler::new(1))),
+        ]
+    }
+
+    #[test]
+    fn test_samples_in_unit_square() {
+        for (name, sampler) in samplers(16) {
+            for index in 0..64 {
+                for dimension in [0, 3, 17, 200, OVERFLOW_DIMENSION] {
+                    let (x, y) = sampler.get_2d((3, 5), index, dimension);
+                    let z = sampler.get_1d((3, 5), index, dimension);
+                    assert!(
+                        [x, y, z].iter().all(|v| (0.0..1.0).contains(v)),
+                        "{} out of range",
+                        name
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_permutation_is_bijective() {
+        for n in [1, 5, 16, 100] {
+            let mut seen = vec![false; n as usize];
+            for i in 0..n {
+                seen[permutation_element(i, n, 0xdeadbeef) as usize] = true;
+            }
+            assert!(seen.iter().all(|&s| s));
+        }
+    }
+
+    #[test]
+    fn test_stream_bounces_are_disjoint() {
+        let sampler = IndependentSampler::new(0);
+        let mut stream = SampleStream::new(&sampler);
+        stream.start_pixel_sample((0, 0), 0);
+        stream.start_bounce(0);
+        let first: Vec<f64> = (0..DIMENSIONS_PER_BOUNCE + 2)
+            .map(|_| stream.get_1d())
+            .collect();
+        stream.start_bounce(1);
+        let second = stream.get_1d();
+        // The two values beyond the budget of bounce 0 must not alias bounce 1
+        assert!(first.iter().all(|&v| v != second));
+    }
+
+    // Error of estimating a smooth 2D integral over many pixels: the stratified and
+    // low discrepancy samplers have to beat pure random sampling clearly
+    #[test]
+    fn test_lower_error_than_independent() {
+        let spp = 64;
+        // Integral of x * y + sin(pi x) over [0,1]^2
+        let exact = 0.25 + 2.0 / std::f64::consts::PI;
+        let f = |(x, y): (f64, f64)| x * y + (std::f64::consts::PI * x).sin();
+        let mut errors = Vec::new();
+        for (name, sampler) in samplers(spp) {
+            let mut squared_error = 0.0;
+            for px in 0..64 {
+                let estimate = (0..spp)
+                    .map(|i| f(sampler.get_2d((px, 0), i, FILM_DIMENSION)))
+                    .sum::<f64>()
+                    / spp as f64;
+                squared_error += (estimate - exact).powi(2);
+            }
+            errors.push((name, squared_error));
+        }
+        let independent = errors[0].1;
+        for (name, error) in &errors[1..] {
+            assert!(
+                *error < independent / 4.0,
+                "{} error {} vs independent {}",
+                name,
+                error,
+                independent
+            );
+        }
+    }
+}
@@ -1,32 +1,113 @@
 use crate::vec3::*;
 use rand::Rng;
-use std::mem;
+use std::io;
+use std::io::{Read, Write};
 
+// Running mean of the samples of a pixel. The variance of their luminance is tracked
+// with Welford's algorithm, which tells how converged the pixel is.
+#[derive(Debug, Clone)]
 pub struct ColorSampler {
     sample_num: i32,
-    acc: Color,
+    mean: Color,
+    luminance_mean: f64,
+    luminance_m2: f64,
 }
 
+// Keeps the relative error of very dark pixels finite
+const DARK_LUMINANCE: f64 = 1.0 / 256.0;
+
 impl ColorSampler {
     pub fn new() -> ColorSampler {
         ColorSampler {
             sample_num: 0,
-            acc: Vec3::zero(),
+            mean: Vec3::zero(),
+            luminance_mean: 0.0,
+            luminance_m2: 0.0,
         }
     }
 
     pub fn add(&mut self, c: &Color) {
         self.sample_num += 1;
-        self.acc.add_cum(c);
+        let n = self.sample_num as f64;
+        self.mean.add_cum(&((c - &self.mean) / n));
+        let luminance = c.luminance();
+        let delta = luminance - self.luminance_mean;
+        self.luminance_mean += delta / n;
+        self.luminance_m2 += delta * (luminance - self.luminance_mean);
+    }
+
+    pub fn sample_num(&self) -> i32 {
+        self.sample_num
+    }
+
+    // Unbiased sample variance of the luminance
+    pub fn variance(&self) -> f64 {
+        if self.sample_num < 2 {
+            return 0.0;
+        }
+        self.luminance_m2 / (self.sample_num - 1) as f64
+    }
+
+    // Standard error of the mean luminance relative to the mean luminance
+    pub fn relative_error(&self) -> f64 {
+        if self.sample_num < 2 {
+            return f64::INFINITY;
+        }
+        (self.variance() / self.sample_num as f64).sqrt() / self.luminance_mean.max(DARK_LUMINANCE)
     }
 
-    pub fn get_and_reset(&mut self) -> Color {
-        let mut res = Vec3::zero();
-        mem::swap(&mut self.acc, &mut res);
-        res = res / (self.sample_num as f64);
-        self.sample_num = 0;
-        res
+    #[allow(dead_code)]
+    pub fn mean(&self) -> Color {
+        self.mean.clone()
     }
+
+    // Exact little endian encoding of the state, for checkpoints
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.sample_num.to_le_bytes())?;
+        let values = [
+            self.mean.e0,
+            self.mean.e1,
+            self.mean.e2,
+            self.luminance_mean,
+            self.luminance_m2,
+        ];
+        for v in values {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<ColorSampler> {
+        let mut int = [0; 4];
+        input.read_exact(&mut int)?;
+        let mut values = [0.0; 5];
+        for v in values.iter_mut() {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            *v = f64::from_le_bytes(bytes);
+        }
+        Ok(ColorSampler {
+            sample_num: i32::from_le_bytes(int),
+            mean: Vec3::new(values[0], values[1], values[2]),
+            luminance_mean: values[3],
+            luminance_m2: values[4],
+        })
+    }
+}
+
+// Finalizer of splitmix64, a cheap hash with good avalanche behaviour
+pub fn mix_bits(mut v: u64) -> u64 {
+    v = (v ^ (v >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    v = (v ^ (v >> 27)).wrapping_mul(0x94d049bb133111eb);
+    v ^ (v >> 31)
+}
+
+// Deterministic pseudo random value in [0,1) derived from the given floats
+pub fn hash_to_unit(values: &[f64]) -> f64 {
+    let h = values
+        .iter()
+        .fold(0x9e3779b97f4a7c15, |h, v| mix_bits(h ^ v.to_bits()));
+    (h >> 11) as f64 / (1u64 << 53) as f64
 }
 
 // Returns random float in [-1,1]
@@ -70,6 +151,7 @@ impl Vec3 {
     }
 
     // Returns random vector in disk with 0 depth z
+    #[allow(dead_code)]
     pub fn random_in_unit_disk(rng: &mut impl Rng) -> Vec3 {
         let mut candidate = Vec3::new(rand_coord(rng), rand_coord(rng), 0.0);
         while candidate.length_squared() >= 1.0 {
@@ -78,3 +160,50 @@ impl Vec3 {
         candidate
     }
 }
+
+// Warps of uniform samples in [0,1)^2 (e.g. from a Sampler) onto other domains
+impl Vec3 {
+    pub fn uniform_unit_vector(u: (f64, f64)) -> Vec3 {
+        let z = 1.0 - 2.0 * u.0;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * u.1;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    pub fn uniform_in_unit_sphere(u: (f64, f64), radius_sample: f64) -> Vec3 {
+        radius_sample.cbrt() * Vec3::uniform_unit_vector(u)
+    }
+
+    // Shirley-Chiu concentric mapping, keeps strata of the square compact on the disk
+    pub fn uniform_in_unit_disk(u: (f64, f64)) -> Vec3 {
+        use std::f64::consts::FRAC_PI_4;
+        let (x, y) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+        if x == 0.0 && y == 0.0 {
+            return Vec3::zero();
+        }
+        let (r, theta) = if x.abs() > y.abs() {
+            (x, FRAC_PI_4 * (y / x))
+        } else {
+            (y, 2.0 * FRAC_PI_4 - FRAC_PI_4 * (x / y))
+        };
+        Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_sampler_statistics() {
+        let mut sampler = ColorSampler::new();
+        for l in [1.0, 2.0, 3.0, 4.0] {
+            sampler.add(&Vec3::new(l, l, l));
+        }
+        assert_eq!(sampler.sample_num(), 4);
+        assert!((sampler.variance() - 5.0 / 3.0).abs() < 1e-12);
+        let expected_error = (5.0 / 3.0 / 4.0_f64).sqrt() / 2.5;
+        assert!((sampler.relative_error() - expected_error).abs() < 1e-12);
+        assert!((&sampler.mean() - Vec3::new(2.5, 2.5, 2.5)).length() < 1e-12);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::bump::*;
+use crate::hittable_list::*;
+use crate::image::Image;
+use crate::materials::*;
+use crate::principled::*;
+use crate::quad::*;
+use crate::sphere::*;
+use crate::texture::*;
+use crate::triangle::*;
+use crate::vec3::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Seeded, the same scene is needed to resume a render from a checkpoint
+pub fn cover() -> HittableList<'static> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut world = HittableList::empty();
+
+    let ground_material = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+    let ground_sphere = Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Box::new(ground_material),
+    );
+
+    world.add(ground_sphere);
+    for a in -11..11 {
+        for b in -11..11 {
+            let material_num: f64 = rng.gen();
+            let center = Vec3::new(
+                a as f64 + 0.9 * rng.gen::<f64>(),
+                0.2,
+                b as f64 + 0.9 * rng.gen::<f64>(),
+            );
+
+            if (&center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                let material: Box<dyn Material> = if material_num < 0.8 {
+                    // diffuse
+                    let albedo = Vec3::random_range(0.0, 1.0, &mut rng)
+                        .hadamard(&Vec3::random_range(0.0, 1.0, &mut rng));
+                    Box::new(Lambertian::new(albedo))
+                } else if material_num < 0.95 {
+                    // metal
+                    let albedo = Vec3::random_range(0.5, 1.0, &mut rng);
+                    let fuzz = rng.gen_range(0.0..0.5);
+                    Box::new(Metal::new(albedo, fuzz))
+                } else {
+                    // glass
+                    Box::new(Dielectric::new(1.5))
+                };
+                world.add(Sphere::new(center, 0.2, material));
+            }
+        }
+    }
+    let material_dielec = Dielectric::with_ior(IorCurve::crown_glass());
+    world.add(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Box::new(material_dielec),
+    ));
+    let material_lamb = Lambertian::new(Vec3::new(0.4, 0.2, 0.1));
+    world.add(Sphere::new(
+        Vec3::new(-4.0, 1.0, 0.0),
+        1.0,
+        Box::new(material_lamb),
+    ));
+    let material_metal = Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0);
+    world.add(Sphere::new(
+        Vec3::new(4.0, 1.0, 0.0),
+        1.0,
+        Box::new(material_metal),
+    ));
+    world
+}
+
+// Row of spheres showing the lobes of the principled material, framed for the
+// same camera as the cover scene
+pub fn materials() -> HittableList<'static> {
+    let mut world = HittableList::empty();
+
+    let ground = Principled::new(Checker::new(
+        Vec3::new(0.2, 0.3, 0.1),
+        Vec3::new(0.9, 0.9, 0.9),
+        10.0,
+    ))
+    .roughness(0.8);
+    world.add(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Box::new(ground),
+    ));
+
+    let preset = |name| GltfMaterial::preset(name).unwrap();
+    // Brushed gold, the roughness comes from a metallic-roughness map
+    let gold = GltfMaterial {
+        roughness_factor: 1.0,
+        metallic_roughness_texture: Some(ImageTexture::new(brushed_metal_map(64, 256))),
+        ..preset("gold")
+    };
+
+    let car_paint = Principled::new(Vec3::new(0.6, 0.02, 0.02))
+        .metallic(0.3)
+        .roughness(0.6)
+        .clearcoat(1.0, 0.02);
+    let velvet = Principled::new(Vec3::new(0.1, 0.05, 0.3))
+        .roughness(1.0)
+        .specular(0.0)
+        .sheen(Vec3::new(0.5, 0.4, 0.8));
+    let frosted_glass = Principled::new(Vec3::new(0.9, 1.0, 0.95))
+        .roughness(0.15)
+        .transmission(1.0, 1.5);
+
+    // Perpendicular to the viewing direction of the cover camera
+    let row = Vec3::new(3.0, 0.0, -13.0).unit_vector();
+    let materials: Vec<Box<dyn Material>> = vec![
+        gold.into_material(),
+        Box::new(car_paint),
+        Box::new(velvet),
+        Box::new(frosted_glass),
+        preset("plastic").into_material(),
+    ];
+    for (i, material) in materials.into_iter().enumerate() {
+        let center = (i as f64 - 2.0) * 1.4 * &row + Vec3::new(0.0, 0.6, 0.0);
+        world.add(Sphere::new(center, 0.6, material));
+    }
+
+    // Layered materials in front of the row
+    let rusty_metal = MixMaterial::new(
+        Box::new(Metal::new(Vec3::new(0.8, 0.8, 0.85), 0.05)),
+        Box::new(Lambertian::new(Vec3::new(0.45, 0.18, 0.06))),
+        Checker::new(0.0, 0.85, 25.0),
+    );
+    let golf_ball = NormalMapped::new(
+        Box::new(Principled::new(Vec3::new(0.9, 0.9, 0.9)).roughness(0.3)),
+        ImageTexture::new(dimple_normal_map(512, 256, 24, 12)),
+    );
+    let lacquered_wood = BumpMapped::new(
+        Box::new(Coated::new(
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.3, 0.15))),
+            1.5,
+            Vec3::new(0.95, 0.9, 0.8),
+        )),
+        ImageTexture::new(grain_height_map(256, 256)),
+        0.002,
+    );
+    let toward_camera = Vec3::new(13.0, 0.0, 3.0).unit_vector();
+    let layered: Vec<Box<dyn Material>> = vec![
+        Box::new(rusty_metal),
+        Box::new(golf_ball),
+        Box::new(lacquered_wood),
+    ];
+    for (i, material) in layered.into_iter().enumerate() {
+        let center =
+            (i as f64 - 1.0) * 1.4 * &row + 2.2 * &toward_camera + Vec3::new(0.0, 0.4, 0.0);
+        world.add(Sphere::new(center, 0.4, material));
+    }
+
+    // Dispersive dielectrics closest to the camera, their rainbows show up in --spectral
+    // mode
+    let front = 4.0 * &toward_camera;
+    world.add(Sphere::new(
+        &front - 1.5 * &row + Vec3::new(0.0, 0.35, 0.0),
+        0.35,
+        Box::new(Dielectric::with_ior(IorCurve::diamond())),
+    ));
+    add_prism(
+        &mut world,
+        &front - 0.6 * &row + Vec3::new(0.0, 0.001, 0.0),
+        1.2 * &row,
+        0.6,
+        || Box::new(Dielectric::with_ior(IorCurve::flint_glass())),
+    );
+    // Water, whose dispersion is well described by the first two Cauchy terms
+    let water = IorCurve::Cauchy {
+        a: 1.3199,
+        b: 0.00653,
+    };
+    world.add(Sphere::new(
+        &front + 1.5 * &row + Vec3::new(0.0, 0.3, 0.0),
+        0.3,
+        Box::new(Dielectric::with_ior(water)),
+    ));
+    world
+}
+
+// Upright prism lying on the ground along axis from start, its cross-section an
+// equilateral triangle with the given side length. Faces are oriented outwards.
+fn add_prism(
+    world: &mut HittableList<'static>,
+    start: Loc,
+    axis: Loc,
+    side: f64,
+    material: impl Fn() -> Box<dyn Material>,
+) {
+    let up = Vec3::new(0.0, 1.0, 0.0);
+    let across = axis.cross(&up).unit_vector();
+    let base_left = &start - side / 2.0 * &across;
+    let base_right = &start + side / 2.0 * &across;
+    let apex = &start + side * 3f64.sqrt() / 2.0 * &up;
+    let center = (&base_left + &base_right + &apex) / 3.0 + 0.5 * &axis;
+    let outwards = |point: &Loc, normal: &Loc| normal.dot(&(point - &center)) > 0.0;
+
+    let corners = [base_left, base_right, apex];
+    for i in 0..3 {
+        let (a, b) = (&corners[i], &corners[(i + 1) % 3]);
+        let (edge_u, edge_v) = (b - a, axis.clone());
+        let (edge_u, edge_v) = if outwards(a, &edge_u.cross(&edge_v)) {
+            (edge_u, edge_v)
+        } else {
+            (edge_v, edge_u)
+        };
+        world.add(Quad::new(a.clone(), edge_u, edge_v, material()));
+    }
+    for offset in [Vec3::zero(), axis.clone()] {
+        let [p0, p1, p2] = [0, 1, 2].map(|i| &corners[i] + &offset);
+        let normal = (&p1 - &p0).cross(&(&p2 - &p0));
+        if outwards(&p0, &normal) {
+            world.add(Triangle::new(p0, p1, p2, material()));
+        } else {
+            world.add(Triangle::new(p0, p2, p1, material()));
+        }
+    }
+}
+
+// Chain-link fence and foliage cards in front of a few spheres, all of the fine
+// geometry comes from alpha textures
+pub fn cutouts() -> HittableList<'static> {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut world = HittableList::empty();
+
+    world.add(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Box::new(Lambertian::new(Vec3::new(0.4, 0.45, 0.3))),
+    ));
+    world.add(Sphere::new(
+        Vec3::new(-3.0, 1.0, -1.0),
+        1.0,
+        Box::new(Lambertian::new(Vec3::new(0.7, 0.2, 0.1))),
+    ));
+    world.add(Sphere::new(
+        Vec3::new(-2.0, 1.0, 2.5),
+        1.0,
+        Box::new(Metal::new(Vec3::new(0.8, 0.8, 0.8), 0.1)),
+    ));
+
+    // Fence in front of the spheres, perpendicular to the viewing direction
+    let row = Vec3::new(3.0, 0.0, -13.0).unit_vector();
+    let fence_width = 10.0;
+    let fence_height = 2.0;
+    world.add(Quad::new(
+        -fence_width / 2.0 * &row,
+        fence_width * &row,
+        Vec3::new(0.0, fence_height, 0.0),
+        Box::new(AlphaMasked::new(
+            Box::new(Metal::new(Vec3::new(0.6, 0.6, 0.6), 0.4)),
+            ChainLink::new(fence_width / 0.3, fence_height / 0.3, 0.06),
+            AlphaMode::Mask(0.5),
+        )),
+    ));
+
+    // Leaf cards, each one made of two triangles
+    for _ in 0..40 {
+        let center = Vec3::new(
+            rng.gen_range(1.0..3.0),
+            rng.gen_range(0.1..1.2),
+            rng.gen_range(-1.5..1.5),
+        );
+        let size = rng.gen_range(0.25..0.45);
+        let edge_u = size * 0.6 * Vec3::random_unit_vector(&mut rng);
+        let edge_v = size * Vec3::random_unit_vector(&mut rng);
+        let corner = &center - 0.5 * &edge_u - 0.5 * &edge_v;
+        let color = Vec3::new(
+            rng.gen_range(0.1..0.3),
+            rng.gen_range(0.35..0.6),
+            rng.gen_range(0.05..0.15),
+        );
+        let leaf = || -> Box<dyn Material> {
+            Box::new(AlphaMasked::new(
+                Box::new(Lambertian::new(color.clone())),
+                Leaf,
+                AlphaMode::Blend,
+            ))
+        };
+        let p00 = corner.clone();
+        let p10 = &corner + &edge_u;
+        let p01 = &corner + &edge_v;
+        let p11 = &corner + &edge_u + &edge_v;
+        world.add(Triangle::new(p00, p10.clone(), p01.clone(), leaf()));
+        world.add(Triangle::with_uvs(
+            [p11, p01, p10],
+            [(1.0, 1.0), (0.0, 1.0), (1.0, 0.0)],
+            leaf(),
+        ));
+    }
+    world
+}
+
+// Diamond mesh of wires, opaque (1) on the wires and transparent (0) in between
+#[derive(Debug)]
+struct ChainLink {
+    cells_u: f64,
+    cells_v: f64,
+    wire_width: f64,
+}
+
+impl ChainLink {
+    fn new(cells_u: f64, cells_v: f64, wire_width: f64) -> ChainLink {
+        ChainLink {
+            cells_u,
+            cells_v,
+            wire_width,
+        }
+    }
+}
+
+impl Texture for ChainLink {
+    fn value(&self, uv: TexCoord, _: &Loc) -> Color {
+        let a = uv.0 * self.cells_u + uv.1 * self.cells_v;
+        let b = uv.0 * self.cells_u - uv.1 * self.cells_v;
+        let on_wire = |x: f64| (x - x.round()).abs() < self.wire_width;
+        let alpha = if on_wire(a) || on_wire(b) { 1.0 } else { 0.0 };
+        Vec3::new(alpha, alpha, alpha)
+    }
+}
+
+// Leaf silhouette along v with soft, fractionally transparent edges
+#[derive(Debug)]
+struct Leaf;
+
+impl Texture for Leaf {
+    fn value(&self, uv: TexCoord, _: &Loc) -> Color {
+        let half_width = 0.45 * (std::f64::consts::PI * uv.1).sin();
+        let alpha = ((half_width - (uv.0 - 0.5).abs()) / 0.08).clamp(0.0, 1.0);
+        Vec3::new(alpha, alpha, alpha)
+    }
+}
+
+// Tangent space normal map of a grid of spherical dimples
+fn dimple_normal_map(width: usize, height: usize, cells_u: usize, cells_v: usize) -> Image {
+    let mut image = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            // Position relative to the center of the current cell, in cell units
+            let cu = (x as f64 + 0.5) / width as f64 * cells_u as f64;
+            let cv = (1.0 - (y as f64 + 0.5) / height as f64) * cells_v as f64;
+            let (dx, dy) = (cu.fract() - 0.5, cv.fract() - 0.5);
+            let normal = if dx * dx + dy * dy < 0.16 {
+                Vec3::new(-dx, -dy, 0.6).unit_vector()
+            } else {
+                Vec3::new(0.0, 0.0, 1.0)
+            };
+            image.set(x, y, 0.5 * (normal + Vec3::new(1.0, 1.0, 1.0)));
+        }
+    }
+    image
+}
+
+// glTF metallic-roughness map of brushed metal: fully metallic (blue), with horizontal
+// streaks of varying roughness (green)
+fn brushed_metal_map(width: usize, height: usize) -> Image {
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut image = Image::new(width, height);
+    for y in 0..height {
+        let roughness = rng.gen_range(0.15..0.35);
+        for x in 0..width {
+            image.set(x, y, Vec3::new(0.0, roughness, 1.0));
+        }
+    }
+    image
+}
+
+// Wavy rings as found in wood grain, heights in [0,1]
+fn grain_height_map(width: usize, height: usize) -> Image {
+    use std::f64::consts::PI;
+    let mut image = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let u = x as f64 / width as f64;
+            let v = y as f64 / height as f64;
+            let h = 0.5 + 0.5 * (2.0 * PI * (30.0 * v + 0.4 * (4.0 * PI * u).sin())).sin();
+            image.set(x, y, Vec3::new(h, h, h));
+        }
+    }
+    image
+}
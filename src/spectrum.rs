@@ -0,0 +1,284 @@
+use crate::vec3::*;
+use std::sync::OnceLock;
+
+// Visible range that is sampled in spectral mode (nm)
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 720.0;
+// Number of wavelengths carried by every camera sample
+pub const N_WAVELENGTHS: usize = 4;
+
+// Wavelengths carried by a single camera sample. The first entry is the hero wavelength,
+// the others are evenly rotated through the visible range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledWavelengths {
+    lambda: [f64; N_WAVELENGTHS],
+    pdf: [f64; N_WAVELENGTHS],
+}
+
+// Spectral quantity (radiance, throughput, ...) evaluated at SampledWavelengths
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledSpectrum {
+    values: [f64; N_WAVELENGTHS],
+}
+
+impl SampledWavelengths {
+    // Stratified sampling of the visible range, u in [0,1)
+    pub fn sample_uniform(u: f64) -> SampledWavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let mut lambda = [0.0; N_WAVELENGTHS];
+        for (i, l) in lambda.iter_mut().enumerate() {
+            let offset = (u + i as f64 / N_WAVELENGTHS as f64).fract();
+            *l = LAMBDA_MIN + offset * range;
+        }
+        SampledWavelengths {
+            lambda,
+            pdf: [1.0 / range; N_WAVELENGTHS],
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    pub fn is_secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&p| p == 0.0)
+    }
+
+    // Used when a wavelength dependent event (e.g. dispersion) happens and only the
+    // hero wavelength can follow the path further.
+    pub fn terminate_secondary(&self) -> SampledWavelengths {
+        if self.is_secondary_terminated() {
+            return *self;
+        }
+        let mut pdf = [0.0; N_WAVELENGTHS];
+        pdf[0] = self.pdf[0] / N_WAVELENGTHS as f64;
+        SampledWavelengths {
+            lambda: self.lambda,
+            pdf,
+        }
+    }
+
+    // Monte Carlo estimate of the XYZ tristimulus values of the radiance
+    pub fn to_xyz(self, radiance: &SampledSpectrum) -> Vec3 {
+        let mut xyz = Vec3::zero();
+        for i in 0..N_WAVELENGTHS {
+            if self.pdf[i] == 0.0 {
+                continue;
+            }
+            let weight = radiance.values[i] / self.pdf[i];
+            xyz.add_cum(&(weight * cie_xyz(self.lambda[i])));
+        }
+        xyz / N_WAVELENGTHS as f64
+    }
+}
+
+impl SampledSpectrum {
+    pub fn constant(c: f64) -> SampledSpectrum {
+        SampledSpectrum {
+            values: [c; N_WAVELENGTHS],
+        }
+    }
+
+    pub fn from_fn(wavelengths: &SampledWavelengths, f: impl Fn(f64) -> f64) -> SampledSpectrum {
+        let mut values = [0.0; N_WAVELENGTHS];
+        for (v, &l) in values.iter_mut().zip(wavelengths.lambda.iter()) {
+            *v = f(l);
+        }
+        SampledSpectrum { values }
+    }
+
+    // Upsamples an RGB reflectance (albedo) to the sampled wavelengths
+    pub fn from_reflectance(rgb: &Color, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        SampledSpectrum::from_fn(wavelengths, |l| rgb_to_reflectance(rgb, l))
+    }
+
+    // Upsamples an RGB emission to the sampled wavelengths. White (1,1,1) maps to
+    // the D65 illuminant, which is the white point of sRGB.
+    pub fn from_illuminant(rgb: &Color, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        SampledSpectrum::from_fn(wavelengths, |l| {
+            rgb_to_reflectance(rgb, l) * d65_normalized(l)
+        })
+    }
+
+    pub fn hadamard(&self, rhs: &SampledSpectrum) -> SampledSpectrum {
+        let mut values = self.values;
+        for (v, r) in values.iter_mut().zip(rhs.values.iter()) {
+            *v *= r;
+        }
+        SampledSpectrum { values }
+    }
+
+    pub fn scaled(&self, s: f64) -> SampledSpectrum {
+        SampledSpectrum {
+            values: self.values.map(|v| s * v),
+        }
+    }
+
+    pub fn max(&self) -> f64 {
+        self.values.iter().copied().fold(0.0, f64::max)
+    }
+}
+
+// Piecewise Gaussian used in the CIE fit below
+fn gaussian(x: f64, mu: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let sigma = if x < mu { sigma_left } else { sigma_right };
+    (-0.5 * ((x - mu) / sigma).powf(2.0)).exp()
+}
+
+// CIE 1931 2° color matching functions, multi-lobe fit by Wyman, Sloan and Shirley (2013)
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056 * gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2);
+    let y =
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1);
+    let z =
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8);
+    Vec3::new(x, y, z)
+}
+
+// Converts XYZ (D65 white) into linear sRGB
+pub fn xyz_to_linear_srgb(xyz: &Vec3) -> Color {
+    Vec3::new(
+        3.2404542 * xyz.e0 - 1.5371385 * xyz.e1 - 0.4985314 * xyz.e2,
+        -0.9692660 * xyz.e0 + 1.8760108 * xyz.e1 + 0.0415560 * xyz.e2,
+        0.0556434 * xyz.e0 - 0.2040259 * xyz.e1 + 1.0572252 * xyz.e2,
+    )
+}
+
+// CIE standard illuminant D65, 380nm to 720nm in 10nm steps
+const D65: [f64; 35] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046, 100.000, 96.3342,
+    95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+    78.2842, 69.7213, 71.6091, 74.349, 61.604,
+];
+
+fn d65(lambda: f64) -> f64 {
+    let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, (D65.len() - 1) as f64);
+    let i = (x as usize).min(D65.len() - 2);
+    let frac = x - i as f64;
+    (1.0 - frac) * D65[i] + frac * D65[i + 1]
+}
+
+// D65 scaled to a luminance (Y) of one
+fn d65_normalized(lambda: f64) -> f64 {
+    static Y_INTEGRAL: OnceLock<f64> = OnceLock::new();
+    let y_integral = Y_INTEGRAL.get_or_init(|| {
+        let mut acc = 0.0;
+        let mut l = LAMBDA_MIN;
+        while l < LAMBDA_MAX {
+            acc += d65(l + 0.5) * cie_xyz(l + 0.5).e1;
+            l += 1.0;
+        }
+        acc
+    });
+    d65(lambda) / y_integral
+}
+
+// Basis spectra for Smits' RGB to spectrum conversion (1999),
+// 10 bins spanning LAMBDA_MIN to LAMBDA_MAX
+const SMITS_WHITE: [f64; 10] = [1.0, 1.0, 0.9999, 0.9993, 0.9992, 0.9998, 1.0, 1.0, 1.0, 1.0];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0, 0.0, 0.0,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0, 1.0, 0.9685, 0.2229, 0.0, 0.0458, 0.8369, 1.0, 1.0, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0, 0.1088, 0.6651, 1.0, 1.0, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0, 0.0, 0.0, 0.0, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0, 0.0, 0.0273, 0.7937, 1.0, 0.9418, 0.1719, 0.0, 0.0, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0, 1.0, 0.8916, 0.3323, 0.0, 0.0, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// Evaluates the smooth reflectance spectrum corresponding to rgb at lambda (Smits 1999)
+pub fn rgb_to_reflectance(rgb: &Color, lambda: f64) -> f64 {
+    let bin = (((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN)) * 10.0).clamp(0.0, 9.0) as usize;
+    let (r, g, b) = (rgb.e0, rgb.e1, rgb.e2);
+
+    let value = if r <= g && r <= b {
+        r * SMITS_WHITE[bin]
+            + if g <= b {
+                (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+            } else {
+                (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+            }
+    } else if g <= r && g <= b {
+        g * SMITS_WHITE[bin]
+            + if r <= b {
+                (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+            } else {
+                (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+            }
+    } else {
+        b * SMITS_WHITE[bin]
+            + if r <= g {
+                (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+            } else {
+                (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+            }
+    };
+    value.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integrate_illuminant(rgb: &Color) -> Color {
+        // Deterministic stratified estimate over the visible range
+        let n = 1000;
+        let mut acc = Vec3::zero();
+        for i in 0..n {
+            let wl = SampledWavelengths::sample_uniform((i as f64 + 0.5) / n as f64);
+            let spectrum = SampledSpectrum::from_illuminant(rgb, &wl);
+            acc.add_cum(&wl.to_xyz(&spectrum));
+        }
+        xyz_to_linear_srgb(&(acc / n as f64))
+    }
+
+    #[test]
+    fn test_white_illuminant_is_white() {
+        let c = integrate_illuminant(&Vec3::new(1.0, 1.0, 1.0));
+        for channel in &[c.e0, c.e1, c.e2] {
+            assert!((channel - 1.0).abs() < 0.05, "white maps to {:?}", c);
+        }
+    }
+
+    #[test]
+    fn test_red_illuminant_is_red() {
+        let c = integrate_illuminant(&Vec3::new(1.0, 0.0, 0.0));
+        assert!(
+            c.e0 > 0.5 && c.e1 < 0.1 && c.e2 < 0.1,
+            "red maps to {:?}",
+            c
+        );
+    }
+
+    #[test]
+    fn test_terminate_secondary_keeps_estimate_unbiased() {
+        let wl = SampledWavelengths::sample_uniform(0.3);
+        let hero_only = wl.terminate_secondary();
+        assert!(hero_only.is_secondary_terminated());
+        assert_eq!(hero_only.hero(), wl.hero());
+        // The hero wavelength alone carries the weight of all wavelengths
+        let flat = SampledSpectrum::constant(1.0);
+        let expected = cie_xyz(wl.hero()) * (LAMBDA_MAX - LAMBDA_MIN);
+        let xyz = hero_only.to_xyz(&flat);
+        assert!((&xyz - expected).length() < 1e-9);
+    }
+
+    #[test]
+    fn test_cie_peaks() {
+        assert!(cie_xyz(555.0).e1 > 0.95);
+        assert!(cie_xyz(380.0).e1 < 0.01);
+        assert!(cie_xyz(445.0).e2 > 1.5);
+    }
+}
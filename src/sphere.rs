@@ -1,6 +1,8 @@
 use crate::hittable::*;
 use crate::materials::*;
 use crate::ray::*;
+use crate::stats::Stats;
+use crate::texture::TexCoord;
 use crate::vec3::*;
 
 #[derive(Debug)]
@@ -13,9 +15,26 @@ pub struct Sphere {
 impl Sphere {
     pub fn new(center: Loc, radius: f64, material: Box<dyn Material>) -> Sphere {
         Sphere {
-            center: center,
-            radius: radius,
-            material: material,
+            center,
+            radius,
+            material,
+        }
+    }
+
+    // Derivatives of the parametrization in sphere_uv at the point with unit normal n
+    fn tangents(&self, n: &Loc) -> Tangents {
+        use std::f64::consts::PI;
+        // Distance to the y axis, clamped to keep the poles finite
+        let sin_theta = (n.e0 * n.e0 + n.e2 * n.e2).sqrt().max(1e-8);
+        Tangents {
+            dpdu: 2.0 * PI * self.radius * Vec3::new(n.e2, 0.0, -n.e0),
+            dpdv: PI
+                * self.radius
+                * Vec3::new(
+                    -n.e0 * n.e1 / sin_theta,
+                    sin_theta,
+                    -n.e1 * n.e2 / sin_theta,
+                ),
         }
     }
 }
@@ -34,6 +53,11 @@ pub fn interval_validator(a_opt: Option<f64>, b_opt: Option<f64>) -> impl Fn(f64
     }
 }
 
+#[allow(dead_code)]
+pub fn trivial_validator() -> impl Fn(f64) -> bool {
+    |_| true
+}
+
 fn solve_pq(p: f64, q: f64) -> Option<(f64, f64)> {
     let p_half_sq = (p / 2.0).powf(2.0);
 
@@ -46,17 +70,23 @@ fn solve_pq(p: f64, q: f64) -> Option<(f64, f64)> {
 }
 
 fn first_acceptable<T: Clone>(vec: Vec<T>, validate: &dyn Fn(T) -> bool) -> Option<T> {
-    for el in vec {
-        if validate(el.clone()) {
-            return Some(el);
-        }
-    }
-    None
+    vec.into_iter().find(|el| validate(el.clone()))
+}
+
+// Maps a point p on the unit sphere to (u, v): u is the angle around the y axis starting
+// at -x, v the angle from -y to +y, both normalized to [0,1]
+fn sphere_uv(p: &Loc) -> TexCoord {
+    let theta = (-p.e1).clamp(-1.0, 1.0).acos();
+    let phi = (-p.e2).atan2(p.e0) + std::f64::consts::PI;
+    (
+        phi / (2.0 * std::f64::consts::PI),
+        theta / std::f64::consts::PI,
+    )
 }
 
 impl Hittable for Sphere {
     #[allow(non_snake_case)]
-    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit<'_>> {
         // Equation:
         // Ray is described via A + t*b (A origin, b direction), for t in (-oo, oo)
         // Sphere is described via C (center) and r (radius).
@@ -75,19 +105,35 @@ impl Hittable for Sphere {
         let q = (A_C.dot(&A_C) - self.radius.powf(2.0)) / bb;
 
         let (t1, t2) = solve_pq(p, q)?;
-        // Check if any roots fulfill validation criteria and return them in case
-        let t = first_acceptable(vec![t1, t2], validate_t)?;
+        // Check if any roots fulfill validation criteria and return them in case.
+        // Roots on cut out parts of the surface are skipped.
+        let t = first_acceptable(vec![t1, t2], &|t| {
+            if !validate_t(t) {
+                return false;
+            }
+            let location = ray.at(t);
+            let uv = sphere_uv(&((&location - &self.center) / self.radius));
+            passes_alpha_test(&*self.material, ray, t, uv, &location)
+        })?;
 
         let hit_location = ray.at(t);
         let outward_normal = (&hit_location - &self.center).unit_vector();
+        let uv = sphere_uv(&outward_normal);
+        let tangents = self.tangents(&outward_normal);
         Some(Hit::from_ray(
             hit_location,
             outward_normal,
             t,
             ray,
             &*self.material,
+            uv,
+            tangents,
         ))
     }
+
+    fn count_tests(&self, stats: &mut Stats) {
+        stats.sphere_tests += 1;
+    }
 }
 
 #[cfg(test)]
@@ -106,7 +152,7 @@ mod tests {
     #[test]
     fn test_pq_solver() {
         let pq_sol_1 = solve_pq(4.0, -5.0);
-        assert!(!pq_sol_1.is_none());
+        assert!(pq_sol_1.is_some());
         let (x1, x2) = pq_sol_1.unwrap();
         assert_eq!(x1, -5.0);
         assert_eq!(x2, 1.0);
@@ -134,6 +180,41 @@ mod tests {
         assert!(!interval_3(-0.7));
     }
 
+    #[test]
+    fn test_sphere_uv() {
+        let close = |a: TexCoord, b: TexCoord| (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9;
+        assert!(close(sphere_uv(&Vec3::new(1.0, 0.0, 0.0)), (0.5, 0.5)));
+        assert!(close(sphere_uv(&Vec3::new(0.0, 0.0, 1.0)), (0.25, 0.5)));
+        assert!((sphere_uv(&Vec3::new(0.0, 1.0, 0.0)).1 - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sphere_tangents() {
+        // Compare against finite differences of the inverse of sphere_uv
+        let sphere = Sphere::new(Vec3::zero(), 2.0, Box::new(Lambertian::new(Vec3::zero())));
+        let point = |u: f64, v: f64| {
+            let theta = v * std::f64::consts::PI;
+            let phi = u * 2.0 * std::f64::consts::PI - std::f64::consts::PI;
+            2.0 * Vec3::new(
+                theta.sin() * phi.cos(),
+                -theta.cos(),
+                -theta.sin() * phi.sin(),
+            )
+        };
+        let (u, v, h) = (0.3, 0.6, 1e-6);
+        let p = point(u, v);
+        let uv = sphere_uv(&(&p / 2.0));
+        assert!((uv.0 - u).abs() < 1e-9 && (uv.1 - v).abs() < 1e-9);
+
+        let tangents = sphere.tangents(&(&p / 2.0));
+        let dpdu = (point(u + h, v) - point(u - h, v)) / (2.0 * h);
+        let dpdv = (point(u, v + h) - point(u, v - h)) / (2.0 * h);
+        assert!((&tangents.dpdu - dpdu).length() < 1e-6);
+        assert!((&tangents.dpdv - dpdv).length() < 1e-6);
+        // Right-handed with the outward normal
+        assert!(tangents.dpdu.cross(&tangents.dpdv).dot(&p) > 0.0);
+    }
+
     #[test]
     fn sphere_hits() {
         let simple_material = Lambertian::new(Vec3::zero());
@@ -0,0 +1,165 @@
+// Counters of the work done while rendering. Every thread counts into its own copy,
+// render passes collect them after each tile and merge them into the totals.
+use std::cell::RefCell;
+use std::fmt::Write as _;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub samples: u64,
+    pub camera_rays: u64,
+    // Camera rays plus the rays that continue paths after bounces
+    pub rays: u64,
+    // Intersection tests by primitive. Scenes are flat lists without a BVH, the reports
+    // show BVH nodes visited as n/a (null in JSON).
+    pub sphere_tests: u64,
+    pub quad_tests: u64,
+    pub triangle_tests: u64,
+    // How paths ended: leaving the scene, absorbed by a material, killed by Russian
+    // roulette or cut off at the bounce limit
+    pub escaped: u64,
+    pub absorbed: u64,
+    pub roulette: u64,
+    pub depth_limit: u64,
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+// Updates the counters of the current thread
+pub fn count(update: impl FnOnce(&mut Stats)) {
+    COUNTERS.with(|counters| update(&mut counters.borrow_mut()));
+}
+
+// Counters of the current thread since the last call
+pub fn take() -> Stats {
+    COUNTERS.with(|counters| counters.replace(Stats::default()))
+}
+
+impl Stats {
+    pub fn merge(&mut self, other: &Stats) {
+        self.samples += other.samples;
+        self.camera_rays += other.camera_rays;
+        self.rays += other.rays;
+        self.sphere_tests += other.sphere_tests;
+        self.quad_tests += other.quad_tests;
+        self.triangle_tests += other.triangle_tests;
+        self.escaped += other.escaped;
+        self.absorbed += other.absorbed;
+        self.roulette += other.roulette;
+        self.depth_limit += other.depth_limit;
+    }
+
+    // Rays per path, counting the camera ray
+    pub fn average_path_length(&self) -> f64 {
+        self.rays as f64 / self.camera_rays.max(1) as f64
+    }
+
+    fn paths(&self) -> u64 {
+        self.escaped + self.absorbed + self.roulette + self.depth_limit
+    }
+
+    // Report for the terminal, of a render that took the given time
+    pub fn summary(&self, seconds: f64, threads: usize) -> String {
+        let per_second = |count: u64| count as f64 / seconds.max(1e-9);
+        let share = |count: u64| 100.0 * count as f64 / self.paths().max(1) as f64;
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Samples: {} ({:.0} per second on {} thread{})",
+            self.samples,
+            per_second(self.samples),
+            threads,
+            if threads == 1 { "" } else { "s" }
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "Rays: {} ({:.0} per second), {} from the camera, {:.2} per path",
+            self.rays,
+            per_second(self.rays),
+            self.camera_rays,
+            self.average_path_length()
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "Intersection tests: {} spheres, {} quads, {} triangles, BVH nodes visited: n/a",
+            self.sphere_tests, self.quad_tests, self.triangle_tests
+        )
+        .unwrap();
+        write!(
+            out,
+            "Paths ended: {:.1}% escaped, {:.1}% absorbed, {:.1}% russian roulette, {:.1}% depth limit",
+            share(self.escaped),
+            share(self.absorbed),
+            share(self.roulette),
+            share(self.depth_limit)
+        )
+        .unwrap();
+        out
+    }
+
+    // Same report for tracking over time
+    pub fn to_json(&self, seconds: f64, threads: usize) -> String {
+        format!(
+            concat!(
+                "{{\n",
+                "  \"seconds\": {},\n",
+                "  \"threads\": {},\n",
+                "  \"samples\": {},\n",
+                "  \"samples_per_second\": {},\n",
+                "  \"camera_rays\": {},\n",
+                "  \"rays\": {},\n",
+                "  \"average_path_length\": {},\n",
+                "  \"intersection_tests\": {{\"sphere\": {}, \"quad\": {}, \"triangle\": {}}},\n",
+                "  \"bvh_nodes_visited\": null,\n",
+                "  \"terminations\": {{\"escaped\": {}, \"absorbed\": {}, \"roulette\": {}, \"depth_limit\": {}}}\n",
+                "}}\n"
+            ),
+            seconds,
+            threads,
+            self.samples,
+            self.samples as f64 / seconds.max(1e-9),
+            self.camera_rays,
+            self.rays,
+            self.average_path_length(),
+            self.sphere_tests,
+            self.quad_tests,
+            self.triangle_tests,
+            self.escaped,
+            self.absorbed,
+            self.roulette,
+            self.depth_limit
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        take();
+        count(|s| s.camera_rays += 2);
+        count(|s| s.rays += 5);
+        count(|s| s.escaped += 2);
+        let mut total = Stats {
+            sphere_tests: 7,
+            ..Stats::default()
+        };
+        total.merge(&take());
+        // Taking resets the counters of the thread
+        assert_eq!(take(), Stats::default());
+        assert_eq!(total.sphere_tests, 7);
+        assert_eq!(total.average_path_length(), 2.5);
+
+        let json = total.to_json(2.0, 4);
+        assert!(json.contains("\"threads\": 4,"));
+        assert!(json.contains("\"average_path_length\": 2.5,"));
+        assert!(json.contains("{\"sphere\": 7, \"quad\": 0, \"triangle\": 0}"));
+        assert!(json.contains("\"bvh_nodes_visited\": null,"));
+        assert!(total.summary(2.0, 4).contains("100.0% escaped"));
+    }
+}
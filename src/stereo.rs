@@ -0,0 +1,169 @@
+// Stereoscopic rigs: two cameras, one per eye, that share one image. The eyes sit on a
+// line through the position of the rig, perpendicular to its view direction.
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::vec3::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+// How the views of the eyes meet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Convergence {
+    // Both eyes look straight ahead, only infinitely far objects line up
+    Parallel,
+    // Both eyes are turned towards the convergence point, which distorts the image
+    // edges vertically
+    ToeIn,
+    // Parallel eyes whose image windows are shifted to line up at the convergence
+    // distance, like a projection screen seen by both eyes
+    OffAxis,
+}
+
+// Arrangement of the views in the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Packing {
+    // Left eye in the left half
+    SideBySide,
+    // Left eye in the upper half
+    TopBottom,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StereoRig {
+    pub interocular: f64,
+    pub convergence: Convergence,
+    // Objects at this distance appear at the depth of the screen
+    pub convergence_distance: f64,
+}
+
+// Placement of an eye camera
+pub struct EyeView {
+    pub lookfrom: Loc,
+    pub lookat: Loc,
+    // Horizontal shift of the image window per unit of distance from the eye, towards
+    // the right of the image
+    pub shift: f64,
+}
+
+impl StereoRig {
+    // Signed distance of the eye from the center of the rig, positive to the right
+    pub fn offset(&self, eye: Eye) -> f64 {
+        match eye {
+            Eye::Left => -self.interocular / 2.0,
+            Eye::Right => self.interocular / 2.0,
+        }
+    }
+
+    pub fn eye_view(&self, eye: Eye, lookfrom: &Loc, lookat: &Loc, vup: &Loc) -> EyeView {
+        let forward = (lookat - lookfrom).unit_vector();
+        let right = forward.cross(vup).unit_vector();
+        let offset = self.offset(eye) * &right;
+        let eye_lookfrom = lookfrom + &offset;
+        let (lookat, shift) = match self.convergence {
+            Convergence::Parallel => (lookat + &offset, 0.0),
+            Convergence::ToeIn => (lookfrom + self.convergence_distance * &forward, 0.0),
+            Convergence::OffAxis => (
+                lookat + &offset,
+                -self.offset(eye) / self.convergence_distance,
+            ),
+        };
+        EyeView {
+            lookfrom: eye_lookfrom,
+            lookat,
+            shift,
+        }
+    }
+}
+
+// Renders both eyes into one image, each eye camera covers its part of the image. Filters
+// wider than a pixel blend the eyes along the seam.
+#[derive(Debug)]
+pub struct StereoCamera {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    packing: Packing,
+}
+
+impl StereoCamera {
+    pub fn new(left: Box<dyn Camera>, right: Box<dyn Camera>, packing: Packing) -> StereoCamera {
+        StereoCamera {
+            left,
+            right,
+            packing,
+        }
+    }
+}
+
+impl Camera for StereoCamera {
+    fn get_ray(&self, s: f64, t: f64, lens: (f64, f64)) -> Option<Ray> {
+        match self.packing {
+            Packing::SideBySide if s < 0.5 => self.left.get_ray(2.0 * s, t, lens),
+            Packing::SideBySide => self.right.get_ray(2.0 * s - 1.0, t, lens),
+            // t counts from the bottom
+            Packing::TopBottom if t >= 0.5 => self.left.get_ray(s, 2.0 * t - 1.0, lens),
+            Packing::TopBottom => self.right.get_ray(s, 2.0 * t, lens),
+        }
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.left.sensitivity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::OrthographicCamera;
+
+    #[test]
+    fn test_eye_views() {
+        let lookfrom = Vec3::new(0.0, 0.0, 10.0);
+        let lookat = Vec3::new(0.0, 0.0, 0.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let rig = |convergence| StereoRig {
+            interocular: 0.5,
+            convergence,
+            convergence_distance: 4.0,
+        };
+
+        let parallel = rig(Convergence::Parallel).eye_view(Eye::Left, &lookfrom, &lookat, &vup);
+        assert_eq!(parallel.lookfrom, Vec3::new(-0.25, 0.0, 10.0));
+        assert_eq!(parallel.lookat, Vec3::new(-0.25, 0.0, 0.0));
+
+        let toe_in = rig(Convergence::ToeIn).eye_view(Eye::Right, &lookfrom, &lookat, &vup);
+        assert_eq!(toe_in.lookfrom, Vec3::new(0.25, 0.0, 10.0));
+        assert_eq!(toe_in.lookat, Vec3::new(0.0, 0.0, 6.0));
+
+        // The window of the left eye moves right, towards the convergence point
+        let off_axis = rig(Convergence::OffAxis).eye_view(Eye::Left, &lookfrom, &lookat, &vup);
+        assert_eq!(off_axis.lookat, Vec3::new(-0.25, 0.0, 0.0));
+        // At the convergence distance the shift makes up for the offset of the eye
+        assert_eq!(4.0 * off_axis.shift, 0.25);
+    }
+
+    #[test]
+    fn test_packing() {
+        let eye = |x: f64| -> Box<dyn Camera> {
+            Box::new(OrthographicCamera::new(
+                Vec3::new(x, 0.0, 1.0),
+                Vec3::new(x, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                2.0,
+                1.0,
+            ))
+        };
+        let side_by_side = StereoCamera::new(eye(-5.0), eye(5.0), Packing::SideBySide);
+        let ray = |camera: &StereoCamera, s, t| camera.get_ray(s, t, (0.5, 0.5)).unwrap().orig;
+        assert_eq!(ray(&side_by_side, 0.25, 0.5), Vec3::new(-5.0, 0.0, 1.0));
+        assert_eq!(ray(&side_by_side, 0.75, 0.5), Vec3::new(5.0, 0.0, 1.0));
+        assert_eq!(ray(&side_by_side, 0.5, 0.0), Vec3::new(4.0, -1.0, 1.0));
+
+        let top_bottom = StereoCamera::new(eye(-5.0), eye(5.0), Packing::TopBottom);
+        assert_eq!(ray(&top_bottom, 0.5, 0.75), Vec3::new(-5.0, 0.0, 1.0));
+        assert_eq!(ray(&top_bottom, 0.5, 0.25), Vec3::new(5.0, 0.0, 1.0));
+    }
+}
@@ -0,0 +1,166 @@
+use crate::image::Image;
+use crate::vec3::*;
+use std::fmt;
+
+// Surface coordinates (u, v) in [0,1]^2, v = 0 is the bottom of an image
+pub type TexCoord = (f64, f64);
+
+pub trait Texture: fmt::Debug + Send + Sync {
+    fn value(&self, uv: TexCoord, location: &Loc) -> Color;
+
+    // For textures describing scalar parameters (roughness, metallic, ...)
+    fn scalar(&self, uv: TexCoord, location: &Loc) -> f64 {
+        self.value(uv, location).e0
+    }
+}
+
+impl Texture for Color {
+    fn value(&self, _: TexCoord, _: &Loc) -> Color {
+        self.clone()
+    }
+}
+
+impl Texture for f64 {
+    fn value(&self, _: TexCoord, _: &Loc) -> Color {
+        Vec3::new(*self, *self, *self)
+    }
+}
+
+// Solid 3D checker pattern
+#[derive(Debug)]
+pub struct Checker {
+    odd: Box<dyn Texture>,
+    even: Box<dyn Texture>,
+    scale: f64,
+}
+
+impl Checker {
+    pub fn new<A: Texture + 'static, B: Texture + 'static>(odd: A, even: B, scale: f64) -> Checker {
+        Checker {
+            odd: Box::new(odd),
+            even: Box::new(even),
+            scale,
+        }
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, uv: TexCoord, location: &Loc) -> Color {
+        let sines = (self.scale * location.e0).sin()
+            * (self.scale * location.e1).sin()
+            * (self.scale * location.e2).sin();
+        if sines < 0.0 {
+            self.odd.value(uv, location)
+        } else {
+            self.even.value(uv, location)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+    image: Image,
+}
+
+// Inverse of the sRGB transfer function
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl ImageTexture {
+    // Values are used as stored, without decoding a transfer function. Right for data
+    // textures (roughness, normal maps, masks).
+    pub fn new(image: Image) -> ImageTexture {
+        ImageTexture { image }
+    }
+
+    // Color textures are stored sRGB encoded, this linearizes the texels
+    pub fn decode_srgb(mut self) -> ImageTexture {
+        for y in 0..self.image.height {
+            for x in 0..self.image.width {
+                let c = self.image.get(x, y);
+                let linear = Vec3::new(
+                    srgb_to_linear(c.e0),
+                    srgb_to_linear(c.e1),
+                    srgb_to_linear(c.e2),
+                );
+                self.image.set(x, y, linear);
+            }
+        }
+        self
+    }
+
+    fn texel(&self, x: i64, y: i64) -> &Color {
+        let w = self.image.width as i64;
+        let h = self.image.height as i64;
+        self.image
+            .get(x.rem_euclid(w) as usize, y.rem_euclid(h) as usize)
+    }
+}
+
+impl Texture for ImageTexture {
+    // Bilinear lookup, repeating outside of [0,1]^2
+    fn value(&self, uv: TexCoord, _: &Loc) -> Color {
+        let x = uv.0 * self.image.width as f64 - 0.5;
+        let y = (1.0 - uv.1) * self.image.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        (1.0 - fx) * (1.0 - fy) * self.texel(x0, y0)
+            + fx * (1.0 - fy) * self.texel(x0 + 1, y0)
+            + (1.0 - fx) * fy * self.texel(x0, y0 + 1)
+            + fx * fy * self.texel(x0 + 1, y0 + 1)
+    }
+}
+
+// Product of a texture and a constant factor
+#[derive(Debug)]
+pub struct Scaled {
+    texture: Box<dyn Texture>,
+    factor: Color,
+}
+
+impl Scaled {
+    pub fn new<T: Texture + 'static>(texture: T, factor: Color) -> Scaled {
+        Scaled {
+            texture: Box::new(texture),
+            factor,
+        }
+    }
+}
+
+impl Texture for Scaled {
+    fn value(&self, uv: TexCoord, location: &Loc) -> Color {
+        self.texture.value(uv, location).hadamard(&self.factor)
+    }
+}
+
+// Broadcasts a single channel (0: red, 1: green, 2: blue) of a texture
+#[derive(Debug)]
+pub struct Channel {
+    texture: Box<dyn Texture>,
+    channel: usize,
+}
+
+impl Channel {
+    pub fn new<T: Texture + 'static>(texture: T, channel: usize) -> Channel {
+        assert!(channel < 3);
+        Channel {
+            texture: Box::new(texture),
+            channel,
+        }
+    }
+}
+
+impl Texture for Channel {
+    fn value(&self, uv: TexCoord, location: &Loc) -> Color {
+        let c = self.texture.value(uv, location);
+        let v = [c.e0, c.e1, c.e2][self.channel];
+        Vec3::new(v, v, v)
+    }
+}
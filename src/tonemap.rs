@@ -0,0 +1,283 @@
+// Turns the linear scene colors of the film into displayable colors: white balance,
+// exposure, a tonemapping operator that compresses highlights into [0,1] and the
+// transfer function of the output.
+use crate::spectrum::xyz_to_linear_srgb;
+use crate::vec3::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    // Cuts off everything above one
+    Clamp,
+    // L / (1 + L) on the luminance, never reaches white
+    Reinhard,
+    // Reinhard that maps a luminance of `white` to one
+    ExtendedReinhard { white: f64 },
+    // Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    Aces,
+    // Troy Sobotka's AgX, with the polynomial fit of its default contrast curve
+    Agx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+    Linear,
+    // Exact piecewise sRGB encoding
+    Srgb,
+    // Pure power law, 2.0 is what older versions wrote
+    Gamma(f64),
+}
+
+type Matrix = [[f64; 3]; 3];
+
+fn apply(m: &Matrix, c: &Color) -> Color {
+    let row = |r: &[f64; 3]| r[0] * c.e0 + r[1] * c.e1 + r[2] * c.e2;
+    Vec3::new(row(&m[0]), row(&m[1]), row(&m[2]))
+}
+
+const SRGB_TO_XYZ: Matrix = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+
+// Cone responses for chromatic adaptation
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+const BRADFORD_INVERSE: Matrix = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+// Chromaticity of a white at the given color temperature: the CIE daylight locus from
+// 4000K on, the Planckian locus (Kim et al.) below
+fn white_point(kelvin: f64) -> (f64, f64) {
+    let t = kelvin.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    if t >= 4000.0 {
+        let x = if t <= 7000.0 {
+            0.244063 + 0.09911e3 / t + 2.9678e6 / t2 - 4.6070e9 / t3
+        } else {
+            0.237040 + 0.24748e3 / t + 1.9018e6 / t2 - 2.0064e9 / t3
+        };
+        (x, -3.0 * x * x + 2.87 * x - 0.275)
+    } else {
+        let x = -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910;
+        let (x2, x3) = (x * x, x * x * x);
+        let y = if t <= 2222.0 {
+            -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+        } else {
+            -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+        };
+        (x, y)
+    }
+}
+
+// Cone response of a white with unit luminance
+fn cone_response(xy: (f64, f64)) -> Vec3 {
+    let (x, y) = xy;
+    apply(&BRADFORD, &Vec3::new(x / y, 1.0, (1.0 - x - y) / y))
+}
+
+fn reinhard(c: &Color, white: f64) -> Color {
+    let l = c.luminance();
+    if l <= 0.0 {
+        return Vec3::zero();
+    }
+    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+    (mapped / l) * c
+}
+
+fn aces(c: &Color) -> Color {
+    const INPUT: Matrix = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: Matrix = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let fit = |v: f64| {
+        let a = v * (v + 0.0245786) - 0.000090537;
+        let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+        a / b
+    };
+    let c = apply(&INPUT, c);
+    apply(&OUTPUT, &Vec3::new(fit(c.e0), fit(c.e1), fit(c.e2)))
+}
+
+fn agx(c: &Color) -> Color {
+    const INSET: Matrix = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: Matrix = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    // Log encoding of the exposure range around middle grey
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+    let curve = |v: f64| {
+        let v = (v.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let (v2, v4) = (v * v, v * v * v * v);
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v
+            - 0.00232
+    };
+    let c = apply(&INSET, c);
+    let c = apply(&OUTSET, &Vec3::new(curve(c.e0), curve(c.e1), curve(c.e2)));
+    // The curve produces display values for a 2.2 power law, linearize them again
+    c.clamp(0.0, 1.0).gamma_correct(1.0 / 2.2)
+}
+
+fn srgb_encode(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostProcess {
+    // Cone response gains that adapt the scene white to D65, None leaves colors as
+    // rendered
+    white_balance: Option<Vec3>,
+    // Scales the scene by 2^EV
+    exposure: f64,
+    tonemap: Tonemap,
+    transfer: Transfer,
+}
+
+impl PostProcess {
+    // white_balance is the color temperature in Kelvin that should appear neutral
+    pub fn new(
+        exposure: f64,
+        white_balance: Option<f64>,
+        tonemap: Tonemap,
+        transfer: Transfer,
+    ) -> PostProcess {
+        let white_balance = white_balance.map(|kelvin| {
+            let d65 = cone_response((0.31271, 0.32902));
+            let scene = cone_response(white_point(kelvin));
+            Vec3::new(d65.e0 / scene.e0, d65.e1 / scene.e1, d65.e2 / scene.e2)
+        });
+        PostProcess {
+            white_balance,
+            exposure,
+            tonemap,
+            transfer,
+        }
+    }
+
+    // Same processing, encoded for a different output
+    pub fn with_transfer(&self, transfer: Transfer) -> PostProcess {
+        PostProcess {
+            transfer,
+            ..self.clone()
+        }
+    }
+
+    // Maps a linear sRGB scene color to an encoded color in [0,1]
+    pub fn apply(&self, c: &Color) -> Color {
+        let mut c = c.clone();
+        if let Some(gains) = &self.white_balance {
+            let cones = apply(&BRADFORD, &apply(&SRGB_TO_XYZ, &c)).hadamard(gains);
+            c = xyz_to_linear_srgb(&apply(&BRADFORD_INVERSE, &cones));
+        }
+        c = 2f64.powf(self.exposure) * c;
+        // Spectral estimates and white balancing may leave the sRGB gamut
+        let c = match self.tonemap {
+            Tonemap::Clamp => c,
+            Tonemap::Reinhard => reinhard(&c.clamp(0.0, f64::INFINITY), f64::INFINITY),
+            Tonemap::ExtendedReinhard { white } => reinhard(&c.clamp(0.0, f64::INFINITY), white),
+            Tonemap::Aces => aces(&c.clamp(0.0, f64::INFINITY)),
+            Tonemap::Agx => agx(&c.clamp(0.0, f64::INFINITY)),
+        }
+        .clamp(0.0, 1.0);
+        match self.transfer {
+            Transfer::Linear => c,
+            Transfer::Srgb => Vec3::new(srgb_encode(c.e0), srgb_encode(c.e1), srgb_encode(c.e2)),
+            Transfer::Gamma(gamma) => c.gamma_correct(gamma),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(v: f64) -> Color {
+        Vec3::new(v, v, v)
+    }
+
+    #[test]
+    fn test_operators() {
+        let operators = [
+            Tonemap::Clamp,
+            Tonemap::Reinhard,
+            Tonemap::ExtendedReinhard { white: 4.0 },
+            Tonemap::Aces,
+            Tonemap::Agx,
+        ];
+        for tonemap in operators {
+            let post = PostProcess::new(0.0, None, tonemap, Transfer::Linear);
+            assert!(post.apply(&grey(0.0)).e0 < 1e-3, "{:?}", tonemap);
+            let mut last = 0.0;
+            for v in [0.01, 0.1, 0.18, 1.0, 4.0, 100.0] {
+                let mapped = post.apply(&grey(v)).e0;
+                assert!(mapped >= last && mapped <= 1.0, "{:?} at {}", tonemap, v);
+                last = mapped;
+            }
+        }
+        let reinhard = PostProcess::new(0.0, None, Tonemap::Reinhard, Transfer::Linear);
+        assert!((reinhard.apply(&grey(1.0)).e1 - 0.5).abs() < 1e-12);
+        let extended = PostProcess::new(
+            0.0,
+            None,
+            Tonemap::ExtendedReinhard { white: 4.0 },
+            Transfer::Linear,
+        );
+        assert!((extended.apply(&grey(4.0)).e2 - 1.0).abs() < 1e-12);
+        // One stop up doubles the scene
+        let brighter = PostProcess::new(1.0, None, Tonemap::Clamp, Transfer::Linear);
+        assert!((brighter.apply(&grey(0.25)).e0 - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_srgb_transfer() {
+        let post = PostProcess::new(0.0, None, Tonemap::Clamp, Transfer::Srgb);
+        assert!((post.apply(&grey(1.0)).e0 - 1.0).abs() < 1e-12);
+        assert!((post.apply(&grey(0.18)).e0 - 0.4614).abs() < 1e-3);
+        // Both pieces meet at the threshold
+        let (below, above) = (srgb_encode(0.0031308 - 1e-9), srgb_encode(0.0031308 + 1e-9));
+        assert!((below - above).abs() < 1e-6);
+        let gamma = post.with_transfer(Transfer::Gamma(2.0));
+        assert_eq!(gamma.apply(&grey(0.25)).e0, 0.5);
+    }
+
+    #[test]
+    fn test_white_balance() {
+        // Balancing for D65 changes nothing
+        let d65 = PostProcess::new(0.0, Some(6504.0), Tonemap::Clamp, Transfer::Linear);
+        let c = Vec3::new(0.2, 0.5, 0.3);
+        assert!((&d65.apply(&c) - &c).length() < 2e-3);
+        // A white lit at 3000K becomes neutral again
+        let (x, y) = white_point(3000.0);
+        let warm = xyz_to_linear_srgb(&(0.5 * Vec3::new(x / y, 1.0, (1.0 - x - y) / y)));
+        assert!(warm.e0 > warm.e2);
+        let balanced =
+            PostProcess::new(0.0, Some(3000.0), Tonemap::Clamp, Transfer::Linear).apply(&warm);
+        // Up to the precision of the published Bradford matrix
+        assert!((balanced.e0 - balanced.e2).abs() < 5e-4);
+        assert!((balanced.e0 - balanced.e1).abs() < 5e-4);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::hittable::*;
+use crate::materials::*;
+use crate::ray::*;
+use crate::stats::Stats;
+use crate::texture::TexCoord;
+use crate::vec3::*;
+
+#[derive(Debug)]
+pub struct Triangle {
+    pub vertices: [Loc; 3],
+    pub uvs: [TexCoord; 3],
+    pub material: Box<dyn Material>,
+}
+
+impl Triangle {
+    // Counter-clockwise vertices (seen from outside) get an outward facing normal
+    pub fn new(p0: Loc, p1: Loc, p2: Loc, material: Box<dyn Material>) -> Triangle {
+        Triangle::with_uvs([p0, p1, p2], [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)], material)
+    }
+
+    pub fn with_uvs(
+        vertices: [Loc; 3],
+        uvs: [TexCoord; 3],
+        material: Box<dyn Material>,
+    ) -> Triangle {
+        Triangle {
+            vertices,
+            uvs,
+            material,
+        }
+    }
+
+    // Solves dp = du * dpdu + dv * dpdv for both edges of the triangle
+    fn tangents(&self, e1: &Loc, e2: &Loc, normal: &Loc) -> Tangents {
+        let (du1, dv1) = (self.uvs[1].0 - self.uvs[0].0, self.uvs[1].1 - self.uvs[0].1);
+        let (du2, dv2) = (self.uvs[2].0 - self.uvs[0].0, self.uvs[2].1 - self.uvs[0].1);
+        let det = du1 * dv2 - dv1 * du2;
+        if det.abs() < 1e-12 {
+            // Degenerate uv mapping, any frame around the normal will do
+            let (dpdu, dpdv) = normal.orthonormal_basis();
+            return Tangents { dpdu, dpdv };
+        }
+        Tangents {
+            dpdu: (dv2 * e1 - dv1 * e2) / det,
+            dpdv: (du1 * e2 - du2 * e1) / det,
+        }
+    }
+}
+
+impl Hittable for Triangle {
+    // Möller-Trumbore intersection
+    fn hit(&self, ray: &Ray, validate_t: &dyn Fn(f64) -> bool) -> Option<Hit<'_>> {
+        let [p0, p1, p2] = &self.vertices;
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = ray.dir.cross(&e2);
+        let det = e1.dot(&pvec);
+        if det.abs() < 1e-12 {
+            return None; // ray parallel to the triangle
+        }
+        let inv_det = 1.0 / det;
+        let tvec = &ray.orig - p0;
+        let b1 = tvec.dot(&pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = tvec.cross(&e1);
+        let b2 = ray.dir.dot(&qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&qvec) * inv_det;
+        if !validate_t(t) {
+            return None;
+        }
+
+        let b0 = 1.0 - b1 - b2;
+        let uv = (
+            b0 * self.uvs[0].0 + b1 * self.uvs[1].0 + b2 * self.uvs[2].0,
+            b0 * self.uvs[0].1 + b1 * self.uvs[1].1 + b2 * self.uvs[2].1,
+        );
+        let location = ray.at(t);
+        if !passes_alpha_test(&*self.material, ray, t, uv, &location) {
+            return None;
+        }
+        let outward_normal = e1.cross(&e2).unit_vector();
+        let tangents = self.tangents(&e1, &e2, &outward_normal);
+        Some(Hit::from_ray(
+            location,
+            outward_normal,
+            t,
+            ray,
+            &*self.material,
+            uv,
+            tangents,
+        ))
+    }
+
+    fn count_tests(&self, stats: &mut Stats) {
+        stats.triangle_tests += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::interval_validator;
+    use crate::texture::Texture;
+
+    fn unit_triangle(material: Box<dyn Material>) -> Triangle {
+        Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            material,
+        )
+    }
+
+    #[test]
+    fn triangle_hits() {
+        let triangle = unit_triangle(Box::new(Lambertian::new(Vec3::zero())));
+        let validator = interval_validator(Some(0.0), None);
+
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangle
+            .hit(&ray, &validator)
+            .expect("Ray should've hit triangle");
+        assert_eq!(hit.t, 2.0);
+        assert_eq!(hit.uv, (0.25, 0.5));
+        assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(hit.surface, Surface::Outside);
+        assert_eq!(hit.tangents.dpdu, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(hit.tangents.dpdv, Vec3::new(0.0, 1.0, 0.0));
+
+        // Outside of the edges
+        let ray = Ray::new(Vec3::new(0.75, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(triangle.hit(&ray, &validator).is_none());
+
+        // From below
+        let ray = Ray::new(Vec3::new(0.25, 0.25, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = triangle
+            .hit(&ray, &validator)
+            .expect("Ray should've hit triangle");
+        assert_eq!(hit.surface, Surface::Inside);
+    }
+
+    #[test]
+    fn test_alpha_cutout() {
+        // Transparent for u < 0.5
+        #[derive(Debug)]
+        struct HalfMask;
+        impl Texture for HalfMask {
+            fn value(&self, uv: TexCoord, _: &Loc) -> Color {
+                let a = if uv.0 < 0.5 { 0.0 } else { 1.0 };
+                Vec3::new(a, a, a)
+            }
+        }
+        let triangle = unit_triangle(Box::new(AlphaMasked::new(
+            Box::new(Lambertian::new(Vec3::zero())),
+            HalfMask,
+            AlphaMode::Mask(0.5),
+        )));
+        let validator = interval_validator(Some(0.0), None);
+        let down = Vec3::new(0.0, 0.0, -1.0);
+        assert!(triangle
+            .hit(
+                &Ray::new(Vec3::new(0.2, 0.2, 1.0), down.clone()),
+                &validator
+            )
+            .is_none());
+        assert!(triangle
+            .hit(&Ray::new(Vec3::new(0.6, 0.2, 1.0), down), &validator)
+            .is_some());
+    }
+
+    #[test]
+    fn test_fractional_alpha_passes_stochastically() {
+        let triangle = unit_triangle(Box::new(AlphaMasked::new(
+            Box::new(Lambertian::new(Vec3::zero())),
+            0.25,
+            AlphaMode::Blend,
+        )));
+        let validator = interval_validator(Some(0.0), None);
+        let n = 2000;
+        let hits = (0..n)
+            .filter(|i| {
+                let x = 0.1 + 0.4 * (*i as f64 / n as f64);
+                let ray = Ray::new(Vec3::new(x, 0.2, 1.0), Vec3::new(0.0, 0.0, -1.0));
+                triangle.hit(&ray, &validator).is_some()
+            })
+            .count();
+        let fraction = hits as f64 / n as f64;
+        assert!((fraction - 0.25).abs() < 0.05, "hit fraction {}", fraction);
+    }
+}
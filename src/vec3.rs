@@ -13,11 +13,7 @@ pub struct Vec3 {
 
 impl Vec3 {
     pub const fn new(e0: f64, e1: f64, e2: f64) -> Vec3 {
-        Vec3 {
-            e0: e0,
-            e1: e1,
-            e2: e2,
-        }
+        Vec3 { e0, e1, e2 }
     }
 
     pub fn dot(&self, rhs: &Self) -> f64 {
@@ -55,6 +51,19 @@ impl Vec3 {
         )
     }
 
+    pub fn clamp(&self, min: f64, max: f64) -> Vec3 {
+        Vec3::new(
+            self.e0.clamp(min, max),
+            self.e1.clamp(min, max),
+            self.e2.clamp(min, max),
+        )
+    }
+
+    // Relative luminance of a linear sRGB color (Rec. 709 weights)
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.e0 + 0.7152 * self.e1 + 0.0722 * self.e2
+    }
+
     pub fn zero() -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
@@ -64,6 +73,22 @@ impl Vec3 {
         self.e0.abs() < tol && self.e1.abs() < tol && self.e2.abs() < tol
     }
 
+    // Returns (t, b) such that t, b and self (a unit vector) form a right-handed
+    // orthonormal basis, see Duff et al. 2017, "Building an Orthonormal Basis, Revisited"
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let sign = 1.0_f64.copysign(self.e2);
+        let a = -1.0 / (sign + self.e2);
+        let b = self.e0 * self.e1 * a;
+        (
+            Vec3::new(
+                1.0 + sign * self.e0 * self.e0 * a,
+                sign * b,
+                -sign * self.e0,
+            ),
+            Vec3::new(b, sign + self.e1 * self.e1 * a, -self.e1),
+        )
+    }
+
     pub fn cross(&self, rhs: &Vec3) -> Vec3 {
         Vec3::new(
             self.e1 * rhs.e2 - self.e2 * rhs.e1,
@@ -145,13 +170,13 @@ mod tests {
 
     #[test]
     fn test_lengths() {
-        assert_eq!(TEST_VEC_2.length(), (2.0 as f64).sqrt());
+        assert_eq!(TEST_VEC_2.length(), 2.0_f64.sqrt());
         assert_eq!(TEST_VEC_2.length_squared(), 2.0);
     }
 
     #[test]
     fn test_unit_vector_creation() {
-        let sqrt_one_half = 1.0 / (2.0 as f64).sqrt();
+        let sqrt_one_half = 1.0 / 2.0_f64.sqrt();
         assert_eq!(
             TEST_VEC_2.unit_vector(),
             Vec3::new(-sqrt_one_half, 0.0, sqrt_one_half)
@@ -175,6 +200,20 @@ mod tests {
         assert_eq!("255 255 255", color_string(&Vec3::new(1.0, 1.0, 1.0)));
     }
 
+    #[test]
+    fn test_orthonormal_basis() {
+        for n in &[
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(1.0, 2.0, -3.0).unit_vector(),
+        ] {
+            let (t, b) = n.orthonormal_basis();
+            assert!((t.length() - 1.0).abs() < 1e-12 && (b.length() - 1.0).abs() < 1e-12);
+            assert!(t.dot(&b).abs() < 1e-12 && t.dot(n).abs() < 1e-12);
+            assert!((&t.cross(&b) - n).length() < 1e-12);
+        }
+    }
+
     #[test]
     fn test_cross() {
         assert_eq!(